@@ -142,8 +142,7 @@ impl Gui {
         self.event_loop.run(move |event, _, control_flow| {
             // Draw the current frame
             if let Event::RedrawRequested(_) = event {
-                let frame = self.gb.cpu.bus.ppu.get_screen();
-                self.pixels.frame_mut()[..frame.len()].copy_from_slice(&frame);
+                self.gb.cpu.bus.ppu.write_rgba_into(self.pixels.frame_mut());
 
                 // Prepare Dear ImGui
                 let now = Instant::now();
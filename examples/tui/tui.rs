@@ -1,8 +1,9 @@
 mod widget;
 
-use widget::{Background, BkWindow, GameWidget, SpritesWidget};
+use widget::{Background, BkWindow, GameWidget, SpritePalette, SpritesWidget, TilesetWidget};
 
 use gb_rs::{
+    cart::SystemClock,
     gb::GbRs,
     joypad::{JoypadDirection, JoypadInput},
     ppu::{BKG_WIDTH, SCREEN_HEIGHT, SCREEN_WIDTH},
@@ -35,6 +36,42 @@ use clap::Parser;
 struct Args {
     #[arg(short, long)]
     rom: String,
+    // Remaps a key, e.g. `--bind x=START`
+    #[arg(long = "bind", value_name = "KEY=BUTTON")]
+    binds: Vec<String>,
+}
+
+// Maps keyboard keys to Game Boy buttons
+struct KeyMap(Vec<(KeyCode, JoypadInput)>);
+
+impl KeyMap {
+    fn get(&self, key: KeyCode) -> Option<JoypadInput> {
+        self.0
+            .iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, input)| *input)
+    }
+
+    // Binds `key` to `input`, replacing whatever `key` was bound to
+    fn bind(&mut self, key: KeyCode, input: JoypadInput) {
+        self.0.retain(|(k, _)| *k != key);
+        self.0.push((key, input));
+    }
+}
+
+impl Default for KeyMap {
+    fn default() -> Self {
+        Self(vec![
+            (KeyCode::Char('w'), JoypadInput::UP),
+            (KeyCode::Char('a'), JoypadInput::LEFT),
+            (KeyCode::Char('d'), JoypadInput::RIGHT),
+            (KeyCode::Char('s'), JoypadInput::DOWN),
+            (KeyCode::Char('j'), JoypadInput::B),
+            (KeyCode::Char('k'), JoypadInput::A),
+            (KeyCode::Char('u'), JoypadInput::START),
+            (KeyCode::Char('i'), JoypadInput::SELECT),
+        ])
+    }
 }
 
 /*
@@ -57,16 +94,23 @@ pub struct App {
     last_frame: Instant,
     frame_counter: u32,
     tab: u8,
+    keymap: KeyMap,
+    speed: f32,
+    sprite_palette: SpritePalette,
 }
 
+// The display refresh rate, the emulator runs `speed` times this much per frame
+const FRAME_TIME: Duration = Duration::from_micros(16666);
+
 impl App {
     /// runs the application's main loop until the user quits
     pub fn run(&mut self, terminal: &mut DefaultTerminal) -> io::Result<()> {
         while !self.exit {
             if !self.halt {
                 let emu_before = Instant::now();
-                self.gb.run_frame();
-                self.frame_counter += 1;
+                if self.gb.run_for(FRAME_TIME, self.speed) {
+                    self.frame_counter += 1;
+                }
                 self.emu_time = Instant::now().duration_since(emu_before);
             }
             let draw_before = Instant::now();
@@ -76,10 +120,8 @@ impl App {
             self.counter += 1;
 
             /* Frame rate caps -> 60fps */
-            let min_frame_time = Duration::from_micros(16666);
-
             /* Spin here since sleeps are not accurate */
-            while Instant::now().duration_since(self.last_frame) < min_frame_time {}
+            while Instant::now().duration_since(self.last_frame) < FRAME_TIME {}
             self.last_frame = Instant::now();
         }
         Ok(())
@@ -119,6 +161,8 @@ impl App {
                 .y_bounds([0.0, BKG_WIDTH as f64]);
 
             frame.render_widget(canvas, main);
+        } else if self.tab == 4 {
+            frame.render_widget(TilesetWidget(&self.gb.cpu.bus.ppu), main);
         } else {
             let canvas = Canvas::default()
                 .marker(ratatui::symbols::Marker::HalfBlock)
@@ -146,35 +190,75 @@ impl App {
                 Line::from(format!("LYC: {:?}", ppu_state.lyc)),
                 Line::from(format!("MODE: {:?}", ppu_state.mode)),
                 Line::from(format!("STAT: {:?}", ppu_state.stat)),
+                Line::from(format!("BG Map: {:#06X}", ppu_state.bg_tile_map_addr)),
+                Line::from(format!("Win Map: {:#06X}", ppu_state.window_tile_map_addr)),
+                Line::from(format!("Tile Data: {:#06X}", ppu_state.tile_data_addr)),
+                Line::from(format!("Sprites on line: {}", ppu_state.sprites_on_line)),
+                Line::from(format!("DMA cycles: {}", ppu_state.dma_remaining)),
             ]),
             bot_left,
         );
 
         let fps = |d: Duration| 1.0 / d.as_secs_f64();
 
+        let mut info = vec![
+            Line::from(format!("GbRs Size: {:?}", size_of_val(&self.gb))),
+            Line::from(format!(
+                "Emu Time: {}us ({:.1} fps)",
+                self.emu_time.as_micros(),
+                fps(self.emu_time)
+            )),
+            Line::from(format!(
+                "Draw Time: {}us ({:.1} fps)",
+                self.draw_time.as_micros(),
+                fps(self.draw_time)
+            )),
+            Line::from(format!("Frame counter: {}", self.frame_counter)),
+            Line::from(format!("Speed: {}x", self.speed)),
+            Line::from(format!("Interrupts: {}", self.gb.cpu.bus.int_controller)),
+            Line::from({
+                let timer = self.gb.cpu.bus.timer.state();
+                format!(
+                    "Timer: DIV:{:02X} TIMA:{:02X} TMA:{:02X} TAC:{:02X}{}",
+                    timer.div,
+                    timer.tima,
+                    timer.tma,
+                    timer.tac,
+                    if timer.enabled { "" } else { " (off)" }
+                )
+            }),
+            Line::from(match self.gb.cpu.fault() {
+                Some(fault) => format!("CPU fault: {:X?}", fault),
+                None => String::new(),
+            }),
+            Line::from(format!(
+                "Game Title: {:?}",
+                self.gb.cpu.bus.cart.get_header().title
+            )),
+            Line::from(format!(
+                "Color Mode: {:?}",
+                self.gb.cpu.bus.cart.get_header().gbc_flag
+            )),
+            Line::from(format!(
+                "ROM bank: {:#04X} / RAM bank: {:#04X}",
+                self.gb.cpu.bus.cart.current_rom_bank(),
+                self.gb.cpu.bus.cart.current_ram_bank()
+            )),
+            Line::from(""),
+        ];
+        for instr in self.gb.cpu.get_next_instrs::<8>() {
+            info.push(Line::from(format!(
+                "{:04X}: {}",
+                instr.addr,
+                instr.mnemonic.as_str()
+            )));
+        }
+        frame.render_widget(Paragraph::new(info), top_right);
+
         frame.render_widget(
-            Paragraph::new(vec![
-                Line::from(format!("GbRs Size: {:?}", size_of_val(&self.gb))),
-                Line::from(format!(
-                    "Emu Time: {}us ({:.1} fps)",
-                    self.emu_time.as_micros(),
-                    fps(self.emu_time)
-                )),
-                Line::from(format!(
-                    "Draw Time: {}us ({:.1} fps)",
-                    self.draw_time.as_micros(),
-                    fps(self.draw_time)
-                )),
-                Line::from(format!("Frame counter: {}", self.frame_counter)),
-                Line::from(format!(
-                    "Game Title: {:?}",
-                    self.gb.cpu.bus.cart.get_header().title
-                )),
-            ]),
-            top_right,
+            SpritesWidget(&self.gb.cpu.bus.ppu, self.sprite_palette),
+            bottom_right,
         );
-
-        frame.render_widget(SpritesWidget(&self.gb.cpu.bus.ppu), bottom_right);
     }
 
     fn handle_events(&mut self) -> io::Result<()> {
@@ -191,19 +275,27 @@ impl App {
                     _ => JoypadDirection::PRESS,
                 };
 
+                if let Some(button) = self.keymap.get(key_event.code) {
+                    self.gb.cpu.bus.joypad_input(button, dir);
+                    return Ok(());
+                }
+
                 match key_event.code {
                     KeyCode::Char('1') => self.tab = 1,
                     KeyCode::Char('2') => self.tab = 2,
                     KeyCode::Char('3') => self.tab = 3,
+                    KeyCode::Char('4') => self.tab = 4,
                     KeyCode::Char('q') => self.exit = true,
-                    KeyCode::Char('w') => self.gb.cpu.bus.joypad.input(JoypadInput::UP, dir),
-                    KeyCode::Char('a') => self.gb.cpu.bus.joypad.input(JoypadInput::LEFT, dir),
-                    KeyCode::Char('d') => self.gb.cpu.bus.joypad.input(JoypadInput::RIGHT, dir),
-                    KeyCode::Char('s') => self.gb.cpu.bus.joypad.input(JoypadInput::DOWN, dir),
-                    KeyCode::Char('j') => self.gb.cpu.bus.joypad.input(JoypadInput::B, dir),
-                    KeyCode::Char('k') => self.gb.cpu.bus.joypad.input(JoypadInput::A, dir),
-                    KeyCode::Char('u') => self.gb.cpu.bus.joypad.input(JoypadInput::START, dir),
-                    KeyCode::Char('i') => self.gb.cpu.bus.joypad.input(JoypadInput::SELECT, dir),
+                    KeyCode::Char('+') | KeyCode::Char('=') => {
+                        if key_event.kind == KeyEventKind::Press {
+                            self.speed = (self.speed * 2.0).min(8.0);
+                        }
+                    }
+                    KeyCode::Char('-') => {
+                        if key_event.kind == KeyEventKind::Press {
+                            self.speed = (self.speed / 2.0).max(0.125);
+                        }
+                    }
                     KeyCode::Char('b') => self.halt = true,
                     KeyCode::Char('c') => self.halt = false,
                     KeyCode::Char('f') => {
@@ -225,6 +317,11 @@ impl App {
                             self.gb.run_line();
                         }
                     }
+                    KeyCode::Char('p') => {
+                        if key_event.kind == KeyEventKind::Press {
+                            self.sprite_palette = self.sprite_palette.next();
+                        }
+                    }
                     _ => {}
                 }
             }
@@ -234,7 +331,7 @@ impl App {
     }
 }
 
-fn run_tui(gb: GbRs<VecCart>) -> io::Result<()> {
+fn run_tui(gb: GbRs<VecCart>, keymap: KeyMap) -> io::Result<()> {
     let mut app = App {
         counter: 0,
         exit: false,
@@ -245,6 +342,9 @@ fn run_tui(gb: GbRs<VecCart>) -> io::Result<()> {
         emu_time: Duration::from_secs(1),
         last_frame: Instant::now(),
         tab: 1,
+        keymap,
+        speed: 1.0,
+        sprite_palette: SpritePalette::default(),
     };
 
     let mut terminal = ratatui::init();
@@ -268,14 +368,23 @@ fn run_tui(gb: GbRs<VecCart>) -> io::Result<()> {
 fn main() -> std::io::Result<()> {
     let args = Args::parse();
 
+    let mut keymap = KeyMap::default();
+    for bind in &args.binds {
+        let (key, button) = bind.split_once('=').expect("Bindings look like KEY=BUTTON");
+        let key = key.chars().next().expect("Missing key to bind");
+        let button = button.parse().expect("Unknown button name");
+        keymap.bind(KeyCode::Char(key), button);
+    }
+
     let rom_path = std::path::Path::new(&args.rom);
     let rom = std::fs::read(rom_path)?;
 
     let rom = VecCart::from_slice(&rom, Some("savedgames/"));
 
-    let gb = GbRs::new(rom);
+    let mut gb = GbRs::new(rom);
+    gb.cpu.bus.cart.set_clock(Box::new(SystemClock));
 
-    run_tui(gb)?;
+    run_tui(gb, keymap)?;
 
     Ok(())
 }
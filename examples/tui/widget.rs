@@ -1,6 +1,6 @@
-use gb_rs::{
-    ppu::{BKG_WIDTH, PPU, SCREEN_HEIGHT, SCREEN_WIDTH},
-    tile::Tile,
+use gb_rs::ppu::{
+    tile::{Palette, Tile as VramTile},
+    BKG_WIDTH, PPU, SCREEN_HEIGHT, SCREEN_WIDTH,
 };
 
 use ratatui::{
@@ -16,7 +16,8 @@ use ratatui::{
 pub struct GameFrame<'a>(pub &'a PPU);
 pub struct Background<'a>(pub &'a PPU);
 pub struct BkWindow<'a>(pub &'a PPU);
-pub struct TileShape<'a>(pub Tile<'a>);
+pub struct TileShape(pub [[u8; 8]; 8]);
+pub struct VramTileShape<'a>(pub &'a VramTile);
 
 impl<'a> Shape for GameFrame<'a> {
     fn draw(&self, painter: &mut Painter<'_, '_>) {
@@ -59,22 +60,69 @@ impl<'a> Shape for BkWindow<'a> {
     }
 }
 
-impl Shape for TileShape<'_> {
+impl Shape for TileShape {
     fn draw(&self, painter: &mut Painter<'_, '_>) {
-        let oam_tile = self.0.render();
         for y in 0..8 {
             for x in 0..8 {
                 if let Some((x2, y2)) = painter.get_point(x as f64, y as f64) {
-                    painter.paint(x2, y2, to_color(oam_tile[7 - y][x]));
+                    painter.paint(x2, y2, to_color(self.0[7 - y][x]));
                 }
             }
         }
     }
 }
 
+impl Shape for VramTileShape<'_> {
+    fn draw(&self, painter: &mut Painter<'_, '_>) {
+        let tile = self.0.render();
+        for y in 0..8 {
+            for x in 0..8 {
+                if let Some((x2, y2)) = painter.get_point(x as f64, y as f64) {
+                    painter.paint(x2, y2, to_color(tile[7 - y][x]));
+                }
+            }
+        }
+    }
+}
+
+// The palette sprite tiles are shown with
+#[derive(Clone, Copy, Default)]
+pub enum SpritePalette {
+    #[default]
+    Obp0,
+    Obp1,
+    Bgp,
+}
+
+impl SpritePalette {
+    pub fn next(self) -> Self {
+        match self {
+            SpritePalette::Obp0 => SpritePalette::Obp1,
+            SpritePalette::Obp1 => SpritePalette::Bgp,
+            SpritePalette::Bgp => SpritePalette::Obp0,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            SpritePalette::Obp0 => "OBP0",
+            SpritePalette::Obp1 => "OBP1",
+            SpritePalette::Bgp => "BGP",
+        }
+    }
+
+    fn palette(self, ppu: &PPU) -> Palette {
+        match self {
+            SpritePalette::Obp0 => ppu.obp0(),
+            SpritePalette::Obp1 => ppu.obp1(),
+            SpritePalette::Bgp => ppu.bgp(),
+        }
+    }
+}
+
 pub struct GameWidget<'a>(pub &'a PPU);
-pub struct SpritesWidget<'a>(pub &'a PPU);
-pub struct TilesetWidget<'a>(pub &'a [Tile<'a>], pub &'static str);
+pub struct SpritesWidget<'a>(pub &'a PPU, pub SpritePalette);
+pub struct TilesetWidget<'a>(pub &'a PPU);
 
 impl Widget for GameWidget<'_> {
     fn render(self, area: Rect, buf: &mut Buffer) {
@@ -103,7 +151,10 @@ impl Widget for SpritesWidget<'_> {
         let sprite_map = self.0.get_sprite_map();
         let oams = sprite_map.get_oams_screen();
 
-        let outer_block = Block::bordered().title(format!("Sprites ({})", oams.len()));
+        let palette = self.1.palette(self.0);
+
+        let outer_block =
+            Block::bordered().title(format!("Sprites ({}, {})", oams.len(), self.1.name()));
         let inner = outer_block.inner(area);
 
         let grid = {
@@ -133,7 +184,7 @@ impl Widget for SpritesWidget<'_> {
                 let canvas = Canvas::default()
                     .marker(ratatui::symbols::Marker::HalfBlock)
                     .paint(|ctx| {
-                        let tile = self.0.get_sprite_tile(data.tile_idx().into());
+                        let tile = self.0.render_tile(data.tile_idx().into(), palette);
                         ctx.draw(&TileShape(tile));
                     })
                     .x_bounds([0.0, 8.0])
@@ -147,24 +198,39 @@ impl Widget for SpritesWidget<'_> {
     }
 }
 
-/*
+// The background tilemap in row order, as many tiles as fit
 impl Widget for TilesetWidget<'_> {
     fn render(self, area: Rect, buf: &mut Buffer) {
+        const TILE_LEN: u16 = 8;
 
-        let outer_block = Block::bordered().title(format!("{} Tiles", self.1));
+        let outer_block = Block::bordered().title("BG Tiles");
         let inner = outer_block.inner(area);
 
+        let rows = (inner.height / (TILE_LEN / 2)) as usize;
+        let cols = (inner.width / TILE_LEN) as usize;
 
-        let vert = Layout::vertical(Constraint::from_lengths([TILE_LEN / 2; GRID_LEN]))
-                .flex(ratatui::layout::Flex::SpaceAround);
-            let slots: [Rect; GRID_LEN] = vert.areas(inner);
+        let vert = Layout::vertical(Constraint::from_lengths(vec![TILE_LEN / 2; rows]));
+        let horiz = Layout::horizontal(Constraint::from_lengths(vec![TILE_LEN; cols]));
 
-            let horiz = Layout::horizontal(Constraint::from_lengths([TILE_LEN; GRID_LEN]))
-                .flex(ratatui::layout::Flex::SpaceAround);
+        let slots = vert
+            .split(inner)
+            .iter()
+            .flat_map(|row| horiz.split(*row).to_vec())
+            .collect::<Vec<_>>();
 
+        for (slot, (_, tile)) in slots.into_iter().zip(self.0.bg_tilemap()) {
+            let canvas = Canvas::default()
+                .marker(ratatui::symbols::Marker::HalfBlock)
+                .paint(|ctx| ctx.draw(&VramTileShape(tile)))
+                .x_bounds([0.0, 8.0])
+                .y_bounds([0.0, 8.0]);
+
+            canvas.render(slot, buf);
+        }
+
+        outer_block.render(area, buf);
     }
 }
-*/
 
 fn to_color(color: u8) -> Color {
     match color {
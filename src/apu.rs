@@ -0,0 +1,1079 @@
+use heapless::Vec;
+
+#[cfg(any(test, feature = "std"))]
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+// The APU runs off the same 1MiHz M-cycle clock as the rest of the system
+const CYCLES_PER_SECOND: u32 = 1 << 20;
+pub const DEFAULT_SAMPLE_RATE: u32 = 48000;
+// Room for a few frames worth of interleaved stereo samples at the default rate
+pub const SAMPLE_BUF_LEN: usize = 4096;
+
+// The frame sequencer steps on the falling edge of this system counter bit (512Hz)
+const FRAME_SEQ_BIT: u16 = 1 << 10;
+
+// Each channel outputs -15..=15. All four channels at full volume with the
+// master volume at its maximum still fit in an i16.
+const AMPLITUDE: i16 = 64;
+
+// The waveform for each NRx1 duty setting, played from the MSB
+const DUTY_PATTERNS: [u8; 4] = [0b0000_0001, 0b1000_0001, 0b1000_0111, 0b0111_1110];
+
+#[derive(Default)]
+struct Envelope {
+    initial: u8,
+    add: bool,
+    period: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    fn write(&mut self, val: u8) {
+        self.initial = val >> 4;
+        self.add = val & 0x8 != 0;
+        self.period = val & 0x7;
+    }
+
+    fn read(&self) -> u8 {
+        (self.initial << 4) | ((self.add as u8) << 3) | self.period
+    }
+
+    // The channel's DAC is off when the upper 5 bits of NRx2 are all 0
+    fn dac_enabled(&self) -> bool {
+        self.read() & 0xF8 != 0
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.initial;
+        self.timer = self.period;
+    }
+
+    fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer != 0 {
+            return;
+        }
+
+        self.timer = self.period;
+        if self.add && self.volume < 15 {
+            self.volume += 1;
+        } else if !self.add && self.volume > 0 {
+            self.volume -= 1;
+        }
+    }
+}
+
+#[derive(Default)]
+struct Sweep {
+    period: u8,
+    negate: bool,
+    shift: u8,
+    timer: u8,
+    enabled: bool,
+    shadow: u16,
+}
+
+impl Sweep {
+    fn write(&mut self, val: u8) {
+        self.period = (val >> 4) & 0x7;
+        self.negate = val & 0x8 != 0;
+        self.shift = val & 0x7;
+    }
+
+    fn read(&self) -> u8 {
+        0x80 | (self.period << 4) | ((self.negate as u8) << 3) | self.shift
+    }
+
+    fn next_freq(&self) -> u16 {
+        let delta = self.shadow >> self.shift;
+        if self.negate {
+            self.shadow - delta
+        } else {
+            self.shadow + delta
+        }
+    }
+}
+
+#[derive(Default)]
+struct Pulse {
+    enabled: bool,
+    duty: u8,
+    duty_pos: u8,
+    freq: u16,
+    // M-cycles until the next step through the duty pattern
+    timer: u16,
+    length: u8,
+    length_en: bool,
+    envelope: Envelope,
+    // Only channel 1 has a sweep unit
+    sweep: Option<Sweep>,
+}
+
+impl Pulse {
+    fn with_sweep() -> Self {
+        Self {
+            sweep: Some(Sweep::default()),
+            ..Default::default()
+        }
+    }
+
+    // `reg` is 0..=4 for NRx0..=NRx4
+    fn write(&mut self, reg: u16, val: u8) {
+        match reg {
+            0 => {
+                if let Some(sweep) = &mut self.sweep {
+                    sweep.write(val);
+                }
+            }
+            1 => {
+                self.duty = val >> 6;
+                self.length = 64 - (val & 0x3F);
+            }
+            2 => {
+                self.envelope.write(val);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => {
+                self.freq = (self.freq & 0x700) | val as u16;
+            }
+            4 => {
+                self.freq = (self.freq & 0xFF) | ((val as u16 & 0x7) << 8);
+                self.length_en = val & 0x40 != 0;
+                if val & 0x80 != 0 {
+                    self.trigger();
+                }
+            }
+            _ => {
+                unreachable!("Invalid pulse register {:?}", reg);
+            }
+        }
+    }
+
+    // Write-only bits read back as 1
+    fn read(&self, reg: u16) -> u8 {
+        match reg {
+            0 => self.sweep.as_ref().map_or(0xFF, Sweep::read),
+            1 => (self.duty << 6) | 0x3F,
+            2 => self.envelope.read(),
+            3 => 0xFF,
+            4 => ((self.length_en as u8) << 6) | 0xBF,
+            _ => {
+                unreachable!("Invalid pulse register {:?}", reg);
+            }
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        if self.length == 0 {
+            self.length = 64;
+        }
+        self.timer = 2048 - self.freq;
+        self.envelope.trigger();
+
+        if let Some(sweep) = &mut self.sweep {
+            sweep.shadow = self.freq;
+            sweep.timer = if sweep.period == 0 { 8 } else { sweep.period };
+            sweep.enabled = sweep.period != 0 || sweep.shift != 0;
+            if sweep.shift != 0 && sweep.next_freq() > 2047 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    // Advances one M-cycle
+    fn tick(&mut self) {
+        if self.timer > 1 {
+            self.timer -= 1;
+            return;
+        }
+
+        self.timer = 2048 - self.freq;
+        self.duty_pos = (self.duty_pos + 1) % 8;
+    }
+
+    fn clock_length(&mut self) {
+        if self.length_en && self.length > 0 {
+            self.length -= 1;
+            if self.length == 0 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn clock_sweep(&mut self) {
+        let Some(sweep) = &mut self.sweep else {
+            return;
+        };
+
+        sweep.timer = sweep.timer.saturating_sub(1);
+        if sweep.timer != 0 {
+            return;
+        }
+
+        sweep.timer = if sweep.period == 0 { 8 } else { sweep.period };
+        if !sweep.enabled || sweep.period == 0 {
+            return;
+        }
+
+        let freq = sweep.next_freq();
+        if freq > 2047 {
+            self.enabled = false;
+            return;
+        }
+
+        if sweep.shift != 0 {
+            sweep.shadow = freq;
+            self.freq = freq;
+
+            // The new frequency is checked for overflow again right away
+            if sweep.next_freq() > 2047 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    // A symmetric square wave, so a silent channel sits at 0
+    fn output(&self) -> i16 {
+        if !self.enabled {
+            return 0;
+        }
+
+        let volume = self.envelope.volume as i16;
+        if (DUTY_PATTERNS[self.duty as usize] >> (7 - self.duty_pos)) & 0x1 == 0x1 {
+            volume
+        } else {
+            -volume
+        }
+    }
+}
+
+// Shifts applied to the 4-bit wave samples for each NR32 output level
+const WAVE_VOLUME_SHIFT: [u8; 4] = [4, 0, 1, 2];
+
+// Noise timer periods for each NR43 divisor code, in M-cycles
+const NOISE_DIVISORS: [u32; 8] = [2, 4, 8, 12, 16, 20, 24, 28];
+
+#[derive(Default)]
+struct Wave {
+    enabled: bool,
+    dac_en: bool,
+    level: u8,
+    freq: u16,
+    // Half M-cycles until the next sample, the wave channel runs at twice
+    // the rate of the pulse channels
+    timer: u16,
+    pos: u8,
+    // The sample last read from RAM, which is what's played. Triggering
+    // doesn't refill it, so it keeps playing until the position next moves.
+    sample_buf: u8,
+    length: u16,
+    length_en: bool,
+    // 32 4-bit samples, played from the high nibble of each byte first
+    ram: [u8; 16],
+}
+
+impl Wave {
+    // `reg` is 0..=4 for NR30..=NR34
+    fn write(&mut self, reg: u16, val: u8) {
+        match reg {
+            0 => {
+                self.dac_en = val & 0x80 != 0;
+                if !self.dac_en {
+                    self.enabled = false;
+                }
+            }
+            1 => {
+                self.length = 256 - val as u16;
+            }
+            2 => {
+                self.level = (val >> 5) & 0x3;
+            }
+            3 => {
+                self.freq = (self.freq & 0x700) | val as u16;
+            }
+            4 => {
+                self.freq = (self.freq & 0xFF) | ((val as u16 & 0x7) << 8);
+                self.length_en = val & 0x40 != 0;
+                if val & 0x80 != 0 {
+                    self.trigger();
+                }
+            }
+            _ => {
+                unreachable!("Invalid wave register {:?}", reg);
+            }
+        }
+    }
+
+    fn read(&self, reg: u16) -> u8 {
+        match reg {
+            0 => ((self.dac_en as u8) << 7) | 0x7F,
+            1 => 0xFF,
+            2 => (self.level << 5) | 0x9F,
+            3 => 0xFF,
+            4 => ((self.length_en as u8) << 6) | 0xBF,
+            _ => {
+                unreachable!("Invalid wave register {:?}", reg);
+            }
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_en;
+        if self.length == 0 {
+            self.length = 256;
+        }
+        // The position moves before a sample is read, so the first one after
+        // a trigger is sample 1. It comes 3 half M-cycles later than a
+        // normal period would.
+        self.timer = 2048 - self.freq + 3;
+        self.pos = 0;
+    }
+
+    // Advances one M-cycle
+    fn tick(&mut self) {
+        for _ in 0..2 {
+            if self.timer > 1 {
+                self.timer -= 1;
+            } else {
+                self.timer = 2048 - self.freq;
+                self.pos = (self.pos + 1) % 32;
+                self.sample_buf = self.sample(self.pos);
+            }
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if self.length_en && self.length > 0 {
+            self.length -= 1;
+            if self.length == 0 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn output(&self) -> i16 {
+        if !self.enabled {
+            return 0;
+        }
+
+        // Center the sample first so that lower volumes stay centered on 0
+        (self.sample_buf as i16 * 2 - 15) >> WAVE_VOLUME_SHIFT[self.level as usize]
+    }
+
+    fn sample(&self, pos: u8) -> u8 {
+        let byte = self.ram[pos as usize / 2];
+        if pos.is_multiple_of(2) {
+            byte >> 4
+        } else {
+            byte & 0xF
+        }
+    }
+}
+
+struct Noise {
+    enabled: bool,
+    shift: u8,
+    width7: bool,
+    divisor: u8,
+    // M-cycles until the LFSR is next clocked
+    timer: u32,
+    lfsr: u16,
+    length: u8,
+    length_en: bool,
+    envelope: Envelope,
+}
+
+impl Noise {
+    // `reg` is 1..=4 for NR41..=NR44, there is no NR40
+    fn write(&mut self, reg: u16, val: u8) {
+        match reg {
+            1 => {
+                self.length = 64 - (val & 0x3F);
+            }
+            2 => {
+                self.envelope.write(val);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => {
+                self.shift = val >> 4;
+                self.width7 = val & 0x8 != 0;
+                self.divisor = val & 0x7;
+            }
+            4 => {
+                self.length_en = val & 0x40 != 0;
+                if val & 0x80 != 0 {
+                    self.trigger();
+                }
+            }
+            _ => {
+                unreachable!("Invalid noise register {:?}", reg);
+            }
+        }
+    }
+
+    fn read(&self, reg: u16) -> u8 {
+        match reg {
+            1 => 0xFF,
+            2 => self.envelope.read(),
+            3 => (self.shift << 4) | ((self.width7 as u8) << 3) | self.divisor,
+            4 => ((self.length_en as u8) << 6) | 0xBF,
+            _ => {
+                unreachable!("Invalid noise register {:?}", reg);
+            }
+        }
+    }
+
+    fn period(&self) -> u32 {
+        NOISE_DIVISORS[self.divisor as usize] << self.shift
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        if self.length == 0 {
+            self.length = 64;
+        }
+        self.timer = self.period();
+        self.lfsr = 0x7FFF;
+        self.envelope.trigger();
+    }
+
+    // Advances one M-cycle
+    fn tick(&mut self) {
+        if self.timer > 1 {
+            self.timer -= 1;
+            return;
+        }
+
+        self.timer = self.period();
+
+        let bit = (self.lfsr ^ (self.lfsr >> 1)) & 0x1;
+        self.lfsr = (self.lfsr >> 1) | (bit << 14);
+        if self.width7 {
+            self.lfsr = (self.lfsr & !(1 << 6)) | (bit << 6);
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if self.length_en && self.length > 0 {
+            self.length -= 1;
+            if self.length == 0 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    // The output is high when bit 0 of the LFSR is clear
+    fn output(&self) -> i16 {
+        if !self.enabled {
+            return 0;
+        }
+
+        let volume = self.envelope.volume as i16;
+        if self.lfsr & 0x1 == 0 {
+            volume
+        } else {
+            -volume
+        }
+    }
+}
+
+impl Default for Noise {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            shift: 0,
+            width7: false,
+            divisor: 0,
+            timer: NOISE_DIVISORS[0],
+            lfsr: 0x7FFF,
+            length: 0,
+            length_en: false,
+            envelope: Envelope::default(),
+        }
+    }
+}
+
+pub struct APU {
+    ch1: Pulse,
+    ch2: Pulse,
+    ch3: Wave,
+    ch4: Noise,
+    nr50: u8,
+    nr51: u8,
+    power: bool,
+    frame_seq_step: u8,
+    frame_seq_bit: bool,
+    sample_rate: u32,
+    // Sub-sample progress, in units of 1/CYCLES_PER_SECOND samples
+    sample_acc: u32,
+    samples: Vec<i16, SAMPLE_BUF_LEN>,
+}
+
+impl APU {
+    pub fn new() -> Self {
+        let mut apu = Self {
+            ch1: Pulse::with_sweep(),
+            ch2: Pulse::default(),
+            ch3: Wave::default(),
+            ch4: Noise::default(),
+            nr50: 0,
+            nr51: 0,
+            power: true,
+            frame_seq_step: 0,
+            frame_seq_bit: false,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            sample_acc: 0,
+            samples: Vec::new(),
+        };
+
+        // The state the boot ROM leaves behind
+        for (addr, val) in [
+            (0xFF10, 0x80),
+            (0xFF11, 0xBF),
+            (0xFF12, 0xF3),
+            (0xFF14, 0x3F),
+            (0xFF16, 0x3F),
+            (0xFF19, 0x3F),
+            (0xFF1A, 0x7F),
+            (0xFF1C, 0x9F),
+            (0xFF1E, 0x3F),
+            (0xFF20, 0xFF),
+            (0xFF23, 0x3F),
+            (0xFF24, 0x77),
+            (0xFF25, 0xF3),
+        ] {
+            apu.write(addr, val);
+        }
+        apu
+    }
+
+    pub fn set_sample_rate(&mut self, rate: u32) {
+        self.sample_rate = rate;
+        self.sample_acc = 0;
+    }
+
+    // Takes the interleaved left/right samples produced since the last call.
+    // Samples are dropped if this isn't called often enough to keep up.
+    pub fn get_samples(&mut self) -> Vec<i16, SAMPLE_BUF_LEN> {
+        core::mem::take(&mut self.samples)
+    }
+
+    pub fn write(&mut self, addr: u16, val: u8) {
+        // Only NR52 and wave RAM can be written while the APU is off
+        if !self.power && !matches!(addr, 0xFF26 | 0xFF30..=0xFF3F) {
+            return;
+        }
+
+        match addr {
+            0xFF10..=0xFF14 => self.ch1.write(addr - 0xFF10, val),
+            0xFF15..=0xFF19 => self.ch2.write(addr - 0xFF15, val),
+            0xFF1A..=0xFF1E => self.ch3.write(addr - 0xFF1A, val),
+            0xFF1F..=0xFF23 => {
+                if addr != 0xFF1F {
+                    self.ch4.write(addr - 0xFF1F, val);
+                }
+            }
+            0xFF24 => self.nr50 = val,
+            0xFF25 => self.nr51 = val,
+            0xFF26 => self.set_power(val & 0x80 != 0),
+            0xFF30..=0xFF3F => self.ch3.ram[addr as usize - 0xFF30] = val,
+            _ => {
+                unreachable!("Invalid write to APU? addr:{:?}, val:{:?}", addr, val);
+            }
+        }
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF10..=0xFF14 => self.ch1.read(addr - 0xFF10),
+            0xFF15..=0xFF19 => self.ch2.read(addr - 0xFF15),
+            0xFF1A..=0xFF1E => self.ch3.read(addr - 0xFF1A),
+            0xFF1F => 0xFF,
+            0xFF20..=0xFF23 => self.ch4.read(addr - 0xFF1F),
+            0xFF24 => self.nr50,
+            0xFF25 => self.nr51,
+            0xFF26 => {
+                ((self.power as u8) << 7)
+                    | 0x70
+                    | ((self.ch4.enabled as u8) << 3)
+                    | ((self.ch3.enabled as u8) << 2)
+                    | ((self.ch2.enabled as u8) << 1)
+                    | self.ch1.enabled as u8
+            }
+            0xFF30..=0xFF3F => self.ch3.ram[addr as usize - 0xFF30],
+            _ => {
+                unreachable!("Invalid read from APU? addr:{:?}", addr);
+            }
+        }
+    }
+
+    // Powering off clears every register except wave RAM, powering back on
+    // restarts the frame sequencer
+    fn set_power(&mut self, on: bool) {
+        if !on {
+            let ram = self.ch3.ram;
+            self.ch1 = Pulse::with_sweep();
+            self.ch2 = Pulse::default();
+            self.ch3 = Wave {
+                ram,
+                ..Default::default()
+            };
+            self.ch4 = Noise::default();
+            self.nr50 = 0;
+            self.nr51 = 0;
+        } else if !self.power {
+            self.frame_seq_step = 0;
+        }
+        self.power = on;
+    }
+
+    // Advances one M-cycle. `system_counter` is the timer's internal counter,
+    // which drives the frame sequencer like DIV does on hardware.
+    pub fn tick(&mut self, system_counter: u16) {
+        let seq_bit = system_counter & FRAME_SEQ_BIT != 0;
+        if self.frame_seq_bit && !seq_bit && self.power {
+            self.step_frame_sequencer();
+        }
+        self.frame_seq_bit = seq_bit;
+
+        self.ch1.tick();
+        self.ch2.tick();
+        self.ch3.tick();
+        self.ch4.tick();
+
+        self.sample_acc += self.sample_rate;
+        if self.sample_acc >= CYCLES_PER_SECOND {
+            self.sample_acc -= CYCLES_PER_SECOND;
+            self.push_sample();
+        }
+    }
+
+    fn step_frame_sequencer(&mut self) {
+        if self.frame_seq_step.is_multiple_of(2) {
+            self.ch1.clock_length();
+            self.ch2.clock_length();
+            self.ch3.clock_length();
+            self.ch4.clock_length();
+        }
+
+        if self.frame_seq_step == 2 || self.frame_seq_step == 6 {
+            self.ch1.clock_sweep();
+        }
+
+        if self.frame_seq_step == 7 {
+            self.ch1.envelope.clock();
+            self.ch2.envelope.clock();
+            self.ch4.envelope.clock();
+        }
+
+        self.frame_seq_step = (self.frame_seq_step + 1) % 8;
+    }
+
+    fn push_sample(&mut self) {
+        if self.samples.len() + 2 > self.samples.capacity() {
+            return;
+        }
+
+        let mut left = 0;
+        let mut right = 0;
+        if self.power {
+            let outputs = [
+                self.ch1.output(),
+                self.ch2.output(),
+                self.ch3.output(),
+                self.ch4.output(),
+            ];
+            for (i, out) in outputs.into_iter().enumerate() {
+                if self.nr51 & (0x10 << i) != 0 {
+                    left += out;
+                }
+                if self.nr51 & (0x01 << i) != 0 {
+                    right += out;
+                }
+            }
+        }
+
+        left *= ((self.nr50 >> 4) & 0x7) as i16 + 1;
+        right *= (self.nr50 & 0x7) as i16 + 1;
+
+        let _ = self.samples.push(left * AMPLITUDE);
+        let _ = self.samples.push(right * AMPLITUDE);
+    }
+}
+
+impl Default for APU {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(any(test, feature = "std"))]
+impl Snapshot for Pulse {
+    fn save(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        w.u8(self.duty);
+        w.u8(self.duty_pos);
+        w.u16(self.freq);
+        w.u16(self.timer);
+        w.u8(self.length);
+        w.bool(self.length_en);
+        w.u8(self.envelope.read());
+        w.u8(self.envelope.volume);
+        w.u8(self.envelope.timer);
+        if let Some(sweep) = &self.sweep {
+            w.u8(sweep.read());
+            w.u8(sweep.timer);
+            w.bool(sweep.enabled);
+            w.u16(sweep.shadow);
+        }
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = r.bool()?;
+        self.duty = r.u8()?;
+        self.duty_pos = r.u8()?;
+        self.freq = r.u16()?;
+        self.timer = r.u16()?;
+        self.length = r.u8()?;
+        self.length_en = r.bool()?;
+        self.envelope.write(r.u8()?);
+        self.envelope.volume = r.u8()?;
+        self.envelope.timer = r.u8()?;
+        if let Some(sweep) = &mut self.sweep {
+            sweep.write(r.u8()?);
+            sweep.timer = r.u8()?;
+            sweep.enabled = r.bool()?;
+            sweep.shadow = r.u16()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(any(test, feature = "std"))]
+impl Snapshot for Wave {
+    fn save(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        w.bool(self.dac_en);
+        w.u8(self.level);
+        w.u16(self.freq);
+        w.u16(self.timer);
+        w.u8(self.pos);
+        w.u8(self.sample_buf);
+        w.u16(self.length);
+        w.bool(self.length_en);
+        w.bytes(&self.ram);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = r.bool()?;
+        self.dac_en = r.bool()?;
+        self.level = r.u8()?;
+        self.freq = r.u16()?;
+        self.timer = r.u16()?;
+        self.pos = r.u8()?;
+        self.sample_buf = r.u8()?;
+        self.length = r.u16()?;
+        self.length_en = r.bool()?;
+        r.bytes(&mut self.ram)?;
+        Ok(())
+    }
+}
+
+#[cfg(any(test, feature = "std"))]
+impl Snapshot for Noise {
+    fn save(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        w.u8(self.read(3));
+        w.u32(self.timer);
+        w.u16(self.lfsr);
+        w.u8(self.length);
+        w.bool(self.length_en);
+        w.u8(self.envelope.read());
+        w.u8(self.envelope.volume);
+        w.u8(self.envelope.timer);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = r.bool()?;
+        self.write(3, r.u8()?);
+        self.timer = r.u32()?;
+        self.lfsr = r.u16()?;
+        self.length = r.u8()?;
+        self.length_en = r.bool()?;
+        self.envelope.write(r.u8()?);
+        self.envelope.volume = r.u8()?;
+        self.envelope.timer = r.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(any(test, feature = "std"))]
+impl Snapshot for APU {
+    // Samples that haven't been collected yet aren't part of the state
+    fn save(&self, w: &mut StateWriter) {
+        self.ch1.save(w);
+        self.ch2.save(w);
+        self.ch3.save(w);
+        self.ch4.save(w);
+        w.u8(self.nr50);
+        w.u8(self.nr51);
+        w.bool(self.power);
+        w.u8(self.frame_seq_step);
+        w.bool(self.frame_seq_bit);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.ch1.load(r)?;
+        self.ch2.load(r)?;
+        self.ch3.load(r)?;
+        self.ch4.load(r)?;
+        self.nr50 = r.u8()?;
+        self.nr51 = r.u8()?;
+        self.power = r.bool()?;
+        self.frame_seq_step = r.u8()?;
+        self.frame_seq_bit = r.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs the APU with the system counter starting at `*counter`
+    fn run(apu: &mut APU, counter: &mut u16, cycles: usize) {
+        for _ in 0..cycles {
+            *counter = counter.wrapping_add(1);
+            apu.tick(*counter);
+        }
+    }
+
+    #[test]
+    fn pulse_duty() {
+        let mut apu = APU::new();
+        let mut counter = 0;
+        apu.set_sample_rate(CYCLES_PER_SECOND);
+        apu.write(0xFF25, 0x02);
+        apu.write(0xFF24, 0x00);
+
+        // Channel 2, 50% duty at volume 15, stepping through the duty
+        // pattern every 4 M-cycles
+        apu.write(0xFF16, 0x80);
+        apu.write(0xFF17, 0xF0);
+        apu.write(0xFF18, 0xFC);
+        apu.write(0xFF19, 0x87);
+        assert_eq!(apu.read(0xFF26) & 0x3, 0x2);
+
+        apu.get_samples();
+        run(&mut apu, &mut counter, 32);
+        let samples = apu.get_samples();
+        assert_eq!(samples.len(), 64);
+
+        // Only the right side is enabled
+        let high = 15 * AMPLITUDE;
+        let right: std::vec::Vec<_> = samples.iter().skip(1).step_by(2).copied().collect();
+        assert!(samples.iter().step_by(2).all(|&s| s == 0));
+        assert_eq!(right.iter().filter(|&&s| s == high).count(), 16);
+        assert_eq!(right.iter().filter(|&&s| s == -high).count(), 16);
+    }
+
+    #[test]
+    fn length_and_envelope() {
+        let mut apu = APU::new();
+        let mut counter = 0;
+
+        // Length of 2, envelope decreasing every step from 2
+        apu.write(0xFF11, 0x3E);
+        apu.write(0xFF12, 0x21);
+        apu.write(0xFF14, 0xC0);
+        assert_eq!(apu.read(0xFF26) & 0x1, 0x1);
+        assert_eq!(apu.ch1.envelope.volume, 2);
+
+        // The length counter is clocked at 256Hz, so 2 ticks of it
+        // take 4 frame sequencer steps
+        run(&mut apu, &mut counter, 4 * 2048);
+        assert_eq!(apu.read(0xFF26) & 0x1, 0x0);
+
+        // Without the length enabled the envelope runs down to 0 instead
+        apu.write(0xFF14, 0x80);
+        run(&mut apu, &mut counter, 8 * 2048);
+        assert_eq!(apu.ch1.envelope.volume, 1);
+        run(&mut apu, &mut counter, 8 * 2048);
+        assert_eq!(apu.ch1.envelope.volume, 0);
+        assert_eq!(apu.read(0xFF26) & 0x1, 0x1);
+    }
+
+    #[test]
+    fn sweep() {
+        let mut apu = APU::new();
+        let mut counter = 0;
+
+        // Sweep up by freq >> 1 on every sweep clock
+        apu.write(0xFF10, 0x11);
+        apu.write(0xFF12, 0xF0);
+        apu.write(0xFF13, 0x00);
+        apu.write(0xFF14, 0x82);
+
+        // The sweep is clocked on every 4th frame sequencer step
+        run(&mut apu, &mut counter, 4 * 2048);
+        assert_eq!(apu.ch1.freq, 0x300);
+        assert_eq!(apu.read(0xFF26) & 0x1, 0x1);
+
+        // Stepping to 0x6C0 is fine, but the overflow check
+        // right after it sees 0xA20 and stops the channel
+        run(&mut apu, &mut counter, 4 * 2048);
+        assert_eq!(apu.ch1.freq, 0x480);
+        run(&mut apu, &mut counter, 4 * 2048);
+        assert_eq!(apu.ch1.freq, 0x6C0);
+        assert_eq!(apu.read(0xFF26) & 0x1, 0x0);
+    }
+
+    #[test]
+    fn register_read_back() {
+        let apu = APU::new();
+        assert_eq!(apu.read(0xFF10), 0x80);
+        assert_eq!(apu.read(0xFF11), 0xBF);
+        assert_eq!(apu.read(0xFF13), 0xFF);
+        assert_eq!(apu.read(0xFF15), 0xFF);
+        assert_eq!(apu.read(0xFF1A), 0x7F);
+        assert_eq!(apu.read(0xFF1C), 0x9F);
+        assert_eq!(apu.read(0xFF1F), 0xFF);
+        assert_eq!(apu.read(0xFF26), 0xF0);
+    }
+
+    #[test]
+    fn wave_channel() {
+        let mut apu = APU::new();
+        let mut counter = 0;
+        apu.set_sample_rate(CYCLES_PER_SECOND);
+        apu.write(0xFF25, 0x40);
+        apu.write(0xFF24, 0x00);
+
+        // A ramp from 0 to 15 and back down
+        for i in 0..16 {
+            let hi = if i < 8 { 2 * i } else { 31 - 2 * i };
+            let lo = if i < 8 { 2 * i + 1 } else { 30 - 2 * i };
+            apu.write(0xFF30 + i as u16, (hi << 4) | lo);
+        }
+        assert_eq!(apu.read(0xFF30), 0x01);
+
+        // Full volume, one sample per M-cycle
+        apu.write(0xFF1A, 0x80);
+        apu.write(0xFF1C, 0x20);
+        apu.write(0xFF1D, 0xFE);
+        apu.write(0xFF1E, 0x87);
+        assert_eq!(apu.read(0xFF26) & 0x4, 0x4);
+
+        apu.get_samples();
+        run(&mut apu, &mut counter, 32);
+        let samples = apu.get_samples();
+        let left: std::vec::Vec<_> = samples.iter().step_by(2).copied().collect();
+        assert_eq!(left.iter().max(), Some(&(15 * AMPLITUDE)));
+        assert_eq!(left.iter().min(), Some(&(-15 * AMPLITUDE)));
+        assert!(samples.iter().skip(1).step_by(2).all(|&s| s == 0));
+
+        // Turning the DAC off stops the channel
+        apu.write(0xFF1A, 0x00);
+        assert_eq!(apu.read(0xFF26) & 0x4, 0x0);
+    }
+
+    #[test]
+    fn trigger() {
+        let mut apu = APU::new();
+        let mut counter = 0;
+
+        // Retriggering a pulse channel reloads its timer but carries on
+        // from the same step of the duty pattern
+        apu.write(0xFF17, 0xF0);
+        apu.write(0xFF18, 0xF0);
+        apu.write(0xFF19, 0x87);
+        run(&mut apu, &mut counter, 3 * 16 + 5);
+        assert_eq!(apu.ch2.duty_pos, 3);
+        apu.write(0xFF19, 0x87);
+        assert_eq!(apu.ch2.duty_pos, 3);
+        assert_eq!(apu.ch2.timer, 16);
+
+        // The wave channel restarts from the top of RAM, but plays the
+        // sample it was on until it reads sample 1
+        for i in 0..16 {
+            apu.write(0xFF30 + i, i as u8 * 0x11);
+        }
+        apu.write(0xFF1A, 0x80);
+        apu.write(0xFF1D, 0xF0);
+        apu.write(0xFF1E, 0x87);
+        run(&mut apu, &mut counter, 8 * 10 + 2);
+        assert_eq!((apu.ch3.pos, apu.ch3.sample_buf), (10, 5));
+
+        apu.write(0xFF1E, 0x87);
+        assert_eq!((apu.ch3.pos, apu.ch3.sample_buf), (0, 5));
+        run(&mut apu, &mut counter, 9);
+        assert_eq!((apu.ch3.pos, apu.ch3.sample_buf), (0, 5));
+        run(&mut apu, &mut counter, 1);
+        assert_eq!((apu.ch3.pos, apu.ch3.sample_buf), (1, 0));
+
+        // The noise channel's LFSR is reset
+        apu.write(0xFF21, 0xF0);
+        apu.write(0xFF23, 0x80);
+        run(&mut apu, &mut counter, 20);
+        assert_ne!(apu.ch4.lfsr, 0x7FFF);
+        apu.write(0xFF23, 0x80);
+        assert_eq!(apu.ch4.lfsr, 0x7FFF);
+    }
+
+    #[test]
+    fn noise_channel() {
+        let mut apu = APU::new();
+        let mut counter = 0;
+
+        // 7-bit LFSR clocked every 2 M-cycles
+        apu.write(0xFF21, 0xF0);
+        apu.write(0xFF22, 0x08);
+        apu.write(0xFF23, 0x80);
+        assert_eq!(apu.read(0xFF26) & 0x8, 0x8);
+        assert_eq!(apu.ch4.lfsr, 0x7FFF);
+
+        // The short mode repeats every 127 steps
+        let mut seen = std::vec::Vec::new();
+        for _ in 0..254 {
+            run(&mut apu, &mut counter, 2);
+            seen.push(apu.ch4.lfsr & 0x7F);
+        }
+        assert_eq!(seen[..127], seen[127..]);
+        assert!(seen.iter().any(|&s| s & 0x1 == 0));
+        assert!(seen.iter().any(|&s| s & 0x1 == 1));
+    }
+
+    #[test]
+    fn power_off() {
+        let mut apu = APU::new();
+        apu.write(0xFF30, 0xAB);
+        apu.write(0xFF12, 0xF0);
+        apu.write(0xFF14, 0x80);
+        assert_eq!(apu.read(0xFF26), 0xF1);
+
+        apu.write(0xFF26, 0x00);
+        assert_eq!(apu.read(0xFF26), 0x70);
+        assert_eq!(apu.read(0xFF12), 0x00);
+        assert_eq!(apu.read(0xFF24), 0x00);
+        assert_eq!(apu.read(0xFF25), 0x00);
+
+        // Registers ignore writes while off, wave RAM is untouched
+        apu.write(0xFF24, 0x77);
+        assert_eq!(apu.read(0xFF24), 0x00);
+        assert_eq!(apu.read(0xFF30), 0xAB);
+
+        apu.write(0xFF26, 0x80);
+        apu.write(0xFF24, 0x77);
+        assert_eq!(apu.read(0xFF24), 0x77);
+    }
+}
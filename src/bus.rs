@@ -1,18 +1,52 @@
-use heapless::Deque;
-use heapless::Vec;
+use core::cell::RefCell;
+use heapless::{Deque, Vec};
 
+use crate::apu::APU;
 use crate::cart::Cartridge;
 use crate::cart::CartridgeData;
+#[cfg(feature = "debug-port")]
+use crate::debug_port::DebugPort;
 use crate::interrupts::{IntSource, InterruptController};
-use crate::joypad::Joypad;
-use crate::ppu::PPU;
+use crate::joypad::{Joypad, JoypadDirection, JoypadInput};
+use crate::ppu::{DMA_CYCLES, PPU};
+use crate::serial::Serial;
+#[cfg(any(test, feature = "std"))]
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
 use crate::timer::Timer;
+#[cfg(any(test, feature = "std"))]
+use core::ops::RangeInclusive;
+#[cfg(any(test, feature = "std"))]
+use std::boxed::Box;
 
 pub trait Device {
     fn write(&mut self, addr: u16, val: u8);
     fn read(&self, addr: u16) -> u8;
 }
 
+pub const MAX_WATCHPOINTS: usize = 8;
+// Watched accesses kept until they're taken, enough for every access one
+// instruction makes
+pub const WATCH_HIT_QUEUE: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    // Either a read or a write
+    Access,
+}
+
+// A watched access, with the value that was read or written
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WatchHit {
+    pub addr: u16,
+    pub kind: WatchKind,
+    pub val: u8,
+}
+
+// The DMG boot ROM, mapped over the start of the cartridge ROM until 0xFF50 is written
+pub const BOOT_ROM_LEN: usize = 0x100;
+
 #[derive(Default)]
 struct BusStats {
     prohibited_area: u16,
@@ -23,25 +57,76 @@ struct BusStats {
 pub struct Bus<T: CartridgeData> {
     pub ppu: PPU,
     wram: [u8; 0x1000],
-    mapped_wram: [u8; 0x1000],
+    // WRAM banks 1-7 at 0xD000..=0xDFFF, a DMG only has the first
+    mapped_wram: [[u8; 0x1000]; 7],
+    // SVBK, the selected WRAM bank. 0 selects bank 1 as well.
+    svbk: u8,
     pub timer: Timer,
     pub int_controller: InterruptController,
     pub joypad: Joypad,
+    pub serial: Serial,
+    pub apu: APU,
     io: [u8; 0x80],
     hram: [u8; 0x7F],
-    passed_buf: Deque<u8, 6>,
     stats: BusStats,
+    watchpoints: Vec<(u16, WatchKind), MAX_WATCHPOINTS>,
+    // Watched accesses that haven't been taken yet, oldest first. Reads
+    // only borrow the bus, so this has to be a RefCell.
+    watch_hits: RefCell<Deque<WatchHit, WATCH_HIT_QUEUE>>,
+    boot_rom: [u8; BOOT_ROM_LEN],
+    boot_rom_mapped: bool,
+    // Runs the CGB's VRAM DMA, WRAM banking and double speed mode, for CGB
+    // games that use them even when running in DMG mode. Off, like a real
+    // DMG, by default.
+    cgb_mode: bool,
+    // KEY1. In double speed the CPU, timer and serial run twice as fast
+    // while the PPU and APU don't. A switch armed through KEY1 happens on
+    // the next STOP.
+    double_speed: bool,
+    speed_switch_armed: bool,
+    // Replaces the whole memory map with plain RAM, see test_bus
+    #[cfg(test)]
+    flat_ram: Option<std::boxed::Box<[u8; 0x10000]>>,
     pub cart: Cartridge<T>,
+    #[cfg(feature = "debug-port")]
+    pub debug_port: DebugPort,
+    // Devices attached from outside the crate with attach_io()
+    #[cfg(any(test, feature = "std"))]
+    io_devices: std::vec::Vec<(RangeInclusive<u16>, Box<dyn Device>)>,
 }
 
 impl<T: CartridgeData> Device for Bus<T> {
     fn write(&mut self, addr: u16, val: u8) {
+        #[cfg(test)]
+        if let Some(ram) = &mut self.flat_ram {
+            ram[addr as usize] = val;
+            return;
+        }
+
+        self.check_watch(addr, WatchKind::Write, val);
+
+        if self.dma_blocks(addr) {
+            return;
+        }
+
+        #[cfg(feature = "debug-port")]
+        if DebugPort::maps(addr) {
+            return self.debug_port.write(addr, val);
+        }
+
+        #[cfg(any(test, feature = "std"))]
+        if let Some(dev) = self.io_device_mut(addr) {
+            return dev.write(addr, val);
+        }
+
         match addr {
             0..=0x7FFF => {
                 self.cart.write(addr, val);
             }
             0x8000..=0x9FFF => {
-                self.ppu.write(addr, val);
+                if self.ppu.vram_accessible() {
+                    self.ppu.write(addr, val);
+                }
             }
             0xA000..=0xBFFF => {
                 self.cart.write(addr, val);
@@ -50,14 +135,18 @@ impl<T: CartridgeData> Device for Bus<T> {
                 self.wram[addr as usize - 0xC000] = val;
             }
             0xD000..=0xDFFF => {
-                self.mapped_wram[addr as usize - 0xD000] = val;
+                self.mapped_wram[self.wram_bank()][addr as usize - 0xD000] = val;
             }
             0xE000..=0xFDFF => {
+                // Echo RAM mirrors 0xC000..=0xDDFF
                 self.stats.echo += 1;
+                self.write(addr - 0x2000, val);
             }
             0xFE00..=0xFE9F => {
                 //OAM
-                self.ppu.write(addr, val);
+                if self.ppu.oam_accessible() {
+                    self.ppu.write(addr, val);
+                }
             }
             0xFEA0..=0xFEFF => {
                 self.stats.prohibited_area += 1;
@@ -65,14 +154,11 @@ impl<T: CartridgeData> Device for Bus<T> {
             0xFF00 => {
                 self.joypad.write(addr, val);
             }
-            0xFF01..=0xFF03 => {
+            0xFF01..=0xFF02 => {
+                self.serial.write(addr, val);
+            }
+            0xFF03 => {
                 self.io[addr as usize - 0xFF00] = val;
-                if addr == 0xFF01 {
-                    if self.passed_buf.is_full() {
-                        let _ = self.passed_buf.pop_front();
-                    }
-                    let _ = self.passed_buf.push_back(val);
-                }
             }
             0xFF04..=0xFF07 => {
                 self.timer.write(addr, val);
@@ -83,19 +169,38 @@ impl<T: CartridgeData> Device for Bus<T> {
             0xFF0F => {
                 self.int_controller.write(addr, val);
             }
-            0xFF10..=0xFF3F => {
+            0xFF10..=0xFF26 | 0xFF30..=0xFF3F => {
+                self.apu.write(addr, val);
+            }
+            0xFF27..=0xFF2F => {
                 self.io[addr as usize - 0xFF00] = val;
             }
             //PPU control registers
             0xFF40..=0xFF4B => {
-                if addr == 0xFF46 {
-                    let mut src = val as u16 * 0x100;
-                    for dst in 0xFE00..=0xFE9F {
-                        self.write(dst, self.read(src));
-                        src += 1;
-                    }
-                } else {
-                    self.ppu.write(addr, val);
+                self.ppu.write(addr, val);
+                if let Some(int) = self.ppu.take_interrupt() {
+                    self.int_controller.interrupt(int);
+                }
+            }
+            0xFF50 => {
+                self.boot_rom_mapped = false;
+                self.io[addr as usize - 0xFF00] = val;
+            }
+            0xFF4D => {
+                if self.cgb_mode {
+                    self.speed_switch_armed = val & 0x1 != 0;
+                }
+            }
+            // VBK, there's no second VRAM bank
+            0xFF4F => {}
+            0xFF55 => {
+                if self.cgb_mode {
+                    self.hdma(val);
+                }
+            }
+            0xFF70 => {
+                if self.cgb_mode {
+                    self.svbk = val & 0x7;
                 }
             }
             0xFF4C..=0xFF7F => {
@@ -111,11 +216,97 @@ impl<T: CartridgeData> Device for Bus<T> {
     }
 
     fn read(&self, addr: u16) -> u8 {
+        #[cfg(test)]
+        if let Some(ram) = &self.flat_ram {
+            return ram[addr as usize];
+        }
+
+        let val = if self.dma_blocks(addr) {
+            0xFF
+        } else {
+            self.read_mapped(addr)
+        };
+
+        self.check_watch(addr, WatchKind::Read, val);
+        val
+    }
+}
+
+impl<T: CartridgeData> Bus<T> {
+    // Reads memory for a debugger. Unlike read(), VRAM and OAM are readable
+    // whatever the PPU and OAM DMA are doing, and watchpoints aren't hit.
+    // Reads don't change any state, so IO registers read the same as for
+    // the CPU.
+    pub fn peek(&self, addr: u16) -> u8 {
+        #[cfg(test)]
+        if let Some(ram) = &self.flat_ram {
+            return ram[addr as usize];
+        }
+
         match addr {
+            0x8000..=0x9FFF | 0xFE00..=0xFE9F => self.ppu.read(addr),
+            _ => self.read_mapped(addr),
+        }
+    }
+
+    fn check_watch(&self, addr: u16, kind: WatchKind, val: u8) {
+        if self.watchpoints.is_empty() || self.watch_hits.borrow().is_full() {
+            return;
+        }
+
+        let hit = self.watchpoints.iter().any(|&(watch_addr, watch_kind)| {
+            watch_addr == addr && (watch_kind == kind || watch_kind == WatchKind::Access)
+        });
+        if hit {
+            let _ = self
+                .watch_hits
+                .borrow_mut()
+                .push_back(WatchHit { addr, kind, val });
+        }
+    }
+
+    // A CGB VRAM DMA from 0xFF51/0xFF52 into VRAM at 0xFF53/0xFF54, started
+    // by writing the length to HDMA5. A general purpose DMA (bit 7 clear)
+    // really does copy everything at once, and an HBLANK DMA is close enough
+    // done the same way, so the transfer always reads as done.
+    fn hdma(&mut self, val: u8) {
+        let src = u16::from_be_bytes([self.io[0x51], self.io[0x52]]) & 0xFFF0;
+        let dst = u16::from_be_bytes([self.io[0x53], self.io[0x54]]) & 0x1FF0;
+        let len = ((val & 0x7F) as u16 + 1) * 0x10;
+
+        for i in 0..len {
+            let byte = self.read_mapped(src.wrapping_add(i));
+            // The destination wraps within VRAM
+            self.ppu.write(0x8000 | ((dst + i) & 0x1FFF), byte);
+        }
+    }
+
+    // While OAM DMA is running the CPU can only reach HRAM
+    fn dma_blocks(&self, addr: u16) -> bool {
+        self.ppu.dma_active() && !(0xFF80..=0xFFFE).contains(&addr)
+    }
+
+    // A read as seen by the DMA controller, ignoring DMA's own bus lock
+    fn read_mapped(&self, addr: u16) -> u8 {
+        #[cfg(feature = "debug-port")]
+        if DebugPort::maps(addr) {
+            return self.debug_port.read(addr);
+        }
+
+        #[cfg(any(test, feature = "std"))]
+        if let Some(dev) = self.io_device(addr) {
+            return dev.read(addr);
+        }
+
+        match addr {
+            0..=0xFF if self.boot_rom_mapped => self.boot_rom[addr as usize],
             0..=0x7FFF => {
                 return self.cart.read(addr);
             }
             0x8000..=0x9FFF => {
+                if !self.ppu.vram_accessible() {
+                    return 0xFF;
+                }
                 return self.ppu.read(addr);
             }
             0xA000..=0xBFFF => self.cart.read(addr),
@@ -123,12 +314,16 @@ impl<T: CartridgeData> Device for Bus<T> {
                 return self.wram[addr as usize - 0xC000];
             }
             0xD000..=0xDFFF => {
-                return self.mapped_wram[addr as usize - 0xD000];
+                return self.mapped_wram[self.wram_bank()][addr as usize - 0xD000];
             }
             0xE000..=0xFDFF => {
-                return 0;
+                // Echo RAM mirrors 0xC000..=0xDDFF
+                return self.read_mapped(addr - 0x2000);
             }
             0xFE00..=0xFE9F => {
+                if !self.ppu.oam_accessible() {
+                    return 0xFF;
+                }
                 return self.ppu.read(addr);
             }
             0xFEA0..=0xFEFF => {
@@ -137,7 +332,10 @@ impl<T: CartridgeData> Device for Bus<T> {
             0xFF00 => {
                 return self.joypad.read(addr);
             }
-            0xFF01..=0xFF03 => {
+            0xFF01..=0xFF02 => {
+                return self.serial.read(addr);
+            }
+            0xFF03 => {
                 return self.io[addr as usize - 0xFF00];
             }
             0xFF04..=0xFF07 => {
@@ -149,13 +347,23 @@ impl<T: CartridgeData> Device for Bus<T> {
             0xFF0F => {
                 return self.int_controller.read(addr);
             }
-            0xFF10..=0xFF3F => {
+            0xFF10..=0xFF26 | 0xFF30..=0xFF3F => self.apu.read(addr),
+            0xFF27..=0xFF2F => {
                 return self.io[addr as usize - 0xFF00];
             }
             0xFF40..=0xFF4B => {
                 // LCD control registers
                 return self.ppu.read(addr);
             }
+            // KEY1, the current speed in bit 7 and whether a switch is armed in
+            // bit 0. Both only get set in CGB mode, so a DMG always reads 0x7E.
+            0xFF4D => 0x7E | (self.double_speed as u8) << 7 | self.speed_switch_armed as u8,
+            // VBK: always bank 0
+            0xFF4F => 0xFE,
+            // The HDMA registers are write only, and a transfer is always done
+            0xFF51..=0xFF55 => 0xFF,
+            0xFF70 if self.cgb_mode => 0xF8 | self.svbk,
+            0xFF70 => 0xFF,
             0xFF4C..=0xFF7F => {
                 return self.io[addr as usize - 0xFF00];
             }
@@ -169,29 +377,126 @@ impl<T: CartridgeData> Device for Bus<T> {
     }
 }
 
+#[cfg(any(test, feature = "std"))]
+impl<T: CartridgeData> Snapshot for Bus<T> {
+    fn save(&self, w: &mut StateWriter) {
+        w.bytes(&self.wram);
+        for bank in &self.mapped_wram {
+            w.bytes(bank);
+        }
+        w.u8(self.svbk);
+        w.bytes(&self.io);
+        w.bytes(&self.hram);
+        self.ppu.save(w);
+        self.timer.save(w);
+        self.int_controller.save(w);
+        self.joypad.save(w);
+        self.serial.save(w);
+        self.apu.save(w);
+        w.bool(self.boot_rom_mapped);
+        w.bool(self.double_speed);
+        w.bool(self.speed_switch_armed);
+        self.cart.save(w);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.bytes(&mut self.wram)?;
+        for bank in &mut self.mapped_wram {
+            r.bytes(bank)?;
+        }
+        self.svbk = r.u8()?;
+        r.bytes(&mut self.io)?;
+        r.bytes(&mut self.hram)?;
+        self.ppu.load(r)?;
+        self.timer.load(r)?;
+        self.int_controller.load(r)?;
+        self.joypad.load(r)?;
+        self.serial.load(r)?;
+        self.apu.load(r)?;
+        self.boot_rom_mapped = r.bool()?;
+        self.double_speed = r.bool()?;
+        self.speed_switch_armed = r.bool()?;
+        self.cart.load(r)
+    }
+}
+
 impl<T: CartridgeData> Bus<T> {
     pub fn new(cart: T) -> Self {
         Self {
             ppu: PPU::new(),
             wram: [0; 0x1000],
-            mapped_wram: [0; 0x1000],
+            mapped_wram: [[0; 0x1000]; 7],
+            svbk: 0,
             timer: Timer::new(),
             int_controller: InterruptController::new(),
             joypad: Joypad::new(),
+            serial: Serial::new(),
+            apu: APU::new(),
             io: [0; 0x80],
             hram: [0; 0x7F],
-            passed_buf: Deque::new(),
             stats: BusStats::default(),
+            watchpoints: Vec::new(),
+            watch_hits: RefCell::new(Deque::new()),
+            boot_rom: [0; BOOT_ROM_LEN],
+            boot_rom_mapped: false,
+            cgb_mode: false,
+            double_speed: false,
+            speed_switch_armed: false,
+            #[cfg(test)]
+            flat_ram: None,
             cart: Cartridge::new(cart),
+            #[cfg(feature = "debug-port")]
+            debug_port: DebugPort::new(),
+            #[cfg(any(test, feature = "std"))]
+            io_devices: std::vec::Vec::new(),
         }
     }
 
-    pub fn is_passed(&self) -> bool {
-        let buf: Vec<_, 10> = self.passed_buf.clone().into_iter().collect();
-        let str = core::str::from_utf8(&buf).expect("No!");
+    // Maps `boot` over 0x0000..=0x00FF until the game writes to 0xFF50
+    pub fn map_boot_rom(&mut self, boot: &[u8; BOOT_ROM_LEN]) {
+        self.boot_rom = *boot;
+        self.boot_rom_mapped = true;
+    }
+
+    pub fn boot_rom_mapped(&self) -> bool {
+        self.boot_rom_mapped
+    }
+
+    pub fn set_cgb_mode(&mut self, enabled: bool) {
+        self.cgb_mode = enabled;
+    }
+
+    pub fn cgb_mode(&self) -> bool {
+        self.cgb_mode
+    }
 
-        let moon_passed: [u8; 6] = [3, 5, 8, 13, 21, 34];
-        return str == "Passed" || buf.ends_with(&moon_passed);
+    pub fn double_speed(&self) -> bool {
+        self.double_speed
+    }
+
+    // Called by STOP. Switches speed and returns true if a switch was armed.
+    pub fn take_speed_switch(&mut self) -> bool {
+        if !self.speed_switch_armed {
+            return false;
+        }
+
+        self.speed_switch_armed = false;
+        self.double_speed = !self.double_speed;
+        true
+    }
+
+    // Index into mapped_wram of the bank at 0xD000
+    fn wram_bank(&self) -> usize {
+        if self.cgb_mode {
+            (self.svbk as usize).max(1) - 1
+        } else {
+            0
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn use_flat_ram(&mut self) {
+        self.flat_ram = Some(std::boxed::Box::new([0; 0x10000]));
     }
 
     pub fn query_interrupt(&mut self) -> Option<IntSource> {
@@ -203,25 +508,396 @@ impl<T: CartridgeData> Bus<T> {
     }
 
     pub fn run_cycles(&mut self, cycles: u16) {
-        /* Move along the PPU */
-        let maybe_int = self.ppu.run(cycles as i32);
+        /* Copy the bytes of any OAM DMA covered by these cycles */
+        if self.ppu.dma_active() {
+            let src = self.ppu.dma_source();
+            let done = self.ppu.dma_progress();
+            for i in done..(done + cycles).min(DMA_CYCLES as u16) {
+                let val = self.read_mapped(src + i);
+                self.ppu.write(0xFE00 + i, val);
+            }
+        }
+        self.ppu.run_dma(cycles);
 
         /* Move along the timer */
+        let mut slow_cycles = 0;
         for _ in 0..cycles {
             if self.timer.tick() {
                 self.int_controller.interrupt(IntSource::TIMER);
             }
+
+            // In double speed the PPU and APU only step every other tick.
+            // The APU's frame sequencer moves to the next counter bit up.
+            let counter = self.timer.system_counter();
+            if !self.double_speed || counter.is_multiple_of(2) {
+                slow_cycles += 1;
+                self.apu.tick(counter >> self.double_speed as u8);
+            }
+
+            if self.serial.tick() {
+                self.int_controller.interrupt(IntSource::SERIAL);
+            }
         }
 
+        /* Move along the PPU */
+        let maybe_int = self.ppu.run(slow_cycles);
+
         /* Handle PPU interrupts */
         // TODO: Why not do this with the `run` call?
         //       immediately?
         if let Some(ppu_int) = maybe_int {
             self.int_controller.interrupt(ppu_int)
         }
+        if let Some(ppu_int) = self.ppu.take_interrupt() {
+            self.int_controller.interrupt(ppu_int)
+        }
+    }
+
+    pub fn joypad_input(&mut self, button: JoypadInput, direction: JoypadDirection) {
+        if self.joypad.input(button, direction) {
+            self.int_controller.interrupt(IntSource::JOYPAD);
+        }
+    }
+
+    // Records accesses to `addr` for take_watch_hit().
+    // Returns false if there are already MAX_WATCHPOINTS.
+    pub fn add_watchpoint(&mut self, addr: u16, kind: WatchKind) -> bool {
+        self.watchpoints.push((addr, kind)).is_ok()
+    }
+
+    pub fn remove_watchpoint(&mut self, addr: u16, kind: WatchKind) {
+        self.watchpoints.retain(|&watch| watch != (addr, kind));
+    }
+
+    pub fn has_watchpoint(&self, addr: u16, kind: WatchKind) -> bool {
+        self.watchpoints.contains(&(addr, kind))
+    }
+
+    // The oldest watched access that hasn't been taken yet, if any. Once
+    // WATCH_HIT_QUEUE are waiting later ones are dropped.
+    pub fn take_watch_hit(&mut self) -> Option<WatchHit> {
+        self.watch_hits.get_mut().pop_front()
+    }
+
+    // Puts hits taken with take_watch_hit() back in front of any still
+    // waiting, as if they had never been taken
+    pub(crate) fn untake_watch_hits(&mut self, hits: &[WatchHit]) {
+        let queue = self.watch_hits.get_mut();
+        for &hit in hits.iter().rev() {
+            if queue.is_full() {
+                queue.pop_back();
+            }
+            let _ = queue.push_front(hit);
+        }
     }
 
     pub fn interrupt_pending(&self) -> bool {
         self.int_controller.pending()
     }
+
+    // Maps `dev` over `range`, in front of whatever the bus has there. It
+    // sees every read and write in the range with the full address. Where
+    // ranges overlap the device attached last wins. Attached devices aren't
+    // part of save states.
+    #[cfg(any(test, feature = "std"))]
+    pub fn attach_io(&mut self, range: RangeInclusive<u16>, dev: Box<dyn Device>) {
+        self.io_devices.push((range, dev));
+    }
+
+    // Removes and returns the device that currently handles `addr`
+    #[cfg(any(test, feature = "std"))]
+    pub fn detach_io(&mut self, addr: u16) -> Option<Box<dyn Device>> {
+        let idx = self
+            .io_devices
+            .iter()
+            .rposition(|(range, _)| range.contains(&addr))?;
+        Some(self.io_devices.remove(idx).1)
+    }
+
+    #[cfg(any(test, feature = "std"))]
+    fn io_device(&self, addr: u16) -> Option<&dyn Device> {
+        self.io_devices
+            .iter()
+            .rev()
+            .find(|(range, _)| range.contains(&addr))
+            .map(|(_, dev)| dev.as_ref())
+    }
+
+    #[cfg(any(test, feature = "std"))]
+    fn io_device_mut(&mut self, addr: u16) -> Option<&mut dyn Device> {
+        self.io_devices
+            .iter_mut()
+            .rev()
+            .find(|(range, _)| range.contains(&addr))
+            .map(|(_, dev)| dev.as_mut() as &mut dyn Device)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gb::SmallInMemoryCartridge;
+    use crate::ppu::{PpuMode, CYCLES_PER_SCANLINE, DRAW_CYCLES, HBLANK_CYCLES, OAMSCAN_CYCLES};
+
+    #[test]
+    fn peek() {
+        let mut bus = Bus::new(SmallInMemoryCartridge::from_slice(&[0; 0x8000]));
+        bus.write(0x8000, 0x12);
+        bus.write(0xFE00, 0x34);
+        bus.write(0xC000, 0x56);
+        bus.add_watchpoint(0xC000, WatchKind::Read);
+        bus.ppu.set_access_restrictions(true);
+
+        assert_eq!(bus.peek(0xC000), 0x56);
+        assert_eq!(bus.take_watch_hit(), None);
+
+        // LCD on, then into DRAW where VRAM and OAM are locked
+        bus.write(0xFF40, 0x80);
+        bus.run_cycles(OAMSCAN_CYCLES as u16);
+        assert!(matches!(bus.ppu.get_ppu_state().mode, PpuMode::DRAW));
+        assert_eq!(bus.read(0x8000), 0xFF);
+        assert_eq!(bus.peek(0x8000), 0x12);
+        assert_eq!(bus.read(0xFE00), 0xFF);
+        assert_eq!(bus.peek(0xFE00), 0x34);
+
+        // Everything but HRAM is locked during OAM DMA
+        bus.write(0xFF46, 0xC0);
+        assert_eq!(bus.read(0xC000), 0xFF);
+        assert_eq!(bus.peek(0xC000), 0x56);
+    }
+
+    #[test]
+    fn cgb_registers() {
+        let mut bus = Bus::new(SmallInMemoryCartridge::from_slice(&[0; 0x8000]));
+
+        // Arming a speed switch or picking VRAM bank 1 doesn't stick
+        bus.write(0xFF4D, 0x01);
+        assert_eq!(bus.read(0xFF4D), 0x7E);
+        bus.write(0xFF4F, 0x01);
+        assert_eq!(bus.read(0xFF4F), 0xFE);
+
+        for i in 0..0x20 {
+            bus.write(0xC100 + i, i as u8 + 1);
+        }
+
+        // 0x20 bytes from 0xC100 to 0x8800, the low source bits are ignored
+        bus.write(0xFF51, 0xC1);
+        bus.write(0xFF52, 0x0F);
+        bus.write(0xFF53, 0x88);
+        bus.write(0xFF54, 0x00);
+
+        // A DMG ignores it
+        bus.write(0xFF55, 0x01);
+        assert_eq!(bus.ppu.read(0x8800), 0x00);
+
+        bus.set_cgb_mode(true);
+        bus.write(0xFF55, 0x01);
+        assert_eq!(bus.read(0xFF55), 0xFF);
+        assert_eq!(bus.ppu.read(0x8800), 0x01);
+        assert_eq!(bus.ppu.read(0x881F), 0x20);
+        assert_eq!(bus.ppu.read(0x8820), 0x00);
+    }
+
+    // Latches the last value written and reads it back inverted
+    struct Inverter(u8);
+
+    impl Device for Inverter {
+        fn write(&mut self, _addr: u16, val: u8) {
+            self.0 = val;
+        }
+
+        fn read(&self, _addr: u16) -> u8 {
+            !self.0
+        }
+    }
+
+    #[test]
+    fn attach_io() {
+        let mut bus = Bus::new(SmallInMemoryCartridge::from_slice(&[0; 0x8000]));
+        bus.write(0xFF60, 0x12);
+        bus.write(0xC000, 0x34);
+
+        bus.attach_io(0xFF60..=0xFF61, Box::new(Inverter(0)));
+        assert_eq!(bus.read(0xFF60), 0xFF);
+        bus.write(0xFF61, 0x0F);
+        assert_eq!(bus.read(0xFF60), 0xF0);
+        assert_eq!(bus.peek(0xFF61), 0xF0);
+        assert_eq!(bus.read(0xFF62), 0x00);
+
+        // Devices go in front of the built in memory map too, and the
+        // latest one wins
+        bus.attach_io(0xC000..=0xC000, Box::new(Inverter(0x01)));
+        bus.attach_io(0xC000..=0xFF60, Box::new(Inverter(0x02)));
+        assert_eq!(bus.read(0xC000), 0xFD);
+        assert!(bus.detach_io(0xC000).is_some());
+        assert_eq!(bus.read(0xC000), 0xFE);
+        assert!(bus.detach_io(0xC000).is_some());
+        assert_eq!(bus.read(0xC000), 0x34);
+
+        assert!(bus.detach_io(0xFF60).is_some());
+        assert!(bus.detach_io(0xFF60).is_none());
+        assert_eq!(bus.read(0xFF60), 0x12);
+    }
+
+    #[cfg(feature = "debug-port")]
+    #[test]
+    fn debug_port() {
+        use crate::debug_port::{PRINT_PORT, SCRATCH_START};
+
+        let mut bus = Bus::new(SmallInMemoryCartridge::from_slice(&[0; 0x8000]));
+        for b in b"Hi" {
+            bus.write(PRINT_PORT, *b);
+        }
+        assert_eq!(bus.debug_port.take_output().as_slice(), b"Hi");
+        assert_eq!(bus.read(PRINT_PORT), 0xFF);
+
+        bus.write(SCRATCH_START, 0x12);
+        assert_eq!(bus.read(SCRATCH_START), 0x12);
+    }
+
+    #[test]
+    fn double_speed() {
+        let mut bus = Bus::new(SmallInMemoryCartridge::from_slice(&[0; 0x8000]));
+        bus.write(0xFF40, 0x91);
+        bus.write(0xFF07, 0x05);
+
+        // A DMG can't arm a switch
+        bus.write(0xFF4D, 0x01);
+        assert!(!bus.take_speed_switch());
+
+        bus.set_cgb_mode(true);
+        bus.write(0xFF4D, 0x01);
+        assert_eq!(bus.read(0xFF4D), 0x7F);
+        assert!(bus.take_speed_switch());
+        assert!(bus.double_speed());
+        assert_eq!(bus.read(0xFF4D), 0xFE);
+        assert!(!bus.take_speed_switch());
+
+        // Two scanlines worth of CPU cycles only get the PPU through one,
+        // the timer still sees all of them
+        for _ in 0..CYCLES_PER_SCANLINE * 2 {
+            bus.run_cycles(1);
+        }
+        assert_eq!(bus.read(0xFF44), 1);
+        assert_eq!(bus.read(0xFF05), 57);
+
+        // And back again
+        bus.write(0xFF4D, 0x01);
+        assert!(bus.take_speed_switch());
+        assert_eq!(bus.read(0xFF4D), 0x7E);
+        for _ in 0..CYCLES_PER_SCANLINE * 2 {
+            bus.run_cycles(1);
+        }
+        assert_eq!(bus.read(0xFF44), 3);
+    }
+
+    #[test]
+    fn wram_banks() {
+        let mut bus = Bus::new(SmallInMemoryCartridge::from_slice(&[0; 0x8000]));
+
+        // A DMG has one fixed bank and no SVBK
+        bus.write(0xD000, 0x11);
+        bus.write(0xFF70, 0x02);
+        assert_eq!(bus.read(0xFF70), 0xFF);
+        assert_eq!(bus.read(0xD000), 0x11);
+
+        bus.set_cgb_mode(true);
+        assert_eq!(bus.read(0xFF70), 0xF8);
+        for bank in 1..=7 {
+            bus.write(0xFF70, bank);
+            assert_eq!(bus.read(0xFF70), 0xF8 | bank);
+            bus.write(0xDFFF, bank * 0x10);
+        }
+
+        // Bank 0 selects bank 1, which is the DMG's bank
+        bus.write(0xFF70, 0xF8);
+        assert_eq!(bus.read(0xFF70), 0xF8);
+        assert_eq!(bus.read(0xD000), 0x11);
+        assert_eq!(bus.read(0xDFFF), 0x10);
+        bus.write(0xFF70, 0x05);
+        assert_eq!(bus.read(0xDFFF), 0x50);
+        // Echo RAM follows the selected bank
+        bus.write(0xFDFF, 0x55);
+        assert_eq!(bus.read(0xDDFF), 0x55);
+        bus.write(0xFF70, 0x06);
+        assert_eq!(bus.read(0xFDFF), 0x00);
+
+        // 0xC000..=0xCFFF is always bank 0
+        bus.write(0xC000, 0x22);
+        bus.write(0xFF70, 0x03);
+        assert_eq!(bus.read(0xC000), 0x22);
+    }
+
+    #[test]
+    fn oam_dma() {
+        let mut bus = Bus::new(SmallInMemoryCartridge::from_slice(&[0; 0x8000]));
+        for i in 0..0xA0 {
+            bus.write(0xC000 + i, i as u8 + 1);
+        }
+        bus.write(0xFF80, 0x12);
+
+        bus.write(0xFF46, 0xC0);
+        assert_eq!(bus.ppu.get_ppu_state().dma_remaining, 160);
+
+        // Only HRAM is reachable while the transfer runs
+        assert_eq!(bus.read(0xC000), 0xFF);
+        assert_eq!(bus.read(0xFF80), 0x12);
+        bus.write(0xC000, 0x55);
+        bus.write(0xFF81, 0x34);
+        assert_eq!(bus.read(0xFF81), 0x34);
+
+        bus.run_cycles(80);
+        assert_eq!(bus.ppu.get_ppu_state().dma_remaining, 80);
+        // Half of OAM has been copied so far
+        assert_eq!(bus.ppu.read(0xFE4F), 0x50);
+        assert_eq!(bus.ppu.read(0xFE50), 0x00);
+
+        bus.run_cycles(80);
+        assert!(!bus.ppu.dma_active());
+        assert_eq!(bus.read(0xC000), 0x01);
+        for i in 0..0xA0 {
+            assert_eq!(bus.read(0xFE00 + i), i as u8 + 1);
+        }
+    }
+
+    #[test]
+    fn ppu_access_restrictions() {
+        let mut bus = Bus::new(SmallInMemoryCartridge::from_slice(&[0; 0x8000]));
+        bus.write(0x8000, 0x11);
+        bus.write(0xFE00, 0x22);
+        bus.ppu.set_access_restrictions(true);
+        bus.write(0xFF40, 0x91);
+
+        // OAM scan
+        assert_eq!(bus.read(0x8000), 0x11);
+        assert_eq!(bus.read(0xFE00), 0xFF);
+        bus.write(0xFE00, 0x33);
+
+        bus.run_cycles(OAMSCAN_CYCLES as u16);
+        assert!(matches!(bus.ppu.get_ppu_state().mode, PpuMode::DRAW));
+        assert_eq!(bus.read(0x8000), 0xFF);
+        assert_eq!(bus.read(0xFE00), 0xFF);
+        bus.write(0x8000, 0x44);
+
+        bus.run_cycles(DRAW_CYCLES as u16);
+        assert_eq!(bus.read(0x8000), 0x11);
+        assert_eq!(bus.read(0xFE00), 0x22);
+
+        // Permissive again
+        bus.run_cycles(HBLANK_CYCLES as u16 + OAMSCAN_CYCLES as u16);
+        bus.ppu.set_access_restrictions(false);
+        assert_eq!(bus.read(0xFE00), 0x22);
+    }
+
+    #[test]
+    fn echo_ram() {
+        let mut bus = Bus::new(SmallInMemoryCartridge::from_slice(&[0; 0x8000]));
+
+        bus.write(0xC123, 0x12);
+        assert_eq!(bus.read(0xE123), 0x12);
+
+        bus.write(0xFDFF, 0x34);
+        assert_eq!(bus.read(0xDDFF), 0x34);
+        assert_eq!(bus.stats.echo, 1);
+    }
 }
@@ -1,28 +1,84 @@
 use core::time::Duration;
 use heapless::String;
 
+#[cfg(any(test, feature = "std"))]
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+#[cfg(any(test, feature = "std"))]
+use core::cell::Cell;
+#[cfg(any(test, feature = "std"))]
+use std::{boxed::Box, rc::Rc};
+
+// The ROM is read a byte at a time rather than borrowed as a slice, so
+// that it doesn't all have to be in memory at once
 pub trait CartridgeData {
-    fn rom(&self) -> &[u8];
+    fn rom_len(&self) -> usize;
+    // Addresses past the end of the ROM read as 0xFF
+    fn read_rom(&self, addr: usize) -> u8;
     fn ram(&self) -> &[u8];
     fn ram_mut(&mut self) -> &mut [u8];
 
     fn get_header(&self) -> CartridgeHeader {
-        get_cart_header(self.rom())
+        let header: [u8; HEADER_LEN] = core::array::from_fn(|addr| self.read_rom(addr));
+        get_cart_header(&header)
     }
 
     fn save(&mut self) {}
 }
 
+// Lets the emulator borrow a cartridge that lives elsewhere, e.g. in a
+// `static` on targets where it's too big to move
+impl<T: CartridgeData + ?Sized> CartridgeData for &mut T {
+    fn rom_len(&self) -> usize {
+        (**self).rom_len()
+    }
+
+    fn read_rom(&self, addr: usize) -> u8 {
+        (**self).read_rom(addr)
+    }
+
+    fn ram(&self) -> &[u8] {
+        (**self).ram()
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        (**self).ram_mut()
+    }
+
+    fn get_header(&self) -> CartridgeHeader {
+        (**self).get_header()
+    }
+
+    fn save(&mut self) {
+        (**self).save()
+    }
+}
+
+// The size of one switchable RAM bank at 0xA000..=0xBFFF
+const RAM_BANK_LEN: usize = 0x2000;
+
 #[derive(PartialEq, Default)]
 struct Mbc1Reg {
     two_bit_reg: u8,
     bank_mode_sel: bool,
 }
 
+impl Mbc1Reg {
+    // The RAM bank at 0xA000..=0xBFFF. The upper bank bits only pick it in
+    // mode 1, and only on carts with more than one bank of RAM.
+    fn ram_bank(&self, ram_len: usize) -> u8 {
+        if self.bank_mode_sel && ram_len > RAM_BANK_LEN {
+            self.two_bit_reg
+        } else {
+            0
+        }
+    }
+}
+
 #[derive(PartialEq)]
 enum RamOrRtc {
     BankNum(u8),
-    RTC,
+    // The selected RTC register, 0x08..=0x0C
+    RTC(u8),
 }
 
 impl Default for RamOrRtc {
@@ -31,18 +87,223 @@ impl Default for RamOrRtc {
     }
 }
 
+// A source of time for the MBC3 real time clock. Only the time that passes
+// between calls matters, not the value itself.
+pub trait Clock {
+    fn now(&self) -> Duration;
+}
+
+// The host's wall clock
+#[cfg(feature = "std")]
+pub struct SystemClock;
+
+#[cfg(feature = "std")]
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+    }
+}
+
+// A clock that only moves when it's told to, for reproducible runs.
+// Clones share the same time, so one can be kept to advance the clock
+// after another is handed to the cartridge.
+#[cfg(any(test, feature = "std"))]
+#[derive(Clone, Default)]
+pub struct ManualClock {
+    now: Rc<Cell<Duration>>,
+}
+
+#[cfg(any(test, feature = "std"))]
+impl ManualClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn advance(&self, elapsed: Duration) {
+        self.now.set(self.now.get() + elapsed);
+    }
+}
+
+#[cfg(any(test, feature = "std"))]
+impl Clock for ManualClock {
+    fn now(&self) -> Duration {
+        self.now.get()
+    }
+}
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+// The day counter is 9 bits
+const RTC_MAX_DAYS: u64 = 512;
+
 #[derive(PartialEq, Default)]
 struct Mbc3Reg {
     ram_or_rtc: RamOrRtc,
     latch_clock_data: u8,
+    // The live clock, as time since day 0
     rtc: Duration,
+    rtc_halted: bool,
+    rtc_day_carry: bool,
+    // Seconds, minutes, hours, day low, day high as of the last latch
+    rtc_latched: [u8; 5],
+}
+
+impl Mbc3Reg {
+    // The live clock in the register format
+    fn rtc_regs(&self) -> [u8; 5] {
+        let secs = self.rtc.as_secs();
+        let days = secs / SECS_PER_DAY;
+
+        let mut day_high = ((days >> 8) & 0x1) as u8;
+        if self.rtc_halted {
+            day_high |= 0x40;
+        }
+        if self.rtc_day_carry {
+            day_high |= 0x80;
+        }
+
+        [
+            (secs % 60) as u8,
+            ((secs / 60) % 60) as u8,
+            ((secs / 3600) % 24) as u8,
+            days as u8,
+            day_high,
+        ]
+    }
+
+    fn set_rtc_reg(&mut self, reg: u8, val: u8) {
+        let mut regs = self.rtc_regs();
+        let idx = (reg - 0x08) as usize;
+        regs[idx] = val;
+        self.rtc_latched[idx] = val;
+
+        self.rtc_halted = regs[4] & 0x40 != 0;
+        self.rtc_day_carry = regs[4] & 0x80 != 0;
+
+        let days = ((regs[4] as u64 & 0x1) << 8) | regs[3] as u64;
+        let secs = days * SECS_PER_DAY
+            + (regs[2] & 0x1F) as u64 * 3600
+            + (regs[1] & 0x3F) as u64 * 60
+            + (regs[0] & 0x3F) as u64;
+
+        // Writing the seconds resets the sub-second counter
+        let nanos = if idx == 0 { 0 } else { self.rtc.subsec_nanos() };
+        self.rtc = Duration::new(secs, nanos);
+    }
+
+    fn tick_rtc(&mut self, elapsed: Duration) {
+        if self.rtc_halted {
+            return;
+        }
+
+        self.rtc += elapsed;
+
+        let max = Duration::from_secs(RTC_MAX_DAYS * SECS_PER_DAY);
+        while self.rtc >= max {
+            self.rtc -= max;
+            self.rtc_day_carry = true;
+        }
+    }
+}
+
+// The camera sensor image is 128x112 pixels, which the cartridge
+// stores in RAM as 16x14 tiles of 2bpp tile data.
+pub const CAMERA_WIDTH: usize = 128;
+pub const CAMERA_HEIGHT: usize = 112;
+const CAMERA_TILE_DATA_LEN: usize = (CAMERA_WIDTH / 8) * (CAMERA_HEIGHT / 8) * 16;
+// Captured images are written to RAM bank 0 starting at this offset
+const CAMERA_IMAGE_ADDR: usize = 0x100;
+const CAMERA_NUM_REGS: usize = 0x36;
+
+// A stub of the POCKET CAMERA mapper.
+//
+// The sensor isn't emulated; a "capture" copies whatever image was last
+// provided (a test pattern by default) into RAM, where the camera ROM
+// expects to find it, and completes immediately.
+#[derive(PartialEq)]
+struct CameraReg {
+    // Bit 4 maps the camera registers into 0xA000 instead of RAM
+    ram_bank: u8,
+    regs: [u8; CAMERA_NUM_REGS],
+    image: [u8; CAMERA_TILE_DATA_LEN],
+}
+
+impl CameraReg {
+    fn new() -> Self {
+        let mut reg = Self {
+            ram_bank: 0,
+            regs: [0; CAMERA_NUM_REGS],
+            image: [0; CAMERA_TILE_DATA_LEN],
+        };
+
+        // Four vertical bars, light to dark
+        reg.set_image(|x, _| 0xFF - (x * 4) as u8);
+        reg
+    }
+
+    // Encodes a grayscale image (0 is black, 0xFF is white) as tile data
+    fn set_image(&mut self, pixel: impl Fn(usize, usize) -> u8) {
+        self.image.fill(0);
+        for y in 0..CAMERA_HEIGHT {
+            for x in 0..CAMERA_WIDTH {
+                let color = 3 - (pixel(x, y) >> 6);
+                let tile = (y / 8) * (CAMERA_WIDTH / 8) + (x / 8);
+                let idx = tile * 16 + (y % 8) * 2;
+                let bit = 7 - (x % 8);
+
+                self.image[idx] |= (color & 0x1) << bit;
+                self.image[idx + 1] |= ((color >> 1) & 0x1) << bit;
+            }
+        }
+    }
 }
 
+// The camera's ~3.5KiB image buffer is boxed when there's an allocator,
+// so other cartridges don't carry room for it. no_std builds keep it inline.
+#[cfg(any(test, feature = "std"))]
+type CameraBox = std::boxed::Box<CameraReg>;
+#[cfg(not(any(test, feature = "std")))]
+type CameraBox = CameraReg;
+
+#[cfg_attr(not(any(test, feature = "std")), allow(clippy::large_enum_variant))]
 #[derive(PartialEq)]
 enum MemoryBankController {
     MBC0,
     MBC1(Mbc1Reg),
+    // All of MBC2's state is the common ROM bank and RAM enable
+    MBC2,
     MBC3(Mbc3Reg),
+    Camera(CameraBox),
+}
+
+// The banking registers of the mapper, enough to restore which
+// ROM and RAM banks are mapped in
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub struct MbcRegisters {
+    pub ram_en: bool,
+    pub rom_bank_num: u8,
+    // MBC1: the upper ROM / RAM bank bits and the banking mode
+    pub two_bit_reg: u8,
+    pub bank_mode_sel: bool,
+    // MBC3: the RAM bank, or 0x08..=0x0C for an RTC register.
+    // Camera: the RAM bank register.
+    pub ram_bank: u8,
+}
+
+// MBC1's banking mode. In Advanced mode the upper two bank bits also
+// select the RAM bank and the bank mapped at 0x0000..=0x3FFF.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BankMode {
+    Simple,
+    Advanced,
+}
+
+// import_ram() was given a save that isn't the size of the cartridge RAM
+#[derive(Debug, PartialEq)]
+pub struct RamSizeMismatch {
+    pub expected: usize,
+    pub actual: usize,
 }
 
 pub struct Cartridge<T: CartridgeData> {
@@ -50,6 +311,11 @@ pub struct Cartridge<T: CartridgeData> {
     mbc: MemoryBankController,
     ram_en: bool,
     rom_bank_num: u8,
+    // RAM was written since the last mark_saved()
+    ram_dirty: bool,
+    // Drives the MBC3 clock along with the time it last read
+    #[cfg(any(test, feature = "std"))]
+    clock: Option<(Box<dyn Clock>, Duration)>,
 }
 
 impl<T: CartridgeData> Cartridge<T> {
@@ -59,7 +325,9 @@ impl<T: CartridgeData> Cartridge<T> {
         let mbc: MemoryBankController = match header.cart_type {
             0 => MemoryBankController::MBC0,
             1 | 2 | 3 => MemoryBankController::MBC1(Mbc1Reg::default()),
+            5 | 6 => MemoryBankController::MBC2,
             0x0F..=0x13 => MemoryBankController::MBC3(Mbc3Reg::default()),
+            0xFC => MemoryBankController::Camera(CameraReg::new().into()),
             _ => {
                 unimplemented!("Unimplemented MBC type")
             }
@@ -70,6 +338,9 @@ impl<T: CartridgeData> Cartridge<T> {
             mbc,
             ram_en: false,
             rom_bank_num: 1,
+            ram_dirty: false,
+            #[cfg(any(test, feature = "std"))]
+            clock: None,
         }
     }
 
@@ -78,6 +349,19 @@ impl<T: CartridgeData> Cartridge<T> {
             return;
         }
 
+        if self.mbc == MemoryBankController::MBC2 {
+            return self.write_mbc2(addr, val);
+        }
+
+        // The clock has to be current before it's latched or set
+        #[cfg(any(test, feature = "std"))]
+        if let MemoryBankController::MBC3(reg) = &self.mbc {
+            let rtc_selected = matches!(reg.ram_or_rtc, RamOrRtc::RTC(_));
+            if matches!(addr, 0x6000..=0x7FFF) || (rtc_selected && addr >= 0xA000) {
+                self.sync_rtc();
+            }
+        }
+
         match addr {
             /* Registers */
             0..=0x1FFF => {
@@ -89,11 +373,12 @@ impl<T: CartridgeData> Cartridge<T> {
             }
             0x2000..=0x3FFF => {
                 let mask = match self.mbc {
-                    MemoryBankController::MBC0 => {
+                    MemoryBankController::MBC0 | MemoryBankController::MBC2 => {
                         unreachable!("")
                     }
                     MemoryBankController::MBC1(_) => 0x1F,
                     MemoryBankController::MBC3(_) => 0x7F,
+                    MemoryBankController::Camera(_) => 0x3F,
                 };
 
                 self.rom_bank_num = val & mask;
@@ -110,11 +395,7 @@ impl<T: CartridgeData> Cartridge<T> {
                 // e.g. a 256 KiB cart only needs a 4-bit bank number to address all of its 16 banks,
                 // so this register is masked to 4 bits. The upper bit would be ignored for bank selection."
                 //
-                // This generates that mask
-
-                let max_banks = self.get_header().num_rom_banks;
-                let bank_mask = (max_banks - 1) as u8;
-                //let bank_mask = (1 << max_banks.ilog2()) - 1;
+                let bank_mask = self.rom_bank_mask();
 
                 //Note: By performing the masking after the 0 -> 1 translation
                 //      above, we satisfy this section of pandocs for MBC1:
@@ -133,7 +414,7 @@ impl<T: CartridgeData> Cartridge<T> {
                 let num_rom_banks = self.get_header().num_rom_banks;
 
                 match &mut self.mbc {
-                    MemoryBankController::MBC0 => {}
+                    MemoryBankController::MBC0 | MemoryBankController::MBC2 => {}
                     MemoryBankController::MBC1(reg) => {
                         let Mbc1Reg { two_bit_reg, .. } = reg;
 
@@ -149,33 +430,32 @@ impl<T: CartridgeData> Cartridge<T> {
                             0..=0x3 => {
                                 *ram_or_rtc = RamOrRtc::BankNum(val);
                             }
-                            0x8..0xC => *ram_or_rtc = RamOrRtc::RTC,
+                            0x8..=0xC => *ram_or_rtc = RamOrRtc::RTC(val),
                             _ => { /* No OP */ }
                         }
                     }
+
+                    MemoryBankController::Camera(reg) => {
+                        reg.ram_bank = val & 0x1F;
+                    }
                 }
             }
 
             0x6000..=0x7FFF => {
                 match &mut self.mbc {
-                    MemoryBankController::MBC0 => {}
+                    MemoryBankController::MBC0 | MemoryBankController::MBC2 => {}
                     MemoryBankController::MBC1(reg) => {
                         let Mbc1Reg { bank_mode_sel, .. } = reg;
                         *bank_mode_sel = val & 0x1 == 0x1;
                     }
                     MemoryBankController::MBC3(reg) => {
-                        let Mbc3Reg {
-                            latch_clock_data,
-                            rtc,
-                            ..
-                        } = reg;
-                        if *latch_clock_data == 0 && val == 1 {
-                            *rtc += Duration::from_millis(1);
+                        // Writing 0 then 1 latches the live clock
+                        if reg.latch_clock_data == 0 && val == 1 {
+                            reg.rtc_latched = reg.rtc_regs();
                         }
-                        *latch_clock_data = val;
-                        //panic!("Not implemented!");
-                        //TODO: Latch clock data
+                        reg.latch_clock_data = val;
                     }
+                    MemoryBankController::Camera(_) => {}
                 }
             }
             /* Memory banks */
@@ -185,37 +465,66 @@ impl<T: CartridgeData> Cartridge<T> {
                     return;
                 }
 
-                match &self.mbc {
-                    MemoryBankController::MBC0 => {
-                        panic!("Accessing RAM when it doesn't exist!")
+                match &mut self.mbc {
+                    MemoryBankController::MBC0 | MemoryBankController::MBC2 => {
+                        // There is no RAM to write to
                     }
                     MemoryBankController::MBC1(reg) => {
-                        let Mbc1Reg {
-                            two_bit_reg,
-                            bank_mode_sel,
-                        } = reg;
-
-                        let mut addr = (addr - 0xA000) as usize;
+                        let bank = reg.ram_bank(self.data.ram().len()) as usize;
+                        let addr = (addr - 0xA000) as usize | bank << 13;
 
-                        if *bank_mode_sel {
-                            addr |= (*two_bit_reg as usize) << 13;
+                        if let Some(addr) = self.ram_offset(addr) {
+                            self.data.ram_mut()[addr] = val;
+                            self.ram_dirty = true;
                         }
-
-                        self.data.ram_mut()[addr] = val;
                     }
 
                     MemoryBankController::MBC3(reg) => {
-                        let Mbc3Reg { ram_or_rtc, .. } = reg;
                         let mut addr = (addr - 0xA000) as usize;
-                        match ram_or_rtc {
-                            RamOrRtc::RTC => { /* TODO, How does this work?? */ }
+                        match reg.ram_or_rtc {
+                            RamOrRtc::RTC(rtc_reg) => {
+                                reg.set_rtc_reg(rtc_reg, val);
+                                return;
+                            }
                             RamOrRtc::BankNum(bank) => {
-                                addr |= (*bank as usize) << 13;
+                                addr |= (bank as usize) << 13;
                             }
                         }
-                        //TODO: Size check
-                        if addr < (self.get_header().ram_size as usize) {
+                        if let Some(addr) = self.ram_offset(addr) {
                             self.data.ram_mut()[addr] = val;
+                            self.ram_dirty = true;
+                        }
+                    }
+
+                    MemoryBankController::Camera(reg) => {
+                        let addr = (addr - 0xA000) as usize;
+
+                        if reg.ram_bank & 0x10 == 0 {
+                            let addr = addr | ((reg.ram_bank as usize & 0xF) << 13);
+                            if let Some(b) = self.data.ram_mut().get_mut(addr) {
+                                *b = val;
+                                self.ram_dirty = true;
+                            }
+                            return;
+                        }
+
+                        // The registers are mirrored every 0x80 bytes
+                        let reg_idx = addr & 0x7F;
+                        if reg_idx >= CAMERA_NUM_REGS {
+                            return;
+                        }
+
+                        if reg_idx == 0 && val & 0x1 == 0x1 {
+                            // Capture, which finishes instantly
+                            let ram = self.data.ram_mut();
+                            let end = CAMERA_IMAGE_ADDR + CAMERA_TILE_DATA_LEN;
+                            if ram.len() >= end {
+                                ram[CAMERA_IMAGE_ADDR..end].copy_from_slice(&reg.image);
+                                self.ram_dirty = true;
+                            }
+                            reg.regs[0] = val & !0x1;
+                        } else {
+                            reg.regs[reg_idx] = val;
                         }
                     }
                 }
@@ -226,47 +535,48 @@ impl<T: CartridgeData> Cartridge<T> {
         }
     }
 
-    pub fn read(&self, addr: u16) -> u8 {
-        if self.mbc == MemoryBankController::MBC0 {
-            return self.data.rom()[addr as usize];
-        }
-
+    // MBC2 picks the register with address bit 8 rather than the address range,
+    // and has 512 half-bytes of RAM built in, mirrored across 0xA000..=0xBFFF
+    fn write_mbc2(&mut self, addr: u16, val: u8) {
         match addr {
-            /* ROM Bank 0 */
             0x0000..=0x3FFF => {
-                let mut addr = addr as usize;
-
-                if let MemoryBankController::MBC1(regs) = &self.mbc {
-                    if regs.bank_mode_sel {
-                        addr |= (regs.two_bit_reg as usize) << 19;
-                    }
-                }
-
-                let mut mask = 1 << 20;
-                while addr >= self.get_header().rom_size as usize {
-                    addr &= !mask;
-                    mask >>= 1;
+                if addr & 0x100 == 0 {
+                    self.ram_en = (val & 0xF) == 0xA;
+                } else {
+                    // Bank 0 reads as bank 1, like MBC1
+                    self.rom_bank_num = (val & 0xF).max(1);
                 }
-
-                return self.data.rom()[addr as usize];
             }
-
-            /* ROM Bank X */
-            0x4000..=0x7FFF => {
-                let mut addr = addr as usize - 0x4000;
-
-                addr |= (self.rom_bank_num as usize) << 14;
-                if let MemoryBankController::MBC1(regs) = &self.mbc {
-                    addr |= (regs.two_bit_reg as usize) << 19;
+            0x4000..=0x7FFF => {}
+            0xA000..=0xBFFF => {
+                if !self.ram_en {
+                    return;
                 }
 
-                let mut mask = 1 << 20;
-                while addr > self.get_header().rom_size as usize {
-                    addr &= !mask;
-                    mask >>= 1;
+                let addr = (addr & 0x1FF) as usize;
+                if let Some(b) = self.data.ram_mut().get_mut(addr) {
+                    *b = val & 0xF;
+                    self.ram_dirty = true;
                 }
+            }
+            _ => {
+                unreachable!("Invalid MBC2 address! addr: {:?}, val: {:?}", addr, val);
+            }
+        }
+    }
 
-                return self.data.rom()[addr];
+    pub fn read(&self, addr: u16) -> u8 {
+        if self.mbc == MemoryBankController::MBC0 {
+            if addr >= 0xA000 {
+                // No RAM, open bus
+                return 0xFF;
+            }
+            return self.read_rom(addr);
+        }
+
+        match addr {
+            0x0000..=0x7FFF => {
+                return self.read_rom(addr);
             }
 
             /* RAM Bank X */
@@ -276,12 +586,31 @@ impl<T: CartridgeData> Cartridge<T> {
                     return 0xFF;
                 }
 
+                if self.mbc == MemoryBankController::MBC2 {
+                    // Only the low nibble exists, the rest reads as 1s
+                    let addr = (addr & 0x1FF) as usize;
+                    return self.data.ram().get(addr).map_or(0xFF, |b| b | 0xF0);
+                }
+
                 let mut addr = (addr - 0xA000) as usize;
 
+                if let MemoryBankController::Camera(regs) = &self.mbc {
+                    if regs.ram_bank & 0x10 == 0x10 {
+                        // Only the capture/status register is readable
+                        if addr & 0x7F == 0 {
+                            return regs.regs[0];
+                        }
+                        return 0;
+                    }
+
+                    addr |= (regs.ram_bank as usize & 0xF) << 13;
+                    return self.data.ram().get(addr).copied().unwrap_or(0xFF);
+                }
+
                 if let MemoryBankController::MBC3(regs) = &self.mbc {
                     match regs.ram_or_rtc {
-                        RamOrRtc::RTC => {
-                            return 0; /* TODO: RTC */
+                        RamOrRtc::RTC(rtc_reg) => {
+                            return regs.rtc_latched[(rtc_reg - 0x08) as usize];
                         }
                         RamOrRtc::BankNum(bank) => {
                             addr |= (bank as usize) << 13;
@@ -290,13 +619,11 @@ impl<T: CartridgeData> Cartridge<T> {
                 }
 
                 if let MemoryBankController::MBC1(regs) = &self.mbc {
-                    if regs.bank_mode_sel {
-                        addr |= (regs.two_bit_reg as usize) << 13;
-                    }
+                    addr |= (regs.ram_bank(self.data.ram().len()) as usize) << 13;
                 }
 
-                //TODO: Size check
-                self.data.ram()[addr]
+                self.ram_offset(addr)
+                    .map_or(0xFF, |addr| self.data.ram()[addr])
             }
 
             _ => {
@@ -308,13 +635,307 @@ impl<T: CartridgeData> Cartridge<T> {
     pub fn get_header(&self) -> CartridgeHeader {
         self.data.get_header()
     }
+
+    // Wraps a banked RAM offset to the size of the RAM, since carts with less
+    // RAM don't connect the upper bank lines. None if there's no RAM at all.
+    fn ram_offset(&self, offset: usize) -> Option<usize> {
+        let len = self.data.ram().len();
+        (len != 0).then(|| offset % len)
+    }
+
+    pub fn mbc_registers(&self) -> MbcRegisters {
+        let mut regs = MbcRegisters {
+            ram_en: self.ram_en,
+            rom_bank_num: self.rom_bank_num,
+            ..Default::default()
+        };
+
+        match &self.mbc {
+            MemoryBankController::MBC0 | MemoryBankController::MBC2 => {}
+            MemoryBankController::MBC1(reg) => {
+                regs.two_bit_reg = reg.two_bit_reg;
+                regs.bank_mode_sel = reg.bank_mode_sel;
+            }
+            MemoryBankController::MBC3(reg) => {
+                regs.ram_bank = match reg.ram_or_rtc {
+                    RamOrRtc::BankNum(bank) => bank,
+                    RamOrRtc::RTC(rtc_reg) => rtc_reg,
+                };
+            }
+            MemoryBankController::Camera(reg) => {
+                regs.ram_bank = reg.ram_bank;
+            }
+        }
+
+        regs
+    }
+
+    // Restores registers from mbc_registers(), the fields that
+    // don't apply to this cartridge's mapper are ignored
+    pub fn set_mbc_registers(&mut self, regs: MbcRegisters) {
+        self.ram_en = regs.ram_en;
+        self.rom_bank_num = regs.rom_bank_num;
+
+        match &mut self.mbc {
+            MemoryBankController::MBC0 | MemoryBankController::MBC2 => {}
+            MemoryBankController::MBC1(reg) => {
+                reg.two_bit_reg = regs.two_bit_reg;
+                reg.bank_mode_sel = regs.bank_mode_sel;
+            }
+            MemoryBankController::MBC3(reg) => {
+                reg.ram_or_rtc = match regs.ram_bank {
+                    rtc_reg @ 0x08..=0x0C => RamOrRtc::RTC(rtc_reg),
+                    bank => RamOrRtc::BankNum(bank),
+                };
+            }
+            MemoryBankController::Camera(reg) => {
+                reg.ram_bank = regs.ram_bank;
+            }
+        }
+    }
+
+    // The ROM bank mapped at 0x4000..=0x7FFF, including MBC1's upper bank
+    // bits and wrapped to the size of the ROM
+    pub fn current_rom_bank(&self) -> u16 {
+        (self.rom_addr(0x4000) >> 14) as u16
+    }
+
+    // The RAM bank mapped at 0xA000..=0xBFFF. For MBC3 this is the RTC
+    // register while one is selected, and for the camera the raw register,
+    // with bit 4 set while the camera registers are mapped in.
+    pub fn current_ram_bank(&self) -> u8 {
+        match &self.mbc {
+            MemoryBankController::MBC0 | MemoryBankController::MBC2 => 0,
+            MemoryBankController::MBC1(reg) => reg.ram_bank(self.data.ram().len()),
+            MemoryBankController::MBC3(reg) => match reg.ram_or_rtc {
+                RamOrRtc::BankNum(bank) => bank,
+                RamOrRtc::RTC(rtc_reg) => rtc_reg,
+            },
+            MemoryBankController::Camera(reg) => reg.ram_bank,
+        }
+    }
+
+    // The banking mode, None if this isn't an MBC1 cartridge
+    pub fn bank_mode(&self) -> Option<BankMode> {
+        match &self.mbc {
+            MemoryBankController::MBC1(reg) if reg.bank_mode_sel => Some(BankMode::Advanced),
+            MemoryBankController::MBC1(_) => Some(BankMode::Simple),
+            _ => None,
+        }
+    }
+
+    // The cartridge RAM, i.e. the battery save, for frontends that store
+    // saves themselves rather than through CartridgeData::save()
+    pub fn export_ram(&self) -> &[u8] {
+        self.data.ram()
+    }
+
+    // Restores RAM from export_ram(), which has to be the same size
+    pub fn import_ram(&mut self, ram: &[u8]) -> Result<(), RamSizeMismatch> {
+        let dst = self.data.ram_mut();
+        if dst.len() != ram.len() {
+            return Err(RamSizeMismatch {
+                expected: dst.len(),
+                actual: ram.len(),
+            });
+        }
+
+        dst.copy_from_slice(ram);
+        self.ram_dirty = false;
+        Ok(())
+    }
+
+    // Whether RAM has been written since the last mark_saved(), so a
+    // frontend can flush battery saves only when they've changed
+    pub fn ram_dirty(&self) -> bool {
+        self.ram_dirty
+    }
+
+    // Should be called after the RAM has been saved
+    pub fn mark_saved(&mut self) {
+        self.ram_dirty = false;
+    }
+
+    // Advances the MBC3 real time clock, a no-op for any other cartridge type.
+    // The frontend should call this with the wall clock time that has passed.
+    pub fn tick_rtc(&mut self, elapsed: Duration) {
+        if let MemoryBankController::MBC3(reg) = &mut self.mbc {
+            reg.tick_rtc(elapsed);
+        }
+    }
+
+    // Has the MBC3 real time clock follow `clock` from now on, instead of
+    // only moving with tick_rtc()
+    #[cfg(any(test, feature = "std"))]
+    pub fn set_clock(&mut self, clock: Box<dyn Clock>) {
+        let now = clock.now();
+        self.clock = Some((clock, now));
+    }
+
+    #[cfg(any(test, feature = "std"))]
+    fn sync_rtc(&mut self) {
+        let Some((clock, last)) = &mut self.clock else {
+            return;
+        };
+
+        let now = clock.now();
+        let elapsed = now.saturating_sub(*last);
+        *last = now;
+        self.tick_rtc(elapsed);
+    }
+
+    // Provides the image the camera will "capture" next, e.g. from a webcam.
+    // `frame` is a CAMERA_WIDTH x CAMERA_HEIGHT grayscale image, one byte per
+    // pixel with 0 being black. Returns false, ignoring the frame, if it's the
+    // wrong size or this isn't a camera cartridge.
+    pub fn set_camera_frame(&mut self, frame: &[u8]) -> bool {
+        if frame.len() != CAMERA_WIDTH * CAMERA_HEIGHT {
+            return false;
+        }
+
+        let MemoryBankController::Camera(reg) = &mut self.mbc else {
+            return false;
+        };
+        reg.set_image(|x, y| frame[y * CAMERA_WIDTH + x]);
+        true
+    }
+
+    // The offset into the ROM data that `addr` (0x0000..=0x7FFF) currently maps to
+    //
+    // Selecting a bank past the end of the ROM mirrors the lower banks, which for the
+    // power-of-two ROM sizes a cartridge can have is the same as dropping the unused
+    // upper address bits. The mask is derived from the ROM data itself rather than the
+    // header so that a corrupt header (e.g. a reported size of 0) can't break the wrap.
+    pub fn rom_addr(&self, addr: u16) -> usize {
+        let mut rom_addr = addr as usize;
+
+        match addr {
+            /* ROM Bank 0 */
+            0x0000..=0x3FFF => {
+                if let MemoryBankController::MBC1(regs) = &self.mbc {
+                    if regs.bank_mode_sel {
+                        rom_addr |= (regs.two_bit_reg as usize) << 19;
+                    }
+                }
+            }
+
+            /* ROM Bank X */
+            _ => {
+                if self.mbc != MemoryBankController::MBC0 {
+                    rom_addr -= 0x4000;
+                    rom_addr |= (self.rom_bank_num as usize) << 14;
+                }
+                if let MemoryBankController::MBC1(regs) = &self.mbc {
+                    rom_addr |= (regs.two_bit_reg as usize) << 19;
+                }
+            }
+        }
+
+        let rom_len = self.data.rom_len();
+        if rom_len == 0 {
+            return 0;
+        }
+
+        let mask = rom_len.next_power_of_two() - 1;
+
+        // The modulo only matters if the ROM length isn't a power of two
+        (rom_addr & mask) % rom_len
+    }
+
+    // The bits of the ROM bank number that select a bank, i.e. enough bits
+    // to represent every bank up to the last one. Like rom_addr(), this goes
+    // by the ROM itself rather than a header that might be corrupt.
+    fn rom_bank_mask(&self) -> u8 {
+        let num_banks = self.data.rom_len().div_ceil(0x4000).max(1);
+        (num_banks.next_power_of_two() - 1) as u8
+    }
+
+    fn read_rom(&self, addr: u16) -> u8 {
+        self.data.read_rom(self.rom_addr(addr))
+    }
+}
+
+#[cfg(any(test, feature = "std"))]
+impl<T: CartridgeData> Snapshot for Cartridge<T> {
+    // Only the RAM and banking state, the ROM is never part of the state
+    fn save(&self, w: &mut StateWriter) {
+        let regs = self.mbc_registers();
+        w.bool(regs.ram_en);
+        w.u8(regs.rom_bank_num);
+        w.u8(regs.two_bit_reg);
+        w.bool(regs.bank_mode_sel);
+        w.u8(regs.ram_bank);
+
+        match &self.mbc {
+            MemoryBankController::MBC0
+            | MemoryBankController::MBC1(_)
+            | MemoryBankController::MBC2 => {}
+            MemoryBankController::MBC3(reg) => {
+                w.u8(reg.latch_clock_data);
+                w.u64(reg.rtc.as_nanos() as u64);
+                w.bool(reg.rtc_halted);
+                w.bool(reg.rtc_day_carry);
+                w.bytes(&reg.rtc_latched);
+            }
+            MemoryBankController::Camera(reg) => {
+                w.bytes(&reg.regs);
+            }
+        }
+
+        let ram = self.data.ram();
+        w.u64(ram.len() as u64);
+        w.bytes(ram);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.set_mbc_registers(MbcRegisters {
+            ram_en: r.bool()?,
+            rom_bank_num: r.u8()?,
+            two_bit_reg: r.u8()?,
+            bank_mode_sel: r.bool()?,
+            ram_bank: r.u8()?,
+        });
+
+        match &mut self.mbc {
+            MemoryBankController::MBC0
+            | MemoryBankController::MBC1(_)
+            | MemoryBankController::MBC2 => {}
+            MemoryBankController::MBC3(reg) => {
+                reg.latch_clock_data = r.u8()?;
+                reg.rtc = Duration::from_nanos(r.u64()?);
+                reg.rtc_halted = r.bool()?;
+                reg.rtc_day_carry = r.bool()?;
+                r.bytes(&mut reg.rtc_latched)?;
+            }
+            MemoryBankController::Camera(reg) => {
+                r.bytes(&mut reg.regs)?;
+            }
+        }
+
+        // The loaded RAM likely differs from whatever was last saved
+        self.ram_dirty = true;
+
+        let ram = self.data.ram_mut();
+        if r.u64()? != ram.len() as u64 {
+            return Err(StateError::BadLength);
+        }
+        r.bytes(ram)
+    }
+}
+
+// Whether the game runs on a DMG, from the CGB flag at 0x143
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GbcMode {
+    DmgOnly,
+    CgbCompatible,
+    CgbOnly,
 }
 
 #[derive(Debug)]
 pub struct CartridgeHeader {
     pub title: String<25>,
     pub manufacturer_code: String<16>,
-    //pub gbc_flag: GbcMode,
+    pub gbc_flag: GbcMode,
     pub licensee_code: String<16>,
     pub is_sgb: bool,
     pub cart_type: u8,
@@ -326,8 +947,33 @@ pub struct CartridgeHeader {
     */
 }
 
+impl CartridgeHeader {
+    // Whether the cartridge RAM (and RTC, if any) is kept by a battery
+    pub fn has_battery(&self) -> bool {
+        matches!(
+            self.cart_type,
+            0x03 | 0x06 | 0x09 | 0x0D | 0x0F | 0x10 | 0x13 | 0x1B | 0x1E | 0x22 | 0xFF
+        )
+    }
+}
+
+// The header ends at 0x14F, get_cart_header() needs at least this much ROM
+pub const HEADER_LEN: usize = 0x150;
+
 pub fn get_cart_header(rom: &[u8]) -> CartridgeHeader {
-    let title = (0x134..=0x143)
+    let gbc_flag = match rom[0x143] {
+        0x80 => GbcMode::CgbCompatible,
+        0xC0 => GbcMode::CgbOnly,
+        _ => GbcMode::DmgOnly,
+    };
+
+    // On color games the last byte of the title is the CGB flag
+    let title_end = if gbc_flag == GbcMode::DmgOnly {
+        0x143
+    } else {
+        0x142
+    };
+    let title = (0x134..=title_end)
         .into_iter()
         .map(|addr| rom[addr])
         .take_while(|b| *b != 0)
@@ -343,6 +989,8 @@ pub fn get_cart_header(rom: &[u8]) -> CartridgeHeader {
 
     let rom_size = 32768 * (1 << rom[0x148]);
     let ram_size = match rom[0x149] {
+        // MBC2 reports no RAM, but has 512 half-bytes built in
+        0 if matches!(rom[0x147], 0x05 | 0x06) => 512,
         0 => 0,
         1 => unreachable!("Invalid amount of RAM"),
         2 => 8192,
@@ -358,7 +1006,7 @@ pub fn get_cart_header(rom: &[u8]) -> CartridgeHeader {
     CartridgeHeader {
         title,
         manufacturer_code,
-        //gbc_flag,
+        gbc_flag,
         licensee_code: String::new(),
         is_sgb: rom[0x146] != 0x03,
         cart_type: rom[0x147],
@@ -440,3 +1088,462 @@ impl Rom {
     }
 }
 */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A cartridge whose header claims a ROM size of 0
+    struct CorruptHeaderCart {
+        rom: [u8; 0x8000],
+    }
+
+    impl CartridgeData for CorruptHeaderCart {
+        fn rom_len(&self) -> usize {
+            self.rom.len()
+        }
+
+        fn read_rom(&self, addr: usize) -> u8 {
+            self.rom.get(addr).copied().unwrap_or(0xFF)
+        }
+
+        fn ram(&self) -> &[u8] {
+            &[]
+        }
+
+        fn ram_mut(&mut self) -> &mut [u8] {
+            &mut []
+        }
+
+        fn get_header(&self) -> CartridgeHeader {
+            CartridgeHeader {
+                title: String::new(),
+                manufacturer_code: String::new(),
+                gbc_flag: GbcMode::DmgOnly,
+                licensee_code: String::new(),
+                is_sgb: false,
+                cart_type: 1,
+                rom_size: 0,
+                ram_size: 0,
+                num_rom_banks: 0,
+            }
+        }
+    }
+
+    #[test]
+    fn zero_rom_size_header() {
+        let mut rom = [0; 0x8000];
+        rom[0x0123] = 0x12;
+        rom[0x4123] = 0x34;
+        rom[0x7FFF] = 0x56;
+        let mut cart = Cartridge::new(CorruptHeaderCart { rom });
+
+        assert_eq!(cart.read(0x0123), 0x12);
+        assert_eq!(cart.read(0x4123), 0x34);
+
+        // Selecting banks past the end wraps rather than hanging or panicking
+        cart.write(0x2000, 0x1F);
+        cart.write(0x4000, 0x3);
+        cart.write(0x6000, 0x1);
+        assert_eq!(cart.read(0x0123), 0x12);
+        // Bank 0x7F of a 2 bank ROM is bank 1, from the ROM's real size
+        assert_eq!(cart.read(0x4123), 0x34);
+        assert_eq!(cart.read(0x7FFF), 0x56);
+    }
+
+    #[test]
+    fn camera_cart() {
+        use crate::util::VecCart;
+        use std::vec;
+
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = 0xFC;
+        rom[0x148] = 0x5;
+        rom[0x149] = 0x4;
+        let mut cart = Cartridge::new(VecCart::from_slice(&rom, None));
+
+        // The image buffer is boxed, other controllers don't make room for it
+        assert!(core::mem::size_of::<MemoryBankController>() < CAMERA_TILE_DATA_LEN);
+
+        cart.write(0x0000, 0xA);
+
+        // RAM banking
+        cart.write(0x4000, 0x3);
+        cart.write(0xA000, 0x42);
+        assert_eq!(cart.read(0xA000), 0x42);
+        cart.write(0x4000, 0x0);
+        assert_eq!(cart.read(0xA000), 0x00);
+
+        // Sensor registers
+        cart.write(0x4000, 0x10);
+        assert_eq!(cart.read(0xA000), 0x00);
+        assert_eq!(cart.read(0xA001), 0x00);
+
+        let frame = [0u8; CAMERA_WIDTH * CAMERA_HEIGHT];
+        assert!(!cart.set_camera_frame(&frame[1..]));
+        assert!(cart.set_camera_frame(&frame));
+        cart.write(0xA000, 0x3);
+        assert_eq!(cart.read(0xA000) & 0x1, 0x0);
+
+        // An all black image lands in RAM bank 0
+        cart.write(0x4000, 0x0);
+        assert_eq!(cart.read(0xA000 + CAMERA_IMAGE_ADDR as u16), 0xFF);
+        assert_eq!(cart.read(0xA000 + CAMERA_IMAGE_ADDR as u16 + 1), 0xFF);
+    }
+
+    #[test]
+    fn mbc0_ignores_writes() {
+        use crate::gb::SmallInMemoryCartridge;
+
+        let mut rom = [0; 0x8000];
+        rom[0x2000] = 0x12;
+        rom[0x4000] = 0x34;
+        let mut cart = Cartridge::new(SmallInMemoryCartridge::from_slice(&rom));
+
+        // Would select a ROM bank on any other mapper
+        cart.write(0x2000, 0x2);
+        assert_eq!(cart.read(0x2000), 0x12);
+        assert_eq!(cart.read(0x4000), 0x34);
+
+        // There is no RAM, even once "enabled"
+        cart.write(0x0000, 0xA);
+        cart.write(0xA000, 0x56);
+        assert_eq!(cart.read(0xA000), 0xFF);
+        assert_eq!(cart.read(0xBFFF), 0xFF);
+    }
+
+    #[test]
+    fn mbc3_rtc() {
+        use crate::util::VecCart;
+        use std::vec;
+
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = 0x10;
+        rom[0x149] = 0x3;
+        let mut cart = Cartridge::new(VecCart::from_slice(&rom, None));
+        cart.write(0x0000, 0xA);
+
+        let latch = |cart: &mut Cartridge<VecCart>| {
+            cart.write(0x6000, 0);
+            cart.write(0x6000, 1);
+        };
+        let read_reg = |cart: &mut Cartridge<VecCart>, reg: u8| {
+            cart.write(0x4000, reg);
+            cart.read(0xA000)
+        };
+
+        // 1 day, 2 hours, 3 minutes and 4 seconds
+        cart.tick_rtc(Duration::from_secs(SECS_PER_DAY + 2 * 3600 + 3 * 60 + 4));
+
+        // Nothing changes until it's latched
+        assert_eq!(read_reg(&mut cart, 0x08), 0);
+        latch(&mut cart);
+        assert_eq!(read_reg(&mut cart, 0x08), 4);
+        assert_eq!(read_reg(&mut cart, 0x09), 3);
+        assert_eq!(read_reg(&mut cart, 0x0A), 2);
+        assert_eq!(read_reg(&mut cart, 0x0B), 1);
+        assert_eq!(read_reg(&mut cart, 0x0C), 0);
+
+        // Halt the clock and set the day counter
+        cart.write(0x4000, 0x0C);
+        cart.write(0xA000, 0x41);
+        cart.write(0x4000, 0x0B);
+        cart.write(0xA000, 0xFF);
+        cart.tick_rtc(Duration::from_secs(SECS_PER_DAY));
+        latch(&mut cart);
+        assert_eq!(read_reg(&mut cart, 0x0B), 0xFF);
+        assert_eq!(read_reg(&mut cart, 0x0C), 0x41);
+        assert_eq!(read_reg(&mut cart, 0x08), 4);
+
+        // Resume, and overflow the day counter
+        cart.write(0x4000, 0x0C);
+        cart.write(0xA000, 0x01);
+        cart.tick_rtc(Duration::from_secs(SECS_PER_DAY));
+        latch(&mut cart);
+        assert_eq!(read_reg(&mut cart, 0x0B), 0);
+        assert_eq!(read_reg(&mut cart, 0x0C), 0x80);
+
+        // RAM banking still works
+        cart.write(0x4000, 0x1);
+        cart.write(0xA000, 0x42);
+        assert_eq!(cart.read(0xA000), 0x42);
+    }
+
+    #[test]
+    fn mbc3_clock() {
+        use crate::util::VecCart;
+        use std::vec;
+
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = 0x10;
+        rom[0x149] = 0x3;
+        let mut cart = Cartridge::new(VecCart::from_slice(&rom, None));
+        let clock = ManualClock::new();
+        clock.advance(Duration::from_secs(1000));
+        cart.set_clock(Box::new(clock.clone()));
+        cart.write(0x0000, 0xA);
+
+        let latched_days = |cart: &mut Cartridge<VecCart>| {
+            cart.write(0x6000, 0);
+            cart.write(0x6000, 1);
+            cart.write(0x4000, 0x0B);
+            let low = cart.read(0xA000);
+            cart.write(0x4000, 0x0C);
+            (low, cart.read(0xA000))
+        };
+
+        // Only the time since the clock was set counts
+        assert_eq!(latched_days(&mut cart), (0, 0));
+
+        clock.advance(Duration::from_secs(511 * SECS_PER_DAY));
+        assert_eq!(latched_days(&mut cart), (0xFF, 0x01));
+
+        // Day 512 wraps to 0 and sets the carry
+        clock.advance(Duration::from_secs(SECS_PER_DAY));
+        assert_eq!(latched_days(&mut cart), (0, 0x80));
+    }
+
+    #[test]
+    fn mbc1_bank_mask() {
+        use crate::util::VecCart;
+        use std::vec;
+
+        // The header ROM size for 2, 16, 64 and 128 banks, and the bank
+        // selected by writing 0x1F and 0x10 to the bank register
+        for (size, banks, bank_1f, bank_10) in [
+            (0, 2, 0x1, 0x0),
+            (3, 16, 0xF, 0x0),
+            (5, 64, 0x1F, 0x10),
+            (6, 128, 0x1F, 0x10),
+        ] {
+            let mut rom = vec![0; 0x8000 << size];
+            rom[0x147] = 0x1;
+            rom[0x148] = size;
+            let mut cart = Cartridge::new(VecCart::from_slice(&rom, None));
+            assert_eq!(cart.get_header().num_rom_banks, banks);
+
+            cart.write(0x2000, 0x1F);
+            assert_eq!(cart.mbc_registers().rom_bank_num, bank_1f);
+            cart.write(0x2000, 0x10);
+            assert_eq!(cart.mbc_registers().rom_bank_num, bank_10);
+        }
+    }
+
+    #[test]
+    fn rom_bank_mask() {
+        use crate::util::VecCart;
+        use std::vec;
+
+        // MBC3 carts with 96 and 128 banks, each bank starting with its number.
+        // Writes to the bank register, and the bank then read from 0x4000.
+        for (banks, selected) in [
+            (96, [(95, 95), (96, 0), (100, 4), (0xFF, 31)]),
+            (128, [(95, 95), (96, 96), (127, 127), (0xFF, 127)]),
+        ] {
+            let mut rom = vec![0; banks * 0x4000];
+            rom[0x147] = 0x11;
+            for bank in 0..banks {
+                rom[bank * 0x4000] = bank as u8;
+            }
+            let mut cart = Cartridge::new(VecCart::from_slice(&rom, None));
+            assert_eq!(cart.rom_bank_mask(), 0x7F);
+
+            for (val, bank) in selected {
+                cart.write(0x2000, val);
+                assert_eq!(cart.read(0x4000), bank, "{banks} banks, wrote {val:#X}");
+            }
+        }
+    }
+
+    #[test]
+    fn current_banks() {
+        use crate::util::VecCart;
+        use std::vec;
+
+        // MBC1+RAM with 64 ROM banks and 4 RAM banks
+        let mut rom = vec![0; 0x8000 << 5];
+        rom[0x147] = 0x2;
+        rom[0x148] = 5;
+        rom[0x149] = 3;
+        let mut cart = Cartridge::new(VecCart::from_slice(&rom, None));
+        assert_eq!(cart.current_rom_bank(), 1);
+        assert_eq!(cart.current_ram_bank(), 0);
+        assert_eq!(cart.bank_mode(), Some(BankMode::Simple));
+
+        // The upper bits pick the ROM bank in either mode, but only
+        // pick the RAM bank in Advanced mode
+        cart.write(0x2000, 0x1A);
+        cart.write(0x4000, 0x1);
+        assert_eq!(cart.current_rom_bank(), 0x3A);
+        assert_eq!(cart.current_ram_bank(), 0);
+        cart.write(0x6000, 0x1);
+        assert_eq!(cart.bank_mode(), Some(BankMode::Advanced));
+        assert_eq!(cart.current_ram_bank(), 1);
+
+        // Other mappers don't have a banking mode
+        rom[0x147] = 0x0;
+        let cart = Cartridge::new(VecCart::from_slice(&rom[..0x8000], None));
+        assert_eq!(cart.current_rom_bank(), 1);
+        assert_eq!(cart.bank_mode(), None);
+    }
+
+    #[test]
+    fn mbc1_small_ram_mode1() {
+        use crate::util::VecCart;
+        use std::vec;
+
+        // MBC1+RAM with 64 ROM banks, which use the upper bank bits, and a
+        // single 8KiB RAM bank, which doesn't
+        let mut rom = vec![0; 0x8000 << 5];
+        rom[0x147] = 0x2;
+        rom[0x148] = 5;
+        rom[0x149] = 2;
+        let mut cart = Cartridge::new(VecCart::from_slice(&rom, None));
+        cart.write(0x0000, 0xA);
+        cart.write(0xA000, 0x11);
+        cart.write(0xBFFF, 0x12);
+
+        cart.write(0x4000, 0x1);
+        cart.write(0x6000, 0x1);
+        assert_eq!(cart.current_rom_bank(), 0x21);
+        assert_eq!(cart.current_ram_bank(), 0);
+        assert_eq!(cart.read(0xA000), 0x11);
+        assert_eq!(cart.read(0xBFFF), 0x12);
+
+        cart.write(0xA000, 0x21);
+        cart.write(0x6000, 0x0);
+        assert_eq!(cart.read(0xA000), 0x21);
+        assert_eq!(cart.export_ram()[..2], [0x21, 0]);
+    }
+
+    #[test]
+    fn export_import_ram() {
+        use crate::util::VecCart;
+        use std::vec;
+
+        // MBC1 with 8KiB of RAM
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = 0x3;
+        rom[0x149] = 0x2;
+        let mut cart = Cartridge::new(VecCart::from_slice(&rom, None));
+        cart.write(0x0000, 0xA);
+        cart.write(0xA010, 0x42);
+
+        let save = cart.export_ram().to_vec();
+        assert_eq!(save.len(), 0x2000);
+        assert_eq!(save[0x10], 0x42);
+
+        let mut other = Cartridge::new(VecCart::from_slice(&rom, None));
+        assert_eq!(
+            other.import_ram(&save[1..]),
+            Err(RamSizeMismatch {
+                expected: 0x2000,
+                actual: 0x1FFF
+            })
+        );
+        assert_eq!(other.import_ram(&save), Ok(()));
+        other.write(0x0000, 0xA);
+        assert_eq!(other.read(0xA010), 0x42);
+    }
+
+    #[test]
+    fn ram_dirty() {
+        use crate::util::VecCart;
+        use std::vec;
+
+        // MBC1+RAM+BATTERY with 8KiB of RAM
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = 0x3;
+        rom[0x149] = 0x2;
+        let mut cart = Cartridge::new(VecCart::from_slice(&rom, None));
+        assert!(cart.get_header().has_battery());
+        assert!(!cart.ram_dirty());
+
+        // Writes with RAM disabled don't count
+        cart.write(0xA000, 0x42);
+        assert!(!cart.ram_dirty());
+
+        cart.write(0x0000, 0xA);
+        cart.write(0xA000, 0x42);
+        assert!(cart.ram_dirty());
+        cart.mark_saved();
+        assert!(!cart.ram_dirty());
+
+        // MBC1+RAM
+        rom[0x147] = 0x2;
+        assert!(!get_cart_header(&rom).has_battery());
+    }
+
+    #[test]
+    fn mbc1_registers() {
+        use crate::util::VecCart;
+        use std::vec;
+
+        // 8 banks, each starting with its bank number
+        let mut rom = vec![0; 0x20000];
+        rom[0x147] = 0x1;
+        rom[0x148] = 0x2;
+        for bank in 0..8 {
+            rom[bank * 0x4000] = bank as u8;
+        }
+        let mut cart = Cartridge::new(VecCart::from_slice(&rom, None));
+
+        cart.write(0x2000, 5);
+        assert_eq!(cart.read(0x4000), 5);
+        let regs = cart.mbc_registers();
+        assert_eq!(regs.rom_bank_num, 5);
+
+        cart.set_mbc_registers(MbcRegisters::default());
+        cart.write(0x2000, 1);
+        assert_eq!(cart.read(0x4000), 1);
+
+        cart.set_mbc_registers(regs);
+        assert_eq!(cart.read(0x4000), 5);
+        assert_eq!(cart.mbc_registers(), regs);
+    }
+
+    #[test]
+    fn ram_out_of_range() {
+        use crate::util::VecCart;
+        use std::vec;
+
+        // MBC1+RAM with a single 8KiB bank. The ROM is large enough that
+        // the upper bank bits are still wired up.
+        let mut rom = vec![0; 0x100000];
+        rom[0x147] = 0x2;
+        rom[0x148] = 0x5;
+        rom[0x149] = 0x2;
+        let mut cart = Cartridge::new(VecCart::from_slice(&rom, None));
+        cart.write(0x0000, 0x0A);
+
+        // Bank 3 wraps around to bank 0
+        cart.write(0x6000, 1);
+        cart.write(0x4000, 3);
+        cart.write(0xA000, 0x12);
+        cart.write(0x4000, 0);
+        assert_eq!(cart.read(0xA000), 0x12);
+
+        // MBC1 without RAM reads open bus and ignores writes
+        rom[0x147] = 0x1;
+        rom[0x149] = 0x0;
+        let mut cart = Cartridge::new(VecCart::from_slice(&rom, None));
+        cart.write(0x0000, 0x0A);
+        cart.write(0xA000, 0x12);
+        assert_eq!(cart.read(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn gbc_flag() {
+        let mut rom = [0; 0x8000];
+        rom[0x134..0x13E].copy_from_slice(b"COLORGAME!");
+        assert_eq!(get_cart_header(&rom).gbc_flag, GbcMode::DmgOnly);
+
+        rom[0x143] = 0x80;
+        let header = get_cart_header(&rom);
+        assert_eq!(header.gbc_flag, GbcMode::CgbCompatible);
+        assert_eq!(header.title, "COLORGAME!");
+
+        rom[0x143] = 0xC0;
+        assert_eq!(get_cart_header(&rom).gbc_flag, GbcMode::CgbOnly);
+    }
+}
@@ -1,9 +1,43 @@
+use core::fmt::Write;
+use heapless::{String, Vec};
+
 use crate::{
-    bus::{Bus, Device},
+    bus::{Bus, Device, WatchHit},
     cart::CartridgeData,
+    disasm::DecodedInstr,
     interrupts::IntSource,
 };
 
+#[cfg(any(test, feature = "std"))]
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+type Handler<T> = fn(&mut Cpu<T>, u8) -> u8;
+
+pub const MAX_BREAKPOINTS: usize = 16;
+
+// The length of a line from Cpu::trace_line()
+pub const TRACE_LINE_LEN: usize = 65;
+
+// Why run_until_break() stopped
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BreakReason {
+    // PC reached a breakpoint
+    Breakpoint(u16),
+    // An instruction hit a watchpoint on the bus
+    Watchpoint(WatchHit),
+    Fault(CpuError),
+    CycleLimit,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CpuError {
+    // One of the opcodes the SM83 doesn't have, at `pc`
+    InvalidOpcode { opcode: u8, pc: u16 },
+    // A STOP at `pc` that isn't a CGB speed switch. The low power mode it
+    // enters isn't emulated.
+    UnsupportedStop { pc: u16 },
+}
+
 #[inline(always)]
 fn does_bit3_overflow(a: u8, b: u8) -> bool {
     let a = a & 0xF;
@@ -27,6 +61,35 @@ fn does_bit3_borrow(a: u8, b: u8) -> bool {
     return b > a;
 }
 
+// SP plus a signed offset, for ADD SP,e8 and LD HL,SP+e8. The H and C
+// flags come from the unsigned add of the offset byte to SP's low byte,
+// whatever the offset's sign.
+#[inline(always)]
+fn add_sp_signed(sp: u16, e8: i8) -> (u16, bool, bool) {
+    let offset = e8 as u8;
+    let h = (sp & 0xF) + (offset as u16 & 0xF) > 0xF;
+    let c = (sp & 0xFF) + offset as u16 > 0xFF;
+    (sp.wrapping_add(e8 as u16), h, c)
+}
+
+// A copy of the CPU's registers, for setting up and checking its state
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub struct CpuRegisters {
+    pub a: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub sp: u16,
+    pub pc: u16,
+    pub z_f: bool,
+    pub n_f: bool,
+    pub h_f: bool,
+    pub c_f: bool,
+}
+
 pub struct Cpu<T: CartridgeData> {
     a: u8,
     b: u8,
@@ -44,9 +107,23 @@ pub struct Cpu<T: CartridgeData> {
     c_f: bool,
 
     ime: bool,
+    // EI only sets IME once the instruction after it has finished
+    ime_pending: bool,
+    // Set when HALT fails to increment PC on the next fetch
+    halt_bug: bool,
 
     pub sleep: bool,
     pub bus: Bus<T>,
+
+    // Set when the CPU locks up, until a reset or clear_fault()
+    fault: Option<CpuError>,
+
+    // The bus is run as the current instruction accesses memory, see
+    // cycle_read(). Both are only meaningful within run_one().
+    access_pending: bool,
+    cycles_run: u8,
+
+    breakpoints: Vec<u16, MAX_BREAKPOINTS>,
 }
 
 const PAGE0_OFFSET: u16 = 0xFF00;
@@ -131,8 +208,9 @@ impl<T: CartridgeData> Cpu<T> {
     #[inline(always)]
     fn inc_r16(cpu: &mut Self, opcode: u8) -> u8 {
         let r16 = (opcode >> 4) & 0x3;
-        let plus_one = cpu.rreg16(r16).wrapping_add(1);
-        cpu.wreg16(r16, plus_one);
+        let before = cpu.rreg16(r16);
+        cpu.bus.ppu.oam_bug_write(before);
+        cpu.wreg16(r16, before.wrapping_add(1));
         2
     }
 
@@ -183,8 +261,8 @@ impl<T: CartridgeData> Cpu<T> {
         let imm16 = cpu.load_word();
 
         // This is why: https://rgbds.gbdev.io/docs/v0.8.0/gbz80.7#LD__n16_,SP
-        cpu.bus.write(imm16, cpu.sp as u8);
-        cpu.bus.write(imm16 + 1, (cpu.sp >> 8) as u8);
+        cpu.cycle_write(imm16, cpu.sp as u8);
+        cpu.cycle_write(imm16 + 1, (cpu.sp >> 8) as u8);
         5
     }
 
@@ -211,8 +289,9 @@ impl<T: CartridgeData> Cpu<T> {
     #[inline(always)]
     fn dec_r16(cpu: &mut Self, opcode: u8) -> u8 {
         let r16 = (opcode >> 4) & 0x3;
-        let minus_one = cpu.rreg16(r16).wrapping_sub(1);
-        cpu.wreg16(r16, minus_one);
+        let before = cpu.rreg16(r16);
+        cpu.bus.ppu.oam_bug_write(before);
+        cpu.wreg16(r16, before.wrapping_sub(1));
         2
     }
 
@@ -224,8 +303,16 @@ impl<T: CartridgeData> Cpu<T> {
     }
 
     #[inline(always)]
-    fn stop(_cpu: &mut Self, opcode: u8) -> u8 {
-        todo!("Stop instruction not implemented! opcode: {}", opcode);
+    fn stop(cpu: &mut Self, _opcode: u8) -> u8 {
+        // Only the CGB speed switch is supported. STOP skips the byte after it.
+        let pc = cpu.pc.wrapping_sub(1);
+        cpu.load_byte();
+        if cpu.bus.take_speed_switch() {
+            return 2;
+        }
+
+        cpu.fault = Some(CpuError::UnsupportedStop { pc });
+        1
     }
 
     #[inline(always)]
@@ -340,8 +427,10 @@ impl<T: CartridgeData> Cpu<T> {
             return 1;
         }
 
-        //TODO: Handle HALT bug
-        //assert!(false);
+        // HALT bug: with IME off and an interrupt already pending, HALT exits
+        // immediately and the CPU fails to increment PC after the next fetch,
+        // so the byte following HALT is read twice.
+        cpu.halt_bug = true;
         return 1;
     }
 
@@ -360,30 +449,41 @@ impl<T: CartridgeData> Cpu<T> {
         return if r8 == HL_PTR { 2 } else { 1 };
     }
 
+    // A + val + carry, with the flags computed from the whole sum
     #[inline(always)]
-    fn adc_a_r8(cpu: &mut Self, opcode: u8) -> u8 {
-        let r8 = opcode & 0x7;
-        let reg_val = cpu.rreg8(r8);
-        cpu.n_f = false;
-        cpu.h_f = does_bit3_overflow(cpu.a, reg_val);
+    fn adc(&mut self, val: u8) {
+        let carry = self.c_f as u8;
+        let sum = self.a as u16 + val as u16 + carry as u16;
 
-        let (added, overflow) = reg_val.overflowing_add(cpu.a);
+        self.h_f = (self.a & 0xF) + (val & 0xF) + carry > 0xF;
+        self.c_f = sum > 0xFF;
+        self.n_f = false;
+        self.a = sum as u8;
+        self.z_f = self.a == 0;
+    }
 
-        if cpu.c_f {
-            // Next two conditionals check if the carry will overflow
-            if does_bit3_overflow(added, 1) {
-                cpu.h_f = true;
-            }
+    // A - val - carry, with the flags computed from the whole difference
+    #[inline(always)]
+    fn sbc(&mut self, val: u8) {
+        let carry = self.c_f as u8;
 
-            cpu.c_f = overflow || added == 0xFF;
-            cpu.a = added.wrapping_add(1);
+        self.h_f = (self.a & 0xF) < (val & 0xF) + carry;
+        self.c_f = (self.a as u16) < val as u16 + carry as u16;
+        self.n_f = true;
+        self.a = self.a.wrapping_sub(val).wrapping_sub(carry);
+        self.z_f = self.a == 0;
+    }
+
+    #[inline(always)]
+    fn adc_a_r8(cpu: &mut Self, opcode: u8) -> u8 {
+        let r8 = opcode & 0x7;
+        let reg_val = cpu.rreg8(r8);
+        cpu.adc(reg_val);
+        if r8 == HL_PTR {
+            2
         } else {
-            cpu.c_f = overflow;
-            cpu.a = added;
+            1
         }
-
-        cpu.z_f = cpu.a == 0;
-        return if r8 == HL_PTR { 2 } else { 1 };
     }
 
     #[inline(always)]
@@ -404,23 +504,13 @@ impl<T: CartridgeData> Cpu<T> {
     #[inline(always)]
     fn sbc_a_r8(cpu: &mut Self, opcode: u8) -> u8 {
         let r8 = opcode & 0x07;
-        let carry = if cpu.c_f { 1 } else { 0 };
         let reg_val = cpu.rreg8(r8);
-
-        cpu.c_f = (reg_val as u16 + carry as u16) > cpu.a as u16;
-
-        cpu.h_f = does_bit3_borrow(cpu.a, reg_val);
-
-        cpu.n_f = true;
-        cpu.a = cpu.a.wrapping_sub(reg_val);
-
-        if does_bit3_borrow(cpu.a, carry) {
-            cpu.h_f = true;
+        cpu.sbc(reg_val);
+        if r8 == HL_PTR {
+            2
+        } else {
+            1
         }
-
-        cpu.a = cpu.a.wrapping_sub(carry);
-        cpu.z_f = cpu.a == 0;
-        return if r8 == HL_PTR { 2 } else { 1 };
     }
 
     #[inline(always)]
@@ -486,26 +576,8 @@ impl<T: CartridgeData> Cpu<T> {
     #[inline(always)]
     fn adc_a_imm8(cpu: &mut Self, _opcode: u8) -> u8 {
         let imm8 = cpu.load_byte();
-        cpu.n_f = false;
-        cpu.h_f = does_bit3_overflow(cpu.a, imm8);
-
-        let (added, overflow) = cpu.a.overflowing_add(imm8);
-
-        if cpu.c_f {
-            // Next two conditionals check if the carry will overflow
-            if does_bit3_overflow(added, 1) {
-                cpu.h_f = true;
-            }
-
-            cpu.c_f = overflow || added == 0xFF;
-            cpu.a = added.wrapping_add(1);
-        } else {
-            cpu.c_f = overflow;
-            cpu.a = added;
-        }
-
-        cpu.z_f = cpu.a == 0;
-        return 2;
+        cpu.adc(imm8);
+        2
     }
 
     #[inline(always)]
@@ -525,19 +597,7 @@ impl<T: CartridgeData> Cpu<T> {
     #[inline(always)]
     fn sbc_a_imm8(cpu: &mut Self, _opcode: u8) -> u8 {
         let imm8 = cpu.load_byte();
-        let carry = if cpu.c_f { 1 } else { 0 };
-
-        cpu.c_f = (imm8 as u16 + carry as u16) > cpu.a as u16;
-        cpu.h_f = does_bit3_borrow(cpu.a, imm8);
-        cpu.n_f = true;
-        cpu.a = cpu.a.wrapping_sub(imm8);
-
-        if does_bit3_borrow(cpu.a, carry) {
-            cpu.h_f = true;
-        }
-
-        cpu.a = cpu.a.wrapping_sub(carry);
-        cpu.z_f = cpu.a == 0;
+        cpu.sbc(imm8);
         2
     }
 
@@ -719,36 +779,37 @@ impl<T: CartridgeData> Cpu<T> {
     }
 
     #[inline(always)]
-    fn invalid(_cpu: &mut Self, opcode: u8) -> u8 {
-        panic!("Received invalid instruction! opcode: {}", opcode);
+    // The real CPU hangs on these, see fault()
+    fn invalid(cpu: &mut Self, opcode: u8) -> u8 {
+        cpu.fault = Some(CpuError::InvalidOpcode {
+            opcode,
+            pc: cpu.pc.wrapping_sub(1),
+        });
+        1
     }
 
     #[inline(always)]
     fn ldh_imm8_a(cpu: &mut Self, _opcode: u8) -> u8 {
         let imm8 = cpu.load_byte();
-        cpu.bus.write(imm8 as u16 + PAGE0_OFFSET, cpu.a);
+        cpu.cycle_write(imm8 as u16 + PAGE0_OFFSET, cpu.a);
         3
     }
 
     #[inline(always)]
     fn ldh_c_a(cpu: &mut Self, _opcode: u8) -> u8 {
-        cpu.bus.write(PAGE0_OFFSET + cpu.c as u16, cpu.a);
+        cpu.cycle_write(PAGE0_OFFSET + cpu.c as u16, cpu.a);
         2
     }
 
     #[inline(always)]
     fn add_sp_imm8(cpu: &mut Self, _opcode: u8) -> u8 {
         let imm8 = cpu.load_byte();
-        let s_i = imm8 as i8;
+        let (new_sp, h, c) = add_sp_signed(cpu.sp, imm8 as i8);
 
-        let new_sp = cpu.sp.wrapping_add(s_i as u16);
         cpu.z_f = false;
         cpu.n_f = false;
-        cpu.h_f = does_bit3_overflow(imm8, cpu.sp as u8);
-
-        let (_, does_bit7_of) = (cpu.sp as u8).overflowing_add(imm8);
-        cpu.c_f = does_bit7_of;
-
+        cpu.h_f = h;
+        cpu.c_f = c;
         cpu.sp = new_sp;
         4
     }
@@ -763,20 +824,20 @@ impl<T: CartridgeData> Cpu<T> {
     #[inline(always)]
     fn ld_imm16_a(cpu: &mut Self, _opcode: u8) -> u8 {
         let imm16 = cpu.load_word();
-        cpu.bus.write(imm16, cpu.a);
+        cpu.cycle_write(imm16, cpu.a);
         4
     }
 
     #[inline(always)]
     fn ldh_a_imm8(cpu: &mut Self, _opcode: u8) -> u8 {
         let imm8 = cpu.load_byte();
-        cpu.a = cpu.bus.read(imm8 as u16 + PAGE0_OFFSET);
+        cpu.a = cpu.cycle_read(imm8 as u16 + PAGE0_OFFSET);
         3
     }
 
     #[inline(always)]
     fn ldh_a_c(cpu: &mut Self, _opcode: u8) -> u8 {
-        cpu.a = cpu.bus.read(cpu.c as u16 + PAGE0_OFFSET);
+        cpu.a = cpu.cycle_read(cpu.c as u16 + PAGE0_OFFSET);
         2
     }
 
@@ -803,30 +864,25 @@ impl<T: CartridgeData> Cpu<T> {
     #[inline(always)]
     fn di(cpu: &mut Self, _opcode: u8) -> u8 {
         cpu.ime = false;
+        cpu.ime_pending = false;
         1
     }
 
     #[inline(always)]
     fn ei(cpu: &mut Self, _opcode: u8) -> u8 {
-        cpu.ime = true;
+        cpu.ime_pending = true;
         1
     }
 
     #[inline(always)]
     fn ld_hl_sp_imm8(cpu: &mut Self, _opcode: u8) -> u8 {
         let imm8 = cpu.load_byte();
-        let sp = cpu.sp as i32;
-        let i_8 = imm8 as i8;
-        let new_hl = (sp + i_8 as i32) as u16;
+        let (new_hl, h, c) = add_sp_signed(cpu.sp, imm8 as i8);
 
         cpu.z_f = false;
         cpu.n_f = false;
-        cpu.h_f = does_bit3_overflow(imm8 as u8, cpu.sp as u8);
-
-        // Checking for signed byte add overflow
-        let (_, does_of) = (cpu.sp as u8).overflowing_add(imm8);
-
-        cpu.c_f = does_of;
+        cpu.h_f = h;
+        cpu.c_f = c;
         cpu.h = (new_hl >> 8) as u8;
         cpu.l = (new_hl & 0xFF) as u8;
         3
@@ -842,7 +898,7 @@ impl<T: CartridgeData> Cpu<T> {
     #[inline(always)]
     fn ld_a_imm16(cpu: &mut Self, _opcode: u8) -> u8 {
         let imm16 = cpu.load_word();
-        cpu.a = cpu.bus.read(imm16);
+        cpu.a = cpu.cycle_read(imm16);
         4
     }
 
@@ -971,8 +1027,8 @@ impl<T: CartridgeData> Cpu<T> {
         return if r == HL_PTR { 4 } else { 2 };
     }
 
-    //TODO: Add an API to build the CPU in a state that
-    //      does not skip the boot rom
+    // Starts in the state the boot ROM leaves behind, see new_at_boot_rom()
+    // to run the boot ROM itself
     pub fn new(bus: Bus<T>) -> Self {
         Cpu {
             a: 0x01,
@@ -989,8 +1045,14 @@ impl<T: CartridgeData> Cpu<T> {
             n_f: false,
             z_f: true,
             ime: false,
+            ime_pending: false,
+            halt_bug: false,
             sleep: false,
             bus,
+            fault: None,
+            access_pending: false,
+            cycles_run: 0,
+            breakpoints: Vec::new(),
         }
 
         // I don't remember exactly why this was
@@ -1005,6 +1067,127 @@ impl<T: CartridgeData> Cpu<T> {
         // cpu.bus.write(0xFF44, 0x90);
     }
 
+    // Starts executing at `pc` with the stack at `sp`,
+    // otherwise the same as the post boot state from new()
+    pub fn new_running_at(bus: Bus<T>, pc: u16, sp: u16) -> Self {
+        let mut cpu = Self::new(bus);
+        cpu.pc = pc;
+        cpu.sp = sp;
+        cpu
+    }
+
+    // Starts at 0 with every register cleared, for running a boot ROM
+    // mapped with Bus::map_boot_rom()
+    pub fn new_at_boot_rom(bus: Bus<T>) -> Self {
+        let mut cpu = Self::new(bus);
+        cpu.set_registers(CpuRegisters::default());
+        cpu
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    // Why the CPU stopped running code, if it has. run_one() still runs the
+    // rest of the system, but GbRs's run functions stop at a fault so the
+    // frontend can show an error and offer a reset.
+    pub fn fault(&self) -> Option<CpuError> {
+        self.fault
+    }
+
+    // Carries on after a fault, from the instruction after the one that
+    // caused it
+    pub fn clear_fault(&mut self) {
+        self.fault = None;
+    }
+
+    pub fn sp(&self) -> u16 {
+        self.sp
+    }
+
+    pub fn registers(&self) -> CpuRegisters {
+        CpuRegisters {
+            a: self.a,
+            b: self.b,
+            c: self.c,
+            d: self.d,
+            e: self.e,
+            h: self.h,
+            l: self.l,
+            sp: self.sp,
+            pc: self.pc,
+            z_f: self.z_f,
+            n_f: self.n_f,
+            h_f: self.h_f,
+            c_f: self.c_f,
+        }
+    }
+
+    pub fn set_registers(&mut self, regs: CpuRegisters) {
+        let CpuRegisters {
+            a,
+            b,
+            c,
+            d,
+            e,
+            h,
+            l,
+            sp,
+            pc,
+            z_f,
+            n_f,
+            h_f,
+            c_f,
+        } = regs;
+        (self.a, self.b, self.c, self.d, self.e, self.h, self.l) = (a, b, c, d, e, h, l);
+        (self.sp, self.pc) = (sp, pc);
+        (self.z_f, self.n_f, self.h_f, self.c_f) = (z_f, n_f, h_f, c_f);
+    }
+
+    // Disassembles the next N instructions starting at PC without changing
+    // any state, including watchpoints.
+    pub fn get_next_instrs<const N: usize>(&self) -> [DecodedInstr; N] {
+        let mut addr = self.pc;
+        core::array::from_fn(|_| {
+            let bytes = core::array::from_fn(|i| self.bus.peek(addr.wrapping_add(i as u16)));
+            let instr = DecodedInstr::decode(addr, bytes);
+            addr = addr.wrapping_add(instr.len as u16);
+            instr
+        })
+    }
+
+    // The registers and the 4 bytes at PC on one line, for diffing against
+    // reference logs, e.g.
+    // `A:01 F:Z-HC BC:0013 DE:00D8 HL:014D SP:FFFE PC:0100 (00 C3 50 01)`
+    pub fn trace_line(&self) -> String<TRACE_LINE_LEN> {
+        let make_u16 = |h, l| -> u16 { (h as u16) << 8 | (l as u16) };
+        let flag = |set: bool, name: char| if set { name } else { '-' };
+        let mem = |offset: u16| self.bus.peek(self.pc.wrapping_add(offset));
+
+        let mut line = String::new();
+        // Always fits, the line is a fixed length
+        let _ = write!(
+            line,
+            "A:{:02X} F:{}{}{}{} BC:{:04X} DE:{:04X} HL:{:04X} SP:{:04X} PC:{:04X} \
+             ({:02X} {:02X} {:02X} {:02X})",
+            self.a,
+            flag(self.z_f, 'Z'),
+            flag(self.n_f, 'N'),
+            flag(self.h_f, 'H'),
+            flag(self.c_f, 'C'),
+            make_u16(self.b, self.c),
+            make_u16(self.d, self.e),
+            make_u16(self.h, self.l),
+            self.sp,
+            self.pc,
+            mem(0),
+            mem(1),
+            mem(2),
+            mem(3),
+        );
+        line
+    }
+
     #[inline(always)]
     fn rreg8(&mut self, dst: u8) -> u8 {
         match dst {
@@ -1018,7 +1201,7 @@ impl<T: CartridgeData> Cpu<T> {
                 // This is a special case, instead of setting a register,
                 // we use the memory location pointed to by the HL register
                 let hl = ((self.h as u16) << 8) | (self.l as u16);
-                return self.bus.read(hl);
+                return self.cycle_read(hl);
             }
             7 => return self.a,
             _ => unreachable!("rreg8 with invalid bit index! {dst}"),
@@ -1038,7 +1221,7 @@ impl<T: CartridgeData> Cpu<T> {
                 // This is a special case, instead of setting a register,
                 // we use the memory location pointed to by the HL register
                 let hl = ((self.h as u16) << 8) | (self.l as u16);
-                self.bus.write(hl, val);
+                self.cycle_write(hl, val);
             }
             7 => self.a = val,
             _ => unreachable!("Set reg8 with invalid bit index! {dst}"),
@@ -1089,11 +1272,11 @@ impl<T: CartridgeData> Cpu<T> {
     fn rr16mem(&mut self, r16mem: u8) -> u8 {
         let make_u16 = |h, l| -> u16 { (h as u16) << 8 | (l as u16) };
         match r16mem {
-            0 => return self.bus.read(make_u16(self.b, self.c)),
-            1 => return self.bus.read(make_u16(self.d, self.e)),
+            0 => return self.cycle_read(make_u16(self.b, self.c)),
+            1 => return self.cycle_read(make_u16(self.d, self.e)),
             2 => {
                 let mut hl = make_u16(self.h, self.l);
-                let ret = self.bus.read(hl);
+                let ret = self.cycle_read(hl);
                 hl = hl + 1;
                 self.h = (hl >> 8) as u8;
                 self.l = (hl & 0xFF) as u8;
@@ -1101,7 +1284,7 @@ impl<T: CartridgeData> Cpu<T> {
             }
             3 => {
                 let mut hl = make_u16(self.h, self.l);
-                let ret = self.bus.read(hl);
+                let ret = self.cycle_read(hl);
                 hl = hl.wrapping_sub(1);
                 self.h = (hl >> 8) as u8;
                 self.l = (hl & 0xFF) as u8;
@@ -1126,18 +1309,18 @@ impl<T: CartridgeData> Cpu<T> {
     fn wr16mem(&mut self, r16mem: u8, val: u8) {
         let make_u16 = |h, l| -> u16 { (h as u16) << 8 | (l as u16) };
         match r16mem {
-            0 => self.bus.write(make_u16(self.b, self.c), val),
-            1 => self.bus.write(make_u16(self.d, self.e), val),
+            0 => self.cycle_write(make_u16(self.b, self.c), val),
+            1 => self.cycle_write(make_u16(self.d, self.e), val),
             2 => {
                 let mut hl = make_u16(self.h, self.l);
-                self.bus.write(hl, val);
+                self.cycle_write(hl, val);
                 hl = hl.wrapping_add(1);
                 self.h = (hl >> 8) as u8;
                 self.l = (hl & 0xFF) as u8;
             }
             3 => {
                 let mut hl = make_u16(self.h, self.l);
-                self.bus.write(hl, val);
+                self.cycle_write(hl, val);
                 hl = hl.wrapping_sub(1);
                 self.h = (hl >> 8) as u8;
                 self.l = (hl & 0xFF) as u8;
@@ -1149,23 +1332,55 @@ impl<T: CartridgeData> Cpu<T> {
     #[inline(always)]
     fn push_stack(&mut self, val: u16) {
         self.sp = self.sp - 1;
-        self.bus.write(self.sp, (val >> 8) as u8);
+        self.cycle_write(self.sp, (val >> 8) as u8);
         self.sp = self.sp - 1;
-        self.bus.write(self.sp, (val & 0xFF) as u8);
+        self.cycle_write(self.sp, (val & 0xFF) as u8);
     }
 
     #[inline(always)]
     fn pop_stack(&mut self) -> u16 {
-        let mut ret = self.bus.read(self.sp) as u16;
+        let mut ret = self.cycle_read(self.sp) as u16;
         self.sp = self.sp + 1;
-        ret |= (self.bus.read(self.sp) as u16) << 8;
+        ret |= (self.cycle_read(self.sp) as u16) << 8;
         self.sp = self.sp + 1;
         return ret;
     }
 
+    // Every memory access takes an M-cycle. The bus is run for the previous
+    // access's cycle just before the next one, so IO registers see each
+    // access at the right point in the instruction. run_one() runs whatever
+    // is left, the last access and any internal cycles, once it's done.
+    #[inline(always)]
+    fn cycle_read(&mut self, addr: u16) -> u8 {
+        self.catch_up();
+        self.bus.read(addr)
+    }
+
+    #[inline(always)]
+    fn cycle_write(&mut self, addr: u16, val: u8) {
+        self.catch_up();
+        self.bus.write(addr, val);
+    }
+
+    #[inline(always)]
+    fn catch_up(&mut self) {
+        if self.access_pending {
+            self.bus.run_cycles(1);
+            self.cycles_run += 1;
+        }
+        self.access_pending = true;
+    }
+
+    // Runs the bus for the cycles of the instruction that are left
+    #[inline(always)]
+    fn finish_cycles(&mut self, cycles: usize) {
+        let remaining = cycles - self.cycles_run as usize;
+        self.bus.run_cycles(remaining as u16);
+    }
+
     #[inline(always)]
     fn load_byte(&mut self) -> u8 {
-        let next_byte = self.bus.read(self.pc);
+        let next_byte = self.cycle_read(self.pc);
         self.pc += 1;
         return next_byte;
     }
@@ -1230,12 +1445,47 @@ impl<T: CartridgeData> Cpu<T> {
         self.c_f = (reg_val & 1) == 1;
     }
 
-    #[inline(always)]
-    pub fn is_passed(&self) -> bool {
-        return self.bus.is_passed();
+    // Returns false if there are already MAX_BREAKPOINTS
+    pub fn add_breakpoint(&mut self, addr: u16) -> bool {
+        self.breakpoints.contains(&addr) || self.breakpoints.push(addr).is_ok()
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.retain(|&bp| bp != addr);
+    }
+
+    // Runs until PC reaches a breakpoint, a watchpoint on the bus is hit, or
+    // `max_cycles` M-cycles have passed. At least one instruction is run, so
+    // calling this again continues on from a breakpoint.
+    pub fn run_until_break(&mut self, max_cycles: usize) -> BreakReason {
+        while self.bus.take_watch_hit().is_some() {}
+
+        let mut cycles = 0;
+        while cycles < max_cycles {
+            cycles += self.run_one();
+
+            if let Some(hit) = self.bus.take_watch_hit() {
+                return BreakReason::Watchpoint(hit);
+            }
+
+            if let Some(fault) = self.fault {
+                return BreakReason::Fault(fault);
+            }
+
+            if self.breakpoints.contains(&self.pc) {
+                return BreakReason::Breakpoint(self.pc);
+            }
+        }
+
+        BreakReason::CycleLimit
     }
 
     pub fn run_one(&mut self) -> usize {
+        if self.fault.is_some() {
+            self.bus.run_cycles(1);
+            return 1;
+        }
+
         // Review this and make sure all four conditions are handled correctly
         // with IME and HALT
         if self.sleep {
@@ -1249,162 +1499,200 @@ impl<T: CartridgeData> Cpu<T> {
 
         if self.ime {
             if let Some(interrupt) = self.bus.query_interrupt() {
-                let cycles = self.handle_interrupt(interrupt);
-                self.bus.run_cycles(cycles as u16);
-                return cycles;
+                return self.handle_interrupt(interrupt);
             }
         }
 
-        let opcode = self.bus.read(self.pc);
-        self.pc += 1;
-        let cycles = match opcode {
-            0x00 => Self::no_op(self, opcode),
-            0x01 => Self::ld_r16_imm16(self, opcode),
-            0x02 => Self::ld_r16mem_a(self, opcode),
-            0x03 => Self::inc_r16(self, opcode),
-            0x04 => Self::inc_r8(self, opcode),
-            0x05 => Self::dec_r8(self, opcode),
-            0x06 => Self::ld_r8_imm8(self, opcode),
-            0x07 => Self::rlca(self, opcode),
-            0x08 => Self::ld_imm16_sp(self, opcode),
-            0x09 => Self::add_hl_r16(self, opcode),
-            0x0A => Self::ld_a_r16mem(self, opcode),
-            0x0B => Self::dec_r16(self, opcode),
-            0x0C => Self::inc_r8(self, opcode),
-            0x0D => Self::dec_r8(self, opcode),
-            0x0E => Self::ld_r8_imm8(self, opcode),
-            0x0F => Self::rrca(self, opcode),
-            0x10 => Self::stop(self, opcode),
-            0x11 => Self::ld_r16_imm16(self, opcode),
-            0x12 => Self::ld_r16mem_a(self, opcode),
-            0x13 => Self::inc_r16(self, opcode),
-            0x14 => Self::inc_r8(self, opcode),
-            0x15 => Self::dec_r8(self, opcode),
-            0x16 => Self::ld_r8_imm8(self, opcode),
-            0x17 => Self::rla(self, opcode),
-            0x18 => Self::jr_imm8(self, opcode),
-            0x19 => Self::add_hl_r16(self, opcode),
-            0x1A => Self::ld_a_r16mem(self, opcode),
-            0x1B => Self::dec_r16(self, opcode),
-            0x1C => Self::inc_r8(self, opcode),
-            0x1D => Self::dec_r8(self, opcode),
-            0x1E => Self::ld_r8_imm8(self, opcode),
-            0x1F => Self::rra(self, opcode),
-            0x20 => Self::jr_cond_imm8(self, opcode),
-            0x21 => Self::ld_r16_imm16(self, opcode),
-            0x22 => Self::ld_r16mem_a(self, opcode),
-            0x23 => Self::inc_r16(self, opcode),
-            0x24 => Self::inc_r8(self, opcode),
-            0x25 => Self::dec_r8(self, opcode),
-            0x26 => Self::ld_r8_imm8(self, opcode),
-            0x27 => Self::daa(self, opcode),
-            0x28 => Self::jr_cond_imm8(self, opcode),
-            0x29 => Self::add_hl_r16(self, opcode),
-            0x2A => Self::ld_a_r16mem(self, opcode),
-            0x2B => Self::dec_r16(self, opcode),
-            0x2C => Self::inc_r8(self, opcode),
-            0x2D => Self::dec_r8(self, opcode),
-            0x2E => Self::ld_r8_imm8(self, opcode),
-            0x2F => Self::cpl(self, opcode),
-            0x30 => Self::jr_cond_imm8(self, opcode),
-            0x31 => Self::ld_r16_imm16(self, opcode),
-            0x32 => Self::ld_r16mem_a(self, opcode),
-            0x33 => Self::inc_r16(self, opcode),
-            0x34 => Self::inc_r8(self, opcode),
-            0x35 => Self::dec_r8(self, opcode),
-            0x36 => Self::ld_r8_imm8(self, opcode),
-            0x37 => Self::scf(self, opcode),
-            0x38 => Self::jr_cond_imm8(self, opcode),
-            0x39 => Self::add_hl_r16(self, opcode),
-            0x3A => Self::ld_a_r16mem(self, opcode),
-            0x3B => Self::dec_r16(self, opcode),
-            0x3C => Self::inc_r8(self, opcode),
-            0x3D => Self::dec_r8(self, opcode),
-            0x3E => Self::ld_r8_imm8(self, opcode),
-            0x3F => Self::ccf(self, opcode),
-            0x40..=0x75 | 0x77..=0x7F => Self::ld_r8_r8(self, opcode),
-            0x76 => Self::halt(self, opcode),
-            0x80..=0x87 => Self::add_a_r8(self, opcode),
-            0x88..=0x8F => Self::adc_a_r8(self, opcode),
-            0x90..=0x97 => Self::sub_a_r8(self, opcode),
-            0x98..=0x9F => Self::sbc_a_r8(self, opcode),
-            0xA0..=0xA7 => Self::and_a_r8(self, opcode),
-            0xA8..=0xAF => Self::xor_a_r8(self, opcode),
-            0xB0..=0xB7 => Self::or_a_r8(self, opcode),
-            0xB8..=0xBF => Self::cp_a_r8(self, opcode),
-            0xC0 => Self::ret_cond(self, opcode),
-            0xC1 => Self::pop_r16stk(self, opcode),
-            0xC2 => Self::jp_cond_imm16(self, opcode),
-            0xC3 => Self::jp_imm16(self, opcode),
-            0xC4 => Self::call_cond_imm16(self, opcode),
-            0xC5 => Self::push_r16stk(self, opcode),
-            0xC6 => Self::add_a_imm8(self, opcode),
-            0xC7 => Self::rst_tgt3(self, opcode),
-            0xC8 => Self::ret_cond(self, opcode),
-            0xC9 => Self::ret(self, opcode),
-            0xCA => Self::jp_cond_imm16(self, opcode),
-            0xCB => Self::prefix(self, opcode),
-            0xCC => Self::call_cond_imm16(self, opcode),
-            0xCD => Self::call_imm16(self, opcode),
-            0xCE => Self::adc_a_imm8(self, opcode),
-            0xCF => Self::rst_tgt3(self, opcode),
-            0xD0 => Self::ret_cond(self, opcode),
-            0xD1 => Self::pop_r16stk(self, opcode),
-            0xD2 => Self::jp_cond_imm16(self, opcode),
-            0xD3 => Self::invalid(self, opcode),
-            0xD4 => Self::call_cond_imm16(self, opcode),
-            0xD5 => Self::push_r16stk(self, opcode),
-            0xD6 => Self::sub_a_imm8(self, opcode),
-            0xD7 => Self::rst_tgt3(self, opcode),
-            0xD8 => Self::ret_cond(self, opcode),
-            0xD9 => Self::reti(self, opcode),
-            0xDA => Self::jp_cond_imm16(self, opcode),
-            0xDB => Self::invalid(self, opcode),
-            0xDC => Self::call_cond_imm16(self, opcode),
-            0xDD => Self::invalid(self, opcode),
-            0xDE => Self::sbc_a_imm8(self, opcode),
-            0xDF => Self::rst_tgt3(self, opcode),
-            0xE0 => Self::ldh_imm8_a(self, opcode),
-            0xE1 => Self::pop_r16stk(self, opcode),
-            0xE2 => Self::ldh_c_a(self, opcode),
-            0xE3 => Self::invalid(self, opcode),
-            0xE4 => Self::invalid(self, opcode),
-            0xE5 => Self::push_r16stk(self, opcode),
-            0xE6 => Self::and_a_imm8(self, opcode),
-            0xE7 => Self::rst_tgt3(self, opcode),
-            0xE8 => Self::add_sp_imm8(self, opcode),
-            0xE9 => Self::jp_hl(self, opcode),
-            0xEA => Self::ld_imm16_a(self, opcode),
-            0xEB => Self::invalid(self, opcode),
-            0xEC => Self::invalid(self, opcode),
-            0xED => Self::invalid(self, opcode),
-            0xEE => Self::xor_a_imm8(self, opcode),
-            0xEF => Self::rst_tgt3(self, opcode),
-            0xF0 => Self::ldh_a_imm8(self, opcode),
-            0xF1 => Self::pop_r16stk(self, opcode),
-            0xF2 => Self::ldh_a_c(self, opcode),
-            0xF3 => Self::di(self, opcode),
-            0xF4 => Self::invalid(self, opcode),
-            0xF5 => Self::push_r16stk(self, opcode),
-            0xF6 => Self::or_a_imm8(self, opcode),
-            0xF7 => Self::rst_tgt3(self, opcode),
-            0xF8 => Self::ld_hl_sp_imm8(self, opcode),
-            0xF9 => Self::ld_sp_hl(self, opcode),
-            0xFA => Self::ld_a_imm16(self, opcode),
-            0xFB => Self::ei(self, opcode),
-            0xFC => Self::invalid(self, opcode),
-            0xFD => Self::invalid(self, opcode),
-            0xFE => Self::cp_a_imm8(self, opcode),
-            0xFF => Self::rst_tgt3(self, opcode),
+        self.access_pending = false;
+        self.cycles_run = 0;
+
+        // Whether the previous instruction was EI
+        let enable_ime = self.ime_pending;
+
+        let (opcode, handler) = self.fetch();
+        if self.halt_bug {
+            self.halt_bug = false;
+        } else {
+            self.pc += 1;
         }
-        .into();
+        let cycles = handler(self, opcode) as usize;
 
-        self.bus.run_cycles(cycles as u16);
+        // The DI in `EI; DI` cancels the pending enable
+        if enable_ime && self.ime_pending {
+            self.ime = true;
+            self.ime_pending = false;
+        }
+
+        self.finish_cycles(cycles);
         cycles
     }
 
+    fn decode(opcode: u8) -> Handler<T> {
+        match opcode {
+            0x00 => Self::no_op,
+            0x01 => Self::ld_r16_imm16,
+            0x02 => Self::ld_r16mem_a,
+            0x03 => Self::inc_r16,
+            0x04 => Self::inc_r8,
+            0x05 => Self::dec_r8,
+            0x06 => Self::ld_r8_imm8,
+            0x07 => Self::rlca,
+            0x08 => Self::ld_imm16_sp,
+            0x09 => Self::add_hl_r16,
+            0x0A => Self::ld_a_r16mem,
+            0x0B => Self::dec_r16,
+            0x0C => Self::inc_r8,
+            0x0D => Self::dec_r8,
+            0x0E => Self::ld_r8_imm8,
+            0x0F => Self::rrca,
+            0x10 => Self::stop,
+            0x11 => Self::ld_r16_imm16,
+            0x12 => Self::ld_r16mem_a,
+            0x13 => Self::inc_r16,
+            0x14 => Self::inc_r8,
+            0x15 => Self::dec_r8,
+            0x16 => Self::ld_r8_imm8,
+            0x17 => Self::rla,
+            0x18 => Self::jr_imm8,
+            0x19 => Self::add_hl_r16,
+            0x1A => Self::ld_a_r16mem,
+            0x1B => Self::dec_r16,
+            0x1C => Self::inc_r8,
+            0x1D => Self::dec_r8,
+            0x1E => Self::ld_r8_imm8,
+            0x1F => Self::rra,
+            0x20 => Self::jr_cond_imm8,
+            0x21 => Self::ld_r16_imm16,
+            0x22 => Self::ld_r16mem_a,
+            0x23 => Self::inc_r16,
+            0x24 => Self::inc_r8,
+            0x25 => Self::dec_r8,
+            0x26 => Self::ld_r8_imm8,
+            0x27 => Self::daa,
+            0x28 => Self::jr_cond_imm8,
+            0x29 => Self::add_hl_r16,
+            0x2A => Self::ld_a_r16mem,
+            0x2B => Self::dec_r16,
+            0x2C => Self::inc_r8,
+            0x2D => Self::dec_r8,
+            0x2E => Self::ld_r8_imm8,
+            0x2F => Self::cpl,
+            0x30 => Self::jr_cond_imm8,
+            0x31 => Self::ld_r16_imm16,
+            0x32 => Self::ld_r16mem_a,
+            0x33 => Self::inc_r16,
+            0x34 => Self::inc_r8,
+            0x35 => Self::dec_r8,
+            0x36 => Self::ld_r8_imm8,
+            0x37 => Self::scf,
+            0x38 => Self::jr_cond_imm8,
+            0x39 => Self::add_hl_r16,
+            0x3A => Self::ld_a_r16mem,
+            0x3B => Self::dec_r16,
+            0x3C => Self::inc_r8,
+            0x3D => Self::dec_r8,
+            0x3E => Self::ld_r8_imm8,
+            0x3F => Self::ccf,
+            0x40..=0x75 | 0x77..=0x7F => Self::ld_r8_r8,
+            0x76 => Self::halt,
+            0x80..=0x87 => Self::add_a_r8,
+            0x88..=0x8F => Self::adc_a_r8,
+            0x90..=0x97 => Self::sub_a_r8,
+            0x98..=0x9F => Self::sbc_a_r8,
+            0xA0..=0xA7 => Self::and_a_r8,
+            0xA8..=0xAF => Self::xor_a_r8,
+            0xB0..=0xB7 => Self::or_a_r8,
+            0xB8..=0xBF => Self::cp_a_r8,
+            0xC0 => Self::ret_cond,
+            0xC1 => Self::pop_r16stk,
+            0xC2 => Self::jp_cond_imm16,
+            0xC3 => Self::jp_imm16,
+            0xC4 => Self::call_cond_imm16,
+            0xC5 => Self::push_r16stk,
+            0xC6 => Self::add_a_imm8,
+            0xC7 => Self::rst_tgt3,
+            0xC8 => Self::ret_cond,
+            0xC9 => Self::ret,
+            0xCA => Self::jp_cond_imm16,
+            0xCB => Self::prefix,
+            0xCC => Self::call_cond_imm16,
+            0xCD => Self::call_imm16,
+            0xCE => Self::adc_a_imm8,
+            0xCF => Self::rst_tgt3,
+            0xD0 => Self::ret_cond,
+            0xD1 => Self::pop_r16stk,
+            0xD2 => Self::jp_cond_imm16,
+            0xD3 => Self::invalid,
+            0xD4 => Self::call_cond_imm16,
+            0xD5 => Self::push_r16stk,
+            0xD6 => Self::sub_a_imm8,
+            0xD7 => Self::rst_tgt3,
+            0xD8 => Self::ret_cond,
+            0xD9 => Self::reti,
+            0xDA => Self::jp_cond_imm16,
+            0xDB => Self::invalid,
+            0xDC => Self::call_cond_imm16,
+            0xDD => Self::invalid,
+            0xDE => Self::sbc_a_imm8,
+            0xDF => Self::rst_tgt3,
+            0xE0 => Self::ldh_imm8_a,
+            0xE1 => Self::pop_r16stk,
+            0xE2 => Self::ldh_c_a,
+            0xE3 => Self::invalid,
+            0xE4 => Self::invalid,
+            0xE5 => Self::push_r16stk,
+            0xE6 => Self::and_a_imm8,
+            0xE7 => Self::rst_tgt3,
+            0xE8 => Self::add_sp_imm8,
+            0xE9 => Self::jp_hl,
+            0xEA => Self::ld_imm16_a,
+            0xEB => Self::invalid,
+            0xEC => Self::invalid,
+            0xED => Self::invalid,
+            0xEE => Self::xor_a_imm8,
+            0xEF => Self::rst_tgt3,
+            0xF0 => Self::ldh_a_imm8,
+            0xF1 => Self::pop_r16stk,
+            0xF2 => Self::ldh_a_c,
+            0xF3 => Self::di,
+            0xF4 => Self::invalid,
+            0xF5 => Self::push_r16stk,
+            0xF6 => Self::or_a_imm8,
+            0xF7 => Self::rst_tgt3,
+            0xF8 => Self::ld_hl_sp_imm8,
+            0xF9 => Self::ld_sp_hl,
+            0xFA => Self::ld_a_imm16,
+            0xFB => Self::ei,
+            0xFC => Self::invalid,
+            0xFD => Self::invalid,
+            0xFE => Self::cp_a_imm8,
+            0xFF => Self::rst_tgt3,
+        }
+    }
+
+    fn fetch(&mut self) -> (u8, Handler<T>) {
+        // The fetch is always the instruction's first access, so there's
+        // nothing to catch up on, but it still takes its cycle
+        self.access_pending = true;
+
+        let opcode = self.bus.read(self.pc);
+        (opcode, Self::decode(opcode))
+    }
+
+    // Jumps to the handler for `int_source`, running the bus for the whole
+    // dispatch, and returns the M-cycles it took. Should only be called
+    // between instructions.
     pub fn handle_interrupt(&mut self, int_source: IntSource) -> usize {
+        self.access_pending = false;
+        self.cycles_run = 0;
         self.ime = false;
+
+        // `EI; HALT` with an interrupt pending: the handler returns to the HALT
+        if self.halt_bug {
+            self.halt_bug = false;
+            self.pc -= 1;
+        }
+
         self.push_stack(self.pc);
 
         self.pc = match int_source {
@@ -1416,6 +1704,525 @@ impl<T: CartridgeData> Cpu<T> {
         };
 
         self.bus.clear_interrupt(int_source);
+        self.finish_cycles(5);
         return 5;
     }
 }
+
+#[cfg(any(test, feature = "std"))]
+impl<T: CartridgeData> Snapshot for Cpu<T> {
+    fn save(&self, w: &mut StateWriter) {
+        for reg in [self.a, self.b, self.c, self.d, self.e, self.h, self.l] {
+            w.u8(reg);
+        }
+        w.u16(self.sp);
+        w.u16(self.pc);
+        for flag in [
+            self.z_f,
+            self.n_f,
+            self.h_f,
+            self.c_f,
+            self.ime,
+            self.ime_pending,
+            self.halt_bug,
+            self.sleep,
+        ] {
+            w.bool(flag);
+        }
+
+        let (kind, opcode, pc) = match self.fault {
+            None => (0, 0, 0),
+            Some(CpuError::InvalidOpcode { opcode, pc }) => (1, opcode, pc),
+            Some(CpuError::UnsupportedStop { pc }) => (2, 0, pc),
+        };
+        w.u8(kind);
+        w.u8(opcode);
+        w.u16(pc);
+
+        self.bus.save(w);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        for reg in [
+            &mut self.a,
+            &mut self.b,
+            &mut self.c,
+            &mut self.d,
+            &mut self.e,
+            &mut self.h,
+            &mut self.l,
+        ] {
+            *reg = r.u8()?;
+        }
+        self.sp = r.u16()?;
+        self.pc = r.u16()?;
+        for flag in [
+            &mut self.z_f,
+            &mut self.n_f,
+            &mut self.h_f,
+            &mut self.c_f,
+            &mut self.ime,
+            &mut self.ime_pending,
+            &mut self.halt_bug,
+            &mut self.sleep,
+        ] {
+            *flag = r.bool()?;
+        }
+
+        let kind = r.u8()?;
+        let opcode = r.u8()?;
+        let pc = r.u16()?;
+        self.fault = match kind {
+            0 => None,
+            2 => Some(CpuError::UnsupportedStop { pc }),
+            _ => Some(CpuError::InvalidOpcode { opcode, pc }),
+        };
+
+        self.bus.load(r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::WatchKind;
+    use crate::gb::SmallInMemoryCartridge;
+
+    // Copies a one instruction subroutine into WRAM and calls it in a loop,
+    // flipping it between `inc d` and `inc e` after every call.
+    #[rustfmt::skip]
+    const SELF_MODIFYING: &[u8] = &[
+        0x21, 0x00, 0xC0, // ld hl, 0xC000
+        0x36, 0x14,       // ld (hl), 0x14 (inc d)
+        0x23,             // inc hl
+        0x36, 0xC9,       // ld (hl), 0xC9 (ret)
+        0x2B,             // dec hl
+        0x06, 0x0A,       // ld b, 10
+        0x16, 0x00,       // ld d, 0
+        0x1E, 0x00,       // ld e, 0
+        // loop:
+        0xCD, 0x00, 0xC0, // call 0xC000
+        0x7E,             // ld a, (hl)
+        0xEE, 0x08,       // xor 0x08
+        0x77,             // ld (hl), a
+        0x05,             // dec b
+        0x20, 0xF6,       // jr nz, loop
+        0x18, 0xFE,       // jr -2
+    ];
+
+    fn cpu_with_program(program: &[u8]) -> Cpu<SmallInMemoryCartridge> {
+        let mut rom = [0; 0x8000];
+        rom[0x100..0x100 + program.len()].copy_from_slice(program);
+        Cpu::new(Bus::new(SmallInMemoryCartridge::from_slice(&rom)))
+    }
+
+    #[test]
+    fn self_modifying_wram_code() {
+        let mut cpu = cpu_with_program(SELF_MODIFYING);
+        for _ in 0..200 {
+            cpu.run_one();
+        }
+
+        assert_eq!((cpu.b, cpu.d, cpu.e), (0, 5, 5));
+    }
+
+    #[test]
+    fn mid_instruction_io_timing() {
+        // ldh a, (TIMA)
+        let mut cpu = cpu_with_program(&[0xF0, 0x05]);
+
+        // TIMA counts every 4 M-cycles. Line up so it next counts 2 M-cycles in.
+        cpu.bus.write(0xFF07, 0x05);
+        let tima = cpu.bus.read(0xFF05);
+        while cpu.bus.read(0xFF05) == tima {
+            cpu.bus.run_cycles(1);
+        }
+        cpu.bus.run_cycles(2);
+        let tima = cpu.bus.read(0xFF05);
+
+        // The read is the third M-cycle of the instruction, so it sees the count
+        assert_eq!(cpu.run_one(), 3);
+        assert_eq!(cpu.a, tima + 1);
+    }
+
+    #[test]
+    fn set_registers() {
+        // add a, b
+        let mut cpu = cpu_with_program(&[0x80]);
+        let regs = CpuRegisters {
+            a: 0x3A,
+            b: 0xC6,
+            pc: 0x100,
+            sp: 0xFFFE,
+            ..Default::default()
+        };
+        cpu.set_registers(regs);
+        assert_eq!(cpu.registers(), regs);
+
+        cpu.run_one();
+        assert_eq!(
+            cpu.registers(),
+            CpuRegisters {
+                a: 0x00,
+                pc: 0x101,
+                z_f: true,
+                h_f: true,
+                c_f: true,
+                ..regs
+            }
+        );
+    }
+
+    #[test]
+    fn add_sp_signed_flags() {
+        for (sp, e8, result, h, c) in [
+            (0x0000, -1, 0xFFFF, false, false),
+            (0x00FF, -1, 0x00FE, true, true),
+            (0x0001, 127, 0x0080, true, false),
+            (0x0081, 127, 0x0100, true, true),
+        ] {
+            assert_eq!(add_sp_signed(sp, e8), (result, h, c), "{sp:#06X} + {e8}");
+
+            // add sp, e8 and ld hl, sp+e8 agree
+            for opcode in [0xE8, 0xF8] {
+                let mut cpu = cpu_with_program(&[opcode, e8 as u8]);
+                let regs = CpuRegisters {
+                    pc: 0x100,
+                    sp,
+                    z_f: true,
+                    n_f: true,
+                    ..Default::default()
+                };
+                cpu.set_registers(regs);
+                cpu.run_one();
+
+                let after = cpu.registers();
+                let out = if opcode == 0xE8 {
+                    after.sp
+                } else {
+                    u16::from_be_bytes([after.h, after.l])
+                };
+                assert_eq!(out, result);
+                assert_eq!(
+                    (after.z_f, after.n_f, after.h_f, after.c_f),
+                    (false, false, h, c)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn rotate_a_zero_flag() {
+        // rlca, rrca, rla, rra always clear Z, their CB prefixed forms on A
+        // set it from the result
+        for opcode in [0x07, 0x0F, 0x17, 0x1F] {
+            for program in [&[opcode][..], &[0xCB, opcode]] {
+                let mut cpu = cpu_with_program(program);
+                cpu.set_registers(CpuRegisters {
+                    pc: 0x100,
+                    z_f: true,
+                    ..Default::default()
+                });
+                cpu.run_one();
+
+                let regs = cpu.registers();
+                assert_eq!(regs.a, 0);
+                assert_eq!(regs.z_f, program.len() == 2, "{program:02X?}");
+                assert!(!regs.c_f);
+            }
+        }
+    }
+
+    #[test]
+    fn stop_switches_speed() {
+        let mut cpu = cpu_with_program(&[0x10, 0x00, 0x00]);
+        cpu.bus.set_cgb_mode(true);
+        cpu.bus.write(0xFF4D, 0x01);
+
+        assert_eq!(cpu.run_one(), 2);
+        assert_eq!(cpu.registers().pc, 0x102);
+        assert!(cpu.bus.double_speed());
+        assert_eq!(cpu.bus.read(0xFF4D), 0xFE);
+    }
+
+    #[test]
+    fn stop_faults() {
+        // stop; inc a
+        let mut cpu = cpu_with_program(&[0x10, 0x00, 0x3C]);
+        let a = cpu.registers().a;
+
+        cpu.run_one();
+        assert_eq!(cpu.fault(), Some(CpuError::UnsupportedStop { pc: 0x100 }));
+        cpu.run_one();
+        assert_eq!(cpu.registers().a, a);
+
+        cpu.clear_fault();
+        cpu.run_one();
+        assert_eq!(cpu.fault(), None);
+        assert_eq!(cpu.registers().a, a.wrapping_add(1));
+    }
+
+    #[test]
+    fn run_until_break() {
+        let mut cpu = cpu_with_program(SELF_MODIFYING);
+
+        // The `call 0xC000` in the loop
+        assert!(cpu.add_breakpoint(0x10F));
+        assert_eq!(cpu.run_until_break(1000), BreakReason::Breakpoint(0x10F));
+        assert_eq!(cpu.run_until_break(1000), BreakReason::Breakpoint(0x10F));
+        cpu.remove_breakpoint(0x10F);
+
+        // The second pass through the loop flips `inc e` back to `inc d`
+        assert!(cpu.bus.add_watchpoint(0xC000, WatchKind::Write));
+        assert_eq!(
+            cpu.run_until_break(1000),
+            BreakReason::Watchpoint(WatchHit {
+                addr: 0xC000,
+                kind: WatchKind::Write,
+                val: 0x14,
+            })
+        );
+        cpu.bus.remove_watchpoint(0xC000, WatchKind::Write);
+
+        assert_eq!(cpu.run_until_break(1000), BreakReason::CycleLimit);
+
+        let mut cpu = cpu_with_program(&[0x00, 0xFD]);
+        assert_eq!(
+            cpu.run_until_break(1000),
+            BreakReason::Fault(CpuError::InvalidOpcode {
+                opcode: 0xFD,
+                pc: 0x101
+            })
+        );
+    }
+
+    #[test]
+    fn get_next_instrs() {
+        let cpu = cpu_with_program(SELF_MODIFYING);
+        let instrs = cpu.get_next_instrs::<3>();
+        assert_eq!(cpu.pc(), 0x100);
+
+        let addrs = instrs.each_ref().map(|i| i.addr);
+        let mnemonics = instrs.each_ref().map(|i| i.mnemonic.as_str());
+        assert_eq!(addrs, [0x100, 0x103, 0x105]);
+        assert_eq!(mnemonics, ["LD HL,$C000", "LD (HL),$14", "INC HL"]);
+        assert_eq!(instrs[0].bytes, [0x21, 0x00, 0xC0]);
+    }
+
+    #[test]
+    fn trace_line() {
+        let mut cpu = cpu_with_program(&[0x00, 0xC3, 0x50, 0x01]);
+        cpu.set_registers(CpuRegisters {
+            a: 0x01,
+            b: 0x00,
+            c: 0x13,
+            d: 0x00,
+            e: 0xD8,
+            h: 0x01,
+            l: 0x4D,
+            sp: 0xFFFE,
+            pc: 0x100,
+            z_f: true,
+            n_f: false,
+            h_f: true,
+            c_f: true,
+        });
+
+        assert_eq!(
+            cpu.trace_line().as_str(),
+            "A:01 F:Z-HC BC:0013 DE:00D8 HL:014D SP:FFFE PC:0100 (00 C3 50 01)"
+        );
+    }
+
+    #[test]
+    fn handle_interrupt() {
+        let mut cpu = cpu_with_program(&[0x00]);
+        cpu.bus.int_controller.interrupt(IntSource::TIMER);
+        let sp = cpu.sp;
+        let before = cpu.bus.timer.system_counter();
+
+        assert_eq!(cpu.handle_interrupt(IntSource::TIMER), 5);
+        assert_eq!(cpu.pc, 0x50);
+        assert_eq!(cpu.sp, sp - 2);
+        assert!(!cpu.ime);
+        assert_eq!(cpu.bus.timer.system_counter().wrapping_sub(before), 5);
+    }
+
+    #[test]
+    fn halt_bug() {
+        #[rustfmt::skip]
+        let mut cpu = cpu_with_program(&[
+            0x76, // halt
+            0x04, // inc b
+            0x18, 0xFE, // jr -2
+        ]);
+        cpu.b = 0;
+
+        // IME is off and an interrupt is already pending
+        cpu.bus.write(0xFFFF, IntSource::TIMER as u8);
+        cpu.bus.int_controller.interrupt(IntSource::TIMER);
+
+        for _ in 0..4 {
+            cpu.run_one();
+        }
+
+        // `inc b` runs twice
+        assert!(!cpu.sleep);
+        assert_eq!(cpu.b, 2);
+        assert_eq!(cpu.pc, 0x102);
+    }
+
+    #[test]
+    fn halt_without_pending_interrupt() {
+        #[rustfmt::skip]
+        let mut cpu = cpu_with_program(&[
+            0x76, // halt
+            0x04, // inc b
+            0x18, 0xFE, // jr -2
+        ]);
+        cpu.b = 0;
+
+        // Requested, but not enabled, so it doesn't count as pending
+        cpu.bus.int_controller.interrupt(IntSource::TIMER);
+        cpu.run_one();
+        cpu.run_one();
+        assert!(cpu.sleep);
+
+        // Enabling it wakes the CPU, with no HALT bug
+        cpu.bus.write(0xFFFF, IntSource::TIMER as u8);
+        for _ in 0..4 {
+            cpu.run_one();
+        }
+        assert!(!cpu.sleep);
+        assert_eq!(cpu.b, 1);
+    }
+
+    #[test]
+    fn ei_di() {
+        #[rustfmt::skip]
+        let mut cpu = cpu_with_program(&[
+            0xFB, // ei
+            0xF3, // di
+            0x00, // nop
+        ]);
+
+        cpu.bus.write(0xFFFF, IntSource::TIMER as u8);
+        cpu.bus.int_controller.interrupt(IntSource::TIMER);
+
+        for _ in 0..3 {
+            cpu.run_one();
+        }
+        assert!(!cpu.ime);
+        assert_eq!(cpu.pc, 0x103);
+    }
+
+    #[test]
+    fn ei_delay() {
+        #[rustfmt::skip]
+        let mut cpu = cpu_with_program(&[
+            0xFB, // ei
+            0x00, // nop
+            0x00, // nop
+        ]);
+
+        cpu.bus.write(0xFFFF, IntSource::TIMER as u8);
+        cpu.bus.int_controller.interrupt(IntSource::TIMER);
+
+        cpu.run_one();
+        assert!(!cpu.ime);
+
+        // The NOP after EI still runs before the interrupt
+        cpu.run_one();
+        assert_eq!(cpu.pc, 0x102);
+        assert!(cpu.ime);
+
+        cpu.run_one();
+        assert_eq!(cpu.pc, 0x50);
+        assert_eq!(cpu.pop_stack(), 0x102);
+    }
+
+    #[test]
+    fn ei_ei() {
+        #[rustfmt::skip]
+        let mut cpu = cpu_with_program(&[
+            0xFB, // ei
+            0xFB, // ei
+            0x00, // nop
+        ]);
+
+        cpu.bus.write(0xFFFF, IntSource::TIMER as u8);
+        cpu.bus.int_controller.interrupt(IntSource::TIMER);
+
+        // The second EI is the instruction after the first, it doesn't
+        // push the enable back any further
+        cpu.run_one();
+        cpu.run_one();
+        assert!(cpu.ime);
+
+        cpu.run_one();
+        assert_eq!(cpu.pc, 0x50);
+        assert_eq!(cpu.pop_stack(), 0x102);
+    }
+
+    #[test]
+    fn ei_halt() {
+        #[rustfmt::skip]
+        let mut cpu = cpu_with_program(&[
+            0xFB, // ei
+            0x76, // halt
+            0x00, // nop
+        ]);
+
+        cpu.bus.write(0xFFFF, IntSource::TIMER as u8);
+        cpu.bus.int_controller.interrupt(IntSource::TIMER);
+
+        // HALT sees IME still off, IME comes on once it completes
+        cpu.run_one();
+        cpu.run_one();
+        assert!(cpu.ime);
+
+        // The interrupt returns to the HALT itself
+        cpu.run_one();
+        assert_eq!(cpu.pc, 0x50);
+        assert_eq!(cpu.pop_stack(), 0x101);
+    }
+
+    #[test]
+    fn adc_sbc_half_carry() {
+        #[rustfmt::skip]
+        let mut cpu = cpu_with_program(&[
+            0xCE, 0x00, // adc a, 0x00
+            0x88, // adc a, b
+            0xDE, 0x0F, // sbc a, 0x0F
+            0x98, // sbc a, b
+        ]);
+
+        // The carry in alone overflows the low nibble
+        cpu.a = 0x0F;
+        cpu.c_f = true;
+        cpu.run_one();
+        assert_eq!(cpu.a, 0x10);
+        assert!(cpu.h_f && !cpu.c_f && !cpu.n_f);
+
+        cpu.a = 0x0F;
+        cpu.b = 0x00;
+        cpu.c_f = true;
+        cpu.run_one();
+        assert_eq!(cpu.a, 0x10);
+        assert!(cpu.h_f && !cpu.c_f);
+
+        // The carry in borrows on top of val
+        cpu.a = 0x00;
+        cpu.c_f = true;
+        cpu.run_one();
+        assert_eq!(cpu.a, 0xF0);
+        assert!(cpu.h_f && cpu.c_f && cpu.n_f);
+
+        cpu.a = 0x10;
+        cpu.b = 0x0F;
+        cpu.c_f = true;
+        cpu.run_one();
+        assert_eq!(cpu.a, 0x00);
+        assert!(cpu.h_f && !cpu.c_f && cpu.z_f);
+    }
+}
@@ -0,0 +1,107 @@
+use heapless::Deque;
+
+use crate::bus::Device;
+
+// A debug-only MMIO device for homebrew development.
+//
+// It maps a small RAM scratchpad over the otherwise unusable
+// 0xFEA0..=0xFEFF region, and a "print character" port at an
+// IO address that is unused on both DMG and CGB. Test programs
+// can write bytes to the print port to emit diagnostics without
+// having to drive the serial port.
+pub const SCRATCH_START: u16 = 0xFEA0;
+pub const SCRATCH_END: u16 = 0xFEFF;
+pub const PRINT_PORT: u16 = 0xFF7F;
+
+const SCRATCH_LEN: usize = (SCRATCH_END - SCRATCH_START + 1) as usize;
+const OUTPUT_LEN: usize = 256;
+
+pub struct DebugPort {
+    scratch: [u8; SCRATCH_LEN],
+    output: Deque<u8, OUTPUT_LEN>,
+}
+
+impl DebugPort {
+    pub fn new() -> Self {
+        Self {
+            scratch: [0; SCRATCH_LEN],
+            output: Deque::new(),
+        }
+    }
+
+    pub fn maps(addr: u16) -> bool {
+        matches!(addr, SCRATCH_START..=SCRATCH_END | PRINT_PORT)
+    }
+
+    // Drains everything written to the print port so far.
+    // Only the most recent `OUTPUT_LEN` bytes are retained.
+    pub fn take_output(&mut self) -> heapless::Vec<u8, OUTPUT_LEN> {
+        let mut out = heapless::Vec::new();
+        while let Some(b) = self.output.pop_front() {
+            let _ = out.push(b);
+        }
+        out
+    }
+}
+
+impl Default for DebugPort {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Device for DebugPort {
+    fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            SCRATCH_START..=SCRATCH_END => {
+                self.scratch[(addr - SCRATCH_START) as usize] = val;
+            }
+            PRINT_PORT => {
+                if self.output.is_full() {
+                    let _ = self.output.pop_front();
+                }
+                let _ = self.output.push_back(val);
+            }
+            _ => {
+                unreachable!("Invalid write to debug port! addr: {:?}", addr);
+            }
+        }
+    }
+
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            SCRATCH_START..=SCRATCH_END => self.scratch[(addr - SCRATCH_START) as usize],
+            PRINT_PORT => 0xFF,
+            _ => {
+                unreachable!("Invalid read from debug port! addr: {:?}", addr);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn print_port() {
+        let mut port = DebugPort::new();
+        for b in b"Hello" {
+            port.write(PRINT_PORT, *b);
+        }
+
+        assert_eq!(port.take_output().as_slice(), b"Hello");
+        assert!(port.take_output().is_empty());
+    }
+
+    #[test]
+    fn scratchpad() {
+        let mut port = DebugPort::new();
+        port.write(SCRATCH_START, 0x12);
+        port.write(SCRATCH_END, 0x34);
+        assert_eq!(port.read(SCRATCH_START), 0x12);
+        assert_eq!(port.read(SCRATCH_END), 0x34);
+        assert!(DebugPort::maps(PRINT_PORT));
+        assert!(!DebugPort::maps(0xFF7E));
+    }
+}
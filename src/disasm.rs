@@ -0,0 +1,164 @@
+use core::fmt::Write;
+use heapless::String;
+
+// Long enough for the longest mnemonic, e.g. "CALL NZ,$1234"
+pub const MNEMONIC_LEN: usize = 16;
+
+const R8: [&str; 8] = ["B", "C", "D", "E", "H", "L", "(HL)", "A"];
+const R16: [&str; 4] = ["BC", "DE", "HL", "SP"];
+const R16_STK: [&str; 4] = ["BC", "DE", "HL", "AF"];
+const R16_MEM: [&str; 4] = ["(BC)", "(DE)", "(HL+)", "(HL-)"];
+const COND: [&str; 4] = ["NZ", "Z", "NC", "C"];
+const ALU: [&str; 8] = [
+    "ADD A,", "ADC A,", "SUB ", "SBC A,", "AND ", "XOR ", "OR ", "CP ",
+];
+const ROT: [&str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SWAP", "SRL"];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DecodedInstr {
+    pub addr: u16,
+    // Only the first `len` bytes are part of the instruction
+    pub bytes: [u8; 3],
+    pub len: u8,
+    pub mnemonic: String<MNEMONIC_LEN>,
+}
+
+impl DecodedInstr {
+    // Decodes the instruction at the start of `bytes`, which was read from `addr`
+    pub fn decode(addr: u16, bytes: [u8; 3]) -> Self {
+        let mut mnemonic = String::new();
+        let len = write_mnemonic(&mut mnemonic, bytes).unwrap_or(1);
+
+        let mut bytes = bytes;
+        bytes[len as usize..].fill(0);
+
+        Self {
+            addr,
+            bytes,
+            len,
+            mnemonic,
+        }
+    }
+}
+
+// Writes the mnemonic for the instruction in `bytes` and returns its length
+fn write_mnemonic<W: Write>(out: &mut W, bytes: [u8; 3]) -> Result<u8, core::fmt::Error> {
+    let op = bytes[0];
+    let n8 = bytes[1];
+    let e8 = bytes[1] as i8;
+    let n16 = u16::from_le_bytes([bytes[1], bytes[2]]);
+
+    let r8_dst = R8[(op as usize >> 3) & 0x7];
+    let r8_src = R8[op as usize & 0x7];
+    let r16 = (op as usize >> 4) & 0x3;
+    let cond = COND[(op as usize >> 3) & 0x3];
+
+    let len = match op {
+        0x00 => write!(out, "NOP").map(|_| 1),
+        0x10 => write!(out, "STOP").map(|_| 2),
+        0x08 => write!(out, "LD (${n16:04X}),SP").map(|_| 3),
+        0x18 => write!(out, "JR {e8}").map(|_| 2),
+        0x20 | 0x28 | 0x30 | 0x38 => write!(out, "JR {cond},{e8}").map(|_| 2),
+        0x07 => write!(out, "RLCA").map(|_| 1),
+        0x0F => write!(out, "RRCA").map(|_| 1),
+        0x17 => write!(out, "RLA").map(|_| 1),
+        0x1F => write!(out, "RRA").map(|_| 1),
+        0x27 => write!(out, "DAA").map(|_| 1),
+        0x2F => write!(out, "CPL").map(|_| 1),
+        0x37 => write!(out, "SCF").map(|_| 1),
+        0x3F => write!(out, "CCF").map(|_| 1),
+        _ if op < 0x40 => match op & 0xF {
+            0x1 => write!(out, "LD {},${n16:04X}", R16[r16]).map(|_| 3),
+            0x2 => write!(out, "LD {},A", R16_MEM[r16]).map(|_| 1),
+            0x3 => write!(out, "INC {}", R16[r16]).map(|_| 1),
+            0x9 => write!(out, "ADD HL,{}", R16[r16]).map(|_| 1),
+            0xA => write!(out, "LD A,{}", R16_MEM[r16]).map(|_| 1),
+            0xB => write!(out, "DEC {}", R16[r16]).map(|_| 1),
+            0x4 | 0xC => write!(out, "INC {r8_dst}").map(|_| 1),
+            0x5 | 0xD => write!(out, "DEC {r8_dst}").map(|_| 1),
+            _ => write!(out, "LD {r8_dst},${n8:02X}").map(|_| 2),
+        },
+        0x76 => write!(out, "HALT").map(|_| 1),
+        _ if op < 0x80 => write!(out, "LD {r8_dst},{r8_src}").map(|_| 1),
+        _ if op < 0xC0 => write!(out, "{}{r8_src}", ALU[(op as usize >> 3) & 0x7]).map(|_| 1),
+        0xC9 => write!(out, "RET").map(|_| 1),
+        0xD9 => write!(out, "RETI").map(|_| 1),
+        0xC3 => write!(out, "JP ${n16:04X}").map(|_| 3),
+        0xE9 => write!(out, "JP HL").map(|_| 1),
+        0xCD => write!(out, "CALL ${n16:04X}").map(|_| 3),
+        0xCB => {
+            let r8 = R8[n8 as usize & 0x7];
+            let bit = (n8 >> 3) & 0x7;
+            match n8 >> 6 {
+                0 => write!(out, "{} {r8}", ROT[bit as usize]),
+                1 => write!(out, "BIT {bit},{r8}"),
+                2 => write!(out, "RES {bit},{r8}"),
+                _ => write!(out, "SET {bit},{r8}"),
+            }
+            .map(|_| 2)
+        }
+        0xE0 => write!(out, "LDH ($FF{n8:02X}),A").map(|_| 2),
+        0xF0 => write!(out, "LDH A,($FF{n8:02X})").map(|_| 2),
+        0xE2 => write!(out, "LD (C),A").map(|_| 1),
+        0xF2 => write!(out, "LD A,(C)").map(|_| 1),
+        0xEA => write!(out, "LD (${n16:04X}),A").map(|_| 3),
+        0xFA => write!(out, "LD A,(${n16:04X})").map(|_| 3),
+        0xE8 => write!(out, "ADD SP,{e8}").map(|_| 2),
+        0xF8 => write!(out, "LD HL,SP{e8:+}").map(|_| 2),
+        0xF9 => write!(out, "LD SP,HL").map(|_| 1),
+        0xF3 => write!(out, "DI").map(|_| 1),
+        0xFB => write!(out, "EI").map(|_| 1),
+        0xD3 | 0xDB | 0xDD | 0xE3 | 0xE4 | 0xEB | 0xEC | 0xED | 0xF4 | 0xFC | 0xFD => {
+            write!(out, "DB ${op:02X}").map(|_| 1)
+        }
+        _ => match op & 0x7 {
+            0x0 => write!(out, "RET {cond}").map(|_| 1),
+            0x1 => write!(out, "POP {}", R16_STK[r16]).map(|_| 1),
+            0x2 => write!(out, "JP {cond},${n16:04X}").map(|_| 3),
+            0x4 => write!(out, "CALL {cond},${n16:04X}").map(|_| 3),
+            0x5 => write!(out, "PUSH {}", R16_STK[r16]).map(|_| 1),
+            0x6 => write!(out, "{}${n8:02X}", ALU[(op as usize >> 3) & 0x7]).map(|_| 2),
+            _ => write!(out, "RST ${:02X}", op & 0x38).map(|_| 1),
+        },
+    }?;
+
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mnemonic(bytes: [u8; 3]) -> (std::string::String, u8) {
+        let instr = DecodedInstr::decode(0x100, bytes);
+        (instr.mnemonic.as_str().into(), instr.len)
+    }
+
+    #[test]
+    fn decode() {
+        assert_eq!(mnemonic([0x00, 0, 0]), ("NOP".into(), 1));
+        assert_eq!(mnemonic([0xC3, 0x50, 0x01]), ("JP $0150".into(), 3));
+        assert_eq!(mnemonic([0x31, 0xFE, 0xFF]), ("LD SP,$FFFE".into(), 3));
+        assert_eq!(mnemonic([0x3E, 0x12, 0]), ("LD A,$12".into(), 2));
+        assert_eq!(mnemonic([0x22, 0, 0]), ("LD (HL+),A".into(), 1));
+        assert_eq!(mnemonic([0x77, 0, 0]), ("LD (HL),A".into(), 1));
+        assert_eq!(mnemonic([0xAF, 0, 0]), ("XOR A".into(), 1));
+        assert_eq!(mnemonic([0x88, 0, 0]), ("ADC A,B".into(), 1));
+        assert_eq!(mnemonic([0xFE, 0x90, 0]), ("CP $90".into(), 2));
+        assert_eq!(mnemonic([0x20, 0xFB, 0]), ("JR NZ,-5".into(), 2));
+        assert_eq!(mnemonic([0xC4, 0x34, 0x12]), ("CALL NZ,$1234".into(), 3));
+        assert_eq!(mnemonic([0xF5, 0, 0]), ("PUSH AF".into(), 1));
+        assert_eq!(mnemonic([0xFF, 0, 0]), ("RST $38".into(), 1));
+        assert_eq!(mnemonic([0xE0, 0x40, 0]), ("LDH ($FF40),A".into(), 2));
+        assert_eq!(mnemonic([0xF8, 0x02, 0]), ("LD HL,SP+2".into(), 2));
+        assert_eq!(mnemonic([0xCB, 0x7C, 0]), ("BIT 7,H".into(), 2));
+        assert_eq!(mnemonic([0xCB, 0x37, 0]), ("SWAP A".into(), 2));
+        assert_eq!(mnemonic([0xD3, 0, 0]), ("DB $D3".into(), 1));
+    }
+
+    #[test]
+    fn unused_bytes_cleared() {
+        let instr = DecodedInstr::decode(0x100, [0x3E, 0x12, 0x34]);
+        assert_eq!(instr.bytes, [0x3E, 0x12, 0]);
+    }
+}
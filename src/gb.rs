@@ -1,39 +1,304 @@
-use crate::bus::Bus;
+use crate::bus::{Bus, WatchHit, WatchKind, BOOT_ROM_LEN, WATCH_HIT_QUEUE};
 use crate::cart::{get_cart_header, CartridgeData};
 use crate::cpu::Cpu;
-use crate::ppu::SCREEN_HEIGHT;
+use crate::ppu::{CYCLES_PER_SCANLINE, SCANLINES_PER_FRAME};
 use heapless::Vec;
 
-const CYCLES_PER_FRAME: i32 = 17556;
+#[cfg(any(test, feature = "std"))]
+use crate::state::{Snapshot, StateError, StateReader, StateWriter, STATE_MAGIC, STATE_VERSION};
+
+// Frame length in M-cycles, the unit run_one() returns
+pub const CYCLES_PER_FRAME: i32 = CYCLES_PER_SCANLINE * SCANLINES_PER_FRAME;
+// Frame length in T-cycles (dots)
+pub const T_CYCLES_PER_FRAME: i32 = CYCLES_PER_FRAME * 4;
+// The M-cycle clock rate
+pub const CYCLES_PER_SECOND: i32 = 1 << 20;
+
+// How a run_until() call ended
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RunResult {
+    // M-cycles run
+    pub cycles: u64,
+    // The budget ran out before the predicate was true
+    pub timed_out: bool,
+}
+
+// Why with_boot_rom() couldn't use a boot ROM
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BootRomError {
+    // It isn't BOOT_ROM_LEN bytes long
+    BadLength,
+}
 
 pub struct GbRs<T: CartridgeData> {
     pub cpu: Cpu<T>,
+    // M-cycles the last run_line()/run_frame() ran past its budget,
+    // taken out of the next one so the average length stays exact
+    cycle_debt: i32,
 }
 
 impl<T: CartridgeData> GbRs<T> {
     pub fn new(cart: T) -> Self {
         Self {
             cpu: Cpu::new(Bus::new(cart)),
+            cycle_debt: 0,
+        }
+    }
+
+    // Skips straight to running the code at `pc`, e.g. to test a single
+    // subroutine without a full cartridge
+    pub fn new_running_at(cart: T, pc: u16, sp: u16) -> Self {
+        Self {
+            cpu: Cpu::new_running_at(Bus::new(cart), pc, sp),
+            cycle_debt: 0,
         }
     }
 
+    // Runs `boot` first, which hands over to the game at 0x100 like the real
+    // hardware does. `boot` has to be a 256 byte DMG boot ROM.
+    pub fn with_boot_rom(cart: T, boot: &[u8]) -> Result<Self, BootRomError> {
+        let boot: &[u8; BOOT_ROM_LEN] = boot.try_into().map_err(|_| BootRomError::BadLength)?;
+
+        let mut bus = Bus::new(cart);
+        bus.map_boot_rom(boot);
+        Ok(Self {
+            cpu: Cpu::new_at_boot_rom(bus),
+            cycle_debt: 0,
+        })
+    }
+
     pub fn run_one(&mut self) -> usize {
         self.cpu.run_one()
     }
 
+    // The length of one scanline in M-cycles
+    pub fn cycles_per_scanline() -> i32 {
+        CYCLES_PER_SCANLINE
+    }
+
     pub fn run_line(&mut self) {
-        // Cycles per line
-        let mut cyc_remaining: i32 = CYCLES_PER_FRAME / SCREEN_HEIGHT as i32;
-        while cyc_remaining > 0 {
+        self.run_cycles(Self::cycles_per_scanline() * self.speed_factor());
+    }
+
+    // CPU M-cycles per M-cycle of real time, 2 in CGB double speed mode.
+    // Budgets of real time have to be scaled by this before running them.
+    fn speed_factor(&self) -> i32 {
+        1 + self.cpu.bus.double_speed() as i32
+    }
+
+    // Runs `cycles` M-cycles, less whatever the previous call overshot by.
+    // Stops early if the CPU faults.
+    fn run_cycles(&mut self, cycles: i32) {
+        let mut cyc_remaining = cycles - self.cycle_debt;
+        while cyc_remaining > 0 && self.cpu.fault().is_none() {
             cyc_remaining -= self.run_one() as i32;
         }
+        self.cycle_debt = -cyc_remaining.min(0);
+    }
+
+    // Saves everything except the cartridge ROM
+    #[cfg(any(test, feature = "std"))]
+    pub fn save_state(&self) -> std::vec::Vec<u8> {
+        let mut w = StateWriter::new();
+        w.bytes(STATE_MAGIC);
+        w.u8(STATE_VERSION);
+
+        let title = self.cpu.bus.cart.get_header().title;
+        w.u8(title.len() as u8);
+        w.bytes(title.as_bytes());
+
+        self.cpu.save(&mut w);
+        w.finish()
+    }
+
+    // Restores a state from save_state(). The state has to come from the same game.
+    #[cfg(any(test, feature = "std"))]
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        // Every component is a fixed size for a given game, so checking the
+        // length up front means a bad state can't be half loaded.
+        if state.len() != self.save_state().len() {
+            return Err(StateError::BadLength);
+        }
+
+        let mut r = StateReader::new(state);
+
+        let mut magic = [0; 4];
+        r.bytes(&mut magic)?;
+        if &magic != STATE_MAGIC || r.u8()? != STATE_VERSION {
+            return Err(StateError::BadHeader);
+        }
+
+        let title = self.cpu.bus.cart.get_header().title;
+        let mut saved_title = [0; 256];
+        let saved_title = &mut saved_title[..r.u8()? as usize];
+        r.bytes(saved_title)?;
+        if saved_title != title.as_bytes() {
+            return Err(StateError::TitleMismatch);
+        }
+
+        self.cpu.load(&mut r)
+    }
+
+    // Runs until the game writes to the IO register at `addr`, returning the
+    // value written, or None if that doesn't happen within `max_cycles`
+    // M-cycles or every watchpoint is already in use. Watchpoints set by the
+    // caller are left as they were, along with any hits on them.
+    pub fn run_until_io_write(&mut self, addr: u16, max_cycles: usize) -> Option<u8> {
+        let added = !self.cpu.bus.has_watchpoint(addr, WatchKind::Write);
+        if added && !self.cpu.bus.add_watchpoint(addr, WatchKind::Write) {
+            return None;
+        }
+
+        // Hits are taken after every instruction so none are dropped while
+        // the queue is full, and the ones that aren't the write are put back
+        let mut kept: Vec<WatchHit, WATCH_HIT_QUEUE> = Vec::new();
+        while let Some(hit) = self.cpu.bus.take_watch_hit() {
+            let _ = kept.push(hit);
+        }
+
+        let mut cycles = 0;
+        let mut written = None;
+        while written.is_none() && cycles < max_cycles && self.cpu.fault().is_none() {
+            cycles += self.run_one();
+            while let Some(hit) = self.cpu.bus.take_watch_hit() {
+                let wanted = hit.addr == addr && hit.kind == WatchKind::Write;
+                if wanted && written.is_none() {
+                    written = Some(hit.val);
+                }
+                // The caller's own watchpoint on `addr` still sees the write
+                if !wanted || !added {
+                    let _ = kept.push(hit);
+                }
+            }
+        }
+
+        if added {
+            self.cpu.bus.remove_watchpoint(addr, WatchKind::Write);
+        }
+        self.cpu.bus.untake_watch_hits(&kept);
+        written
+    }
+
+    // Runs instructions until `pred` returns true, checking it before each
+    // one, or until `max_cycles` M-cycles have run. A CPU fault doesn't stop
+    // it, `pred` can check for one if it needs to.
+    pub fn run_until<F: FnMut(&Self) -> bool>(
+        &mut self,
+        max_cycles: u64,
+        mut pred: F,
+    ) -> RunResult {
+        let mut cycles = 0;
+        while !pred(self) {
+            if cycles >= max_cycles {
+                return RunResult {
+                    cycles,
+                    timed_out: true,
+                };
+            }
+            cycles += self.run_one() as u64;
+        }
+
+        RunResult {
+            cycles,
+            timed_out: false,
+        }
+    }
+
+    // Runs until the PPU finishes drawing a frame, so the screen holds a
+    // complete picture. Returns the number of M-cycles that took.
+    // Stops early if the CPU faults.
+    pub fn run_to_vblank(&mut self) -> usize {
+        // Only wait for a frame finished from here on
+        self.cpu.bus.ppu.take_frame_ready();
+
+        let budget = CYCLES_PER_FRAME * self.speed_factor() - self.cycle_debt;
+        let mut cycles = 0;
+        while self.cpu.fault().is_none() {
+            cycles += self.run_one();
+            if self.cpu.bus.ppu.take_frame_ready() {
+                // The PPU keeps its own time, so there's nothing to carry over
+                self.cycle_debt = 0;
+                break;
+            }
+
+            // With the LCD off there is no VBLANK, stop after a frame's worth
+            if !self.cpu.bus.ppu.lcd_enabled() && cycles as i32 >= budget {
+                self.cycle_debt = cycles as i32 - budget;
+                break;
+            }
+        }
+        cycles
     }
 
     pub fn run_frame(&mut self) {
-        let mut cyc_remaining: i32 = CYCLES_PER_FRAME;
-        while cyc_remaining > 0 {
-            cyc_remaining -= self.run_one() as i32;
+        self.run_to_vblank();
+    }
+
+    // Runs as much as the hardware would in `wall_elapsed`, scaled by `speed`
+    // (e.g. 2.0 to fast forward), and returns true if a frame was finished.
+    // Frontends should call this with the time since the last call.
+    pub fn run_for(&mut self, wall_elapsed: core::time::Duration, speed: f32) -> bool {
+        let cycles = wall_elapsed.as_secs_f64()
+            * speed as f64
+            * CYCLES_PER_SECOND as f64
+            * self.speed_factor() as f64;
+
+        self.cpu.bus.ppu.take_frame_ready();
+        self.run_cycles(cycles as i32);
+        self.cpu.bus.ppu.take_frame_ready()
+    }
+
+    // Moves the interleaved left/right samples the APU produced since the
+    // last call onto the end of `out` and returns how many were added.
+    // A frontend pacing itself to the audio clock should call this after
+    // every run_frame() and block on its audio queue.
+    #[cfg(any(test, feature = "std"))]
+    pub fn drain_audio(&mut self, out: &mut std::vec::Vec<i16>) -> usize {
+        let samples = self.cpu.bus.apu.get_samples();
+        out.extend_from_slice(&samples);
+        samples.len()
+    }
+
+    // Runs frames until an audio sink that has `sink_remaining` samples
+    // queued would have at least `target`, moving the new samples onto the
+    // end of `out` like drain_audio(). Returns the number of frames run.
+    // Frontends pacing themselves to the audio clock can call this instead
+    // of timing frames, so the sink never runs dry.
+    #[cfg(any(test, feature = "std"))]
+    pub fn run_to_fill_audio(
+        &mut self,
+        sink_remaining: usize,
+        target: usize,
+        out: &mut std::vec::Vec<i16>,
+    ) -> usize {
+        let mut queued = sink_remaining;
+        let mut frames = 0;
+        while queued < target {
+            self.run_frame();
+            frames += 1;
+
+            // With a sample rate of 0 the target is never reached
+            let added = self.drain_audio(out);
+            if added == 0 {
+                break;
+            }
+            queued += added;
         }
+        frames
+    }
+
+    // Runs a frame without drawing it, for fast forwarding
+    pub fn run_frame_no_render(&mut self) {
+        let render_enabled = self.cpu.bus.ppu.render_enabled();
+        self.cpu.bus.ppu.set_render_enabled(false);
+        self.run_frame();
+        self.cpu.bus.ppu.set_render_enabled(render_enabled);
+    }
+
+    // Runs a frame and returns PPU::frame_hash() of it
+    pub fn run_frame_and_hash(&mut self) -> u64 {
+        self.run_frame();
+        self.cpu.bus.ppu.frame_hash()
     }
 }
 
@@ -71,8 +336,12 @@ impl SmallInMemoryCartridge {
 }
 
 impl CartridgeData for SmallInMemoryCartridge {
-    fn rom(&self) -> &[u8] {
-        &self.rom
+    fn rom_len(&self) -> usize {
+        self.rom.len()
+    }
+
+    fn read_rom(&self, addr: usize) -> u8 {
+        self.rom.get(addr).copied().unwrap_or(0xFF)
     }
 
     fn ram(&self) -> &[u8] {
@@ -83,3 +352,513 @@ impl CartridgeData for SmallInMemoryCartridge {
         &mut self.ram
     }
 }
+
+/// An in memory cartridge with save RAM, for targets without an allocator.
+/// `ROM` and `RAM` are the most the cartridge can hold, and are checked at
+/// compile time against the sizes a real cartridge can have.
+///
+/// The whole ROM is held inline, so `from_slice` needs the stack to fit
+/// `ROM` bytes (more in debug builds, where the copies aren't elided).
+/// For anything bigger than the stack, put an empty cartridge from `new`
+/// in a `static`, `load` the ROM into it there, and hand the emulator a
+/// `&'static mut` to it.
+///
+/// ```compile_fail
+/// // 48KiB isn't a valid ROM size
+/// let cart = gb_rs::gb::StaticCartridge::<0xC000, 0>::from_slice(&[0; 0xC000]);
+/// ```
+pub struct StaticCartridge<const ROM: usize, const RAM: usize> {
+    pub rom: Vec<u8, ROM>,
+    // Sized to the RAM the header declares, so banks past it mirror
+    pub ram: Vec<u8, RAM>,
+}
+
+impl<const ROM: usize, const RAM: usize> StaticCartridge<ROM, RAM> {
+    // Evaluated once per instantiation, so a bad size fails the build
+    const VALID_SIZES: () = {
+        assert!(
+            ROM >= 0x8000 && ROM <= 0x80_0000 && ROM.is_power_of_two(),
+            "StaticCartridge ROM must be a power-of-two multiple of 32KiB, up to 8MiB"
+        );
+        assert!(
+            // 512 is MBC2's built in RAM
+            matches!(RAM, 0 | 0x200 | 0x2000 | 0x8000 | 0x1_0000 | 0x2_0000),
+            "StaticCartridge RAM must be 0, 512 bytes, or 8, 32, 64 or 128KiB"
+        );
+    };
+
+    // An empty cartridge, usable as a `static` initializer
+    pub const fn new() -> Self {
+        #[allow(clippy::let_unit_value)]
+        let () = Self::VALID_SIZES;
+
+        Self {
+            rom: Vec::new(),
+            ram: Vec::new(),
+        }
+    }
+
+    // Builds the cartridge on the stack, see the type's docs
+    pub fn from_slice(data: &[u8]) -> Self {
+        let mut cart = Self::new();
+        cart.load(data);
+        cart
+    }
+
+    // Replaces the ROM with `data` and clears the RAM, without moving the
+    // cartridge
+    pub fn load(&mut self, data: &[u8]) {
+        let header = get_cart_header(data);
+
+        if header.rom_size as usize > ROM || data.len() > ROM {
+            panic!("The size of this ROM is too large for this cartridge!");
+        }
+
+        if header.ram_size as usize > RAM {
+            panic!("The RAM on this cartridge is too large for this cartridge!");
+        }
+
+        self.rom.clear();
+        self.rom
+            .extend_from_slice(data)
+            .expect("Building rom failed?");
+        self.ram.clear();
+        self.ram
+            .resize(header.ram_size as usize, 0)
+            .expect("Unable to resize RAM");
+    }
+}
+
+impl<const ROM: usize, const RAM: usize> Default for StaticCartridge<ROM, RAM> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const ROM: usize, const RAM: usize> CartridgeData for StaticCartridge<ROM, RAM> {
+    fn rom_len(&self) -> usize {
+        self.rom.len()
+    }
+
+    fn read_rom(&self, addr: usize) -> u8 {
+        self.rom.get(addr).copied().unwrap_or(0xFF)
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Device;
+    use crate::ppu::PpuMode;
+
+    #[test]
+    fn frame_timing() {
+        let per_line = GbRs::<SmallInMemoryCartridge>::cycles_per_scanline();
+        assert_eq!(per_line, 114);
+        assert_eq!(per_line * 4, crate::ppu::DOTS_PER_SCANLINE);
+        assert_eq!(CYCLES_PER_FRAME, per_line * 154);
+        assert_eq!(CYCLES_PER_FRAME, 17556);
+        assert_eq!(T_CYCLES_PER_FRAME, 70224);
+    }
+
+    #[test]
+    fn drain_audio() {
+        let rom = [0; 0x8000];
+        let mut gb = GbRs::new(SmallInMemoryCartridge::from_slice(&rom));
+        let mut out = std::vec::Vec::new();
+        gb.run_frame();
+        gb.drain_audio(&mut out);
+        out.clear();
+
+        // A frame's worth of stereo samples at the default rate, silent
+        // while the APU is off
+        gb.run_frame();
+        let added = gb.drain_audio(&mut out);
+        let per_frame = CYCLES_PER_FRAME as usize * crate::apu::DEFAULT_SAMPLE_RATE as usize
+            / CYCLES_PER_SECOND as usize;
+        assert_eq!(added, out.len());
+        assert!(added.is_multiple_of(2));
+        assert!(
+            (per_frame * 2..=per_frame * 2 + 2).contains(&added),
+            "{added}"
+        );
+        assert!(out.iter().all(|&s| s == 0));
+
+        assert_eq!(gb.drain_audio(&mut out), 0);
+    }
+
+    #[test]
+    fn run_to_fill_audio() {
+        let rom = [0; 0x8000];
+        let mut gb = GbRs::new(SmallInMemoryCartridge::from_slice(&rom));
+        let mut out = std::vec::Vec::new();
+        gb.run_frame();
+        gb.drain_audio(&mut out);
+        out.clear();
+
+        // Each frame adds just over 1600 samples at the default rate
+        assert_eq!(gb.run_to_fill_audio(0, 4000, &mut out), 3);
+        assert!(out.len() >= 4000);
+        out.clear();
+
+        // A sink that's nearly full only needs topping up, a full one not at all
+        assert_eq!(gb.run_to_fill_audio(3000, 4000, &mut out), 1);
+        assert_eq!(gb.run_to_fill_audio(4000, 4000, &mut out), 0);
+
+        // A sink that's being drained between calls keeps asking for frames
+        let mut sink = 0;
+        let mut frames = 0;
+        for _ in 0..10 {
+            out.clear();
+            frames += gb.run_to_fill_audio(sink, 3200, &mut out);
+            sink = (sink + out.len()).saturating_sub(1600);
+        }
+        assert!((10..=12).contains(&frames), "{frames}");
+
+        // Nothing is produced without a sample rate
+        gb.cpu.bus.apu.set_sample_rate(0);
+        assert_eq!(gb.run_to_fill_audio(0, 4000, &mut out), 1);
+    }
+
+    #[test]
+    fn run_until() {
+        let rom = [0; 0x8000];
+        let mut gb = GbRs::new(SmallInMemoryCartridge::from_slice(&rom));
+
+        // A line of NOPs, one M-cycle each
+        let result = gb.run_until(1000, |gb| gb.cpu.registers().pc == 0x110);
+        assert_eq!(
+            result,
+            RunResult {
+                cycles: 0x10,
+                timed_out: false
+            }
+        );
+
+        let result = gb.run_until(100, |_| false);
+        assert_eq!(
+            result,
+            RunResult {
+                cycles: 100,
+                timed_out: true
+            }
+        );
+        assert_eq!(gb.cpu.registers().pc, 0x110 + 100);
+
+        assert_eq!(gb.run_until(0, |_| true).cycles, 0);
+    }
+
+    #[test]
+    fn invalid_opcode() {
+        let mut rom = [0; 0x8000];
+        // inc a; inc a; an invalid opcode; inc a
+        rom[0x100..0x104].copy_from_slice(&[0x3C, 0x3C, 0xD3, 0x3C]);
+        let mut gb = GbRs::new(SmallInMemoryCartridge::from_slice(&rom));
+        let a = gb.cpu.registers().a;
+
+        gb.run_frame();
+        assert_eq!(
+            gb.cpu.fault(),
+            Some(crate::cpu::CpuError::InvalidOpcode {
+                opcode: 0xD3,
+                pc: 0x102
+            })
+        );
+        assert_eq!(gb.cpu.registers().a, a.wrapping_add(2));
+
+        // Nothing runs after that
+        gb.run_frame();
+        gb.run_line();
+        assert_eq!(gb.cpu.registers().a, a.wrapping_add(2));
+        assert_eq!(gb.cpu.pc(), 0x103);
+    }
+
+    #[test]
+    fn run_for() {
+        use core::time::Duration;
+
+        let rom = test_rom(b"SPEED");
+        let mut gb = GbRs::new(SmallInMemoryCartridge::from_slice(&rom));
+        gb.cpu.bus.write(0xFF40, 0x91);
+
+        // 20ms is 20971 M-cycles, give or take an instruction
+        let mut run_for = |ms, speed| {
+            let before = gb.cpu.bus.timer.system_counter();
+            let frame = gb.run_for(Duration::from_millis(ms), speed);
+            let cycles = gb.cpu.bus.timer.system_counter().wrapping_sub(before);
+            (frame, cycles)
+        };
+
+        // A frame is just under 17ms
+        let (frame, first) = run_for(10, 1.0);
+        assert!(!frame);
+        let (frame, second) = run_for(10, 1.0);
+        assert!(frame);
+        assert!((first + second).abs_diff(20971) <= 4);
+
+        // Half speed runs half as many cycles, double speed twice as many
+        assert!(run_for(40, 0.5).1.abs_diff(20971) <= 4);
+        let (frame, cycles) = run_for(10, 2.0);
+        assert!(frame);
+        assert!(cycles.abs_diff(20971) <= 4);
+    }
+
+    #[test]
+    fn run_for_double_speed() {
+        use core::time::Duration;
+
+        let mut rom = test_rom(b"DOUBLE");
+        // stop; jr -2
+        rom[0x100..0x104].copy_from_slice(&[0x10, 0x00, 0x18, 0xFE]);
+        let mut gb = GbRs::new(SmallInMemoryCartridge::from_slice(&rom));
+        gb.cpu.bus.set_cgb_mode(true);
+        gb.cpu.bus.write(0xFF4D, 0x01);
+        gb.run_one();
+        assert!(gb.cpu.bus.double_speed());
+        gb.cpu.bus.write(0xFF40, 0x91);
+
+        // A second of real time is still about 60 frames
+        let frames = (0..1000)
+            .filter(|_| gb.run_for(Duration::from_millis(1), 1.0))
+            .count();
+        assert!((59..=60).contains(&frames), "{frames} frames");
+
+        // And a line is still a line
+        let ly = gb.cpu.bus.ppu.read(0xFF44);
+        gb.run_line();
+        assert_eq!(gb.cpu.bus.ppu.read(0xFF44), (ly + 1) % 154);
+    }
+
+    fn test_rom(title: &[u8]) -> [u8; 0x8000] {
+        let mut rom = [0; 0x8000];
+        rom[0x134..0x134 + title.len()].copy_from_slice(title);
+        // inc a; inc b; ld (0xC000), a; jr -6
+        rom[0x100..0x107].copy_from_slice(&[0x3C, 0x04, 0xEA, 0x00, 0xC0, 0x18, 0xF9]);
+        rom
+    }
+
+    #[test]
+    fn boot_rom() {
+        // ld a, 0x42; ld b, a, then unmap itself right before 0x100
+        let mut boot = [0; BOOT_ROM_LEN];
+        boot[..3].copy_from_slice(&[0x3E, 0x42, 0x47]);
+        boot[0xFC..].copy_from_slice(&[0x3E, 0x01, 0xE0, 0x50]);
+
+        let rom = test_rom(b"BOOT");
+        let cart = || SmallInMemoryCartridge::from_slice(&rom);
+        assert_eq!(
+            GbRs::with_boot_rom(cart(), &boot[1..]).err(),
+            Some(BootRomError::BadLength)
+        );
+
+        let mut gb = GbRs::with_boot_rom(cart(), &boot).unwrap();
+        assert_eq!(gb.cpu.pc(), 0);
+        assert_eq!(gb.cpu.bus.read(0x0000), 0x3E);
+
+        while gb.cpu.pc() != 0x100 {
+            gb.run_one();
+        }
+        assert!(!gb.cpu.bus.boot_rom_mapped());
+        assert_eq!(gb.cpu.bus.read(0x0000), 0x00);
+
+        // The game starts running with the A register the boot ROM left behind
+        for _ in 0..3 {
+            gb.run_one();
+        }
+        assert_eq!(gb.cpu.bus.read(0xC000), 0x02);
+    }
+
+    #[test]
+    fn save_load_state() {
+        let rom = test_rom(b"STATE");
+        let mut gb = GbRs::new(SmallInMemoryCartridge::from_slice(&rom));
+        gb.cpu.bus.ppu.write(0xFF40, 0x91);
+        gb.run_frame();
+
+        let state = gb.save_state();
+        let ly = gb.cpu.bus.ppu.get_ppu_state().ly;
+        let wram = gb.cpu.bus.read(0xC000);
+
+        gb.run_frame();
+        gb.cpu.bus.ppu.write(0x8000, 0x12);
+        assert_ne!(gb.cpu.bus.read(0xC000), wram);
+
+        assert_eq!(gb.load_state(&state), Ok(()));
+        assert_eq!(gb.cpu.bus.read(0xC000), wram);
+        assert_eq!(gb.cpu.bus.read(0x8000), 0x00);
+        assert_eq!(gb.cpu.bus.ppu.get_ppu_state().ly, ly);
+        assert_eq!(gb.save_state(), state);
+    }
+
+    #[test]
+    fn load_state_errors() {
+        let mut gb = GbRs::new(SmallInMemoryCartridge::from_slice(&test_rom(b"ONE")));
+        let mut other = GbRs::new(SmallInMemoryCartridge::from_slice(&test_rom(b"TWO")));
+        let state = gb.save_state();
+
+        assert_eq!(other.load_state(&state), Err(StateError::TitleMismatch));
+        assert_eq!(gb.load_state(&state[1..]), Err(StateError::BadLength));
+
+        let mut bad = state.clone();
+        bad[0] = 0;
+        assert_eq!(gb.load_state(&bad), Err(StateError::BadHeader));
+    }
+
+    #[test]
+    fn new_running_at() {
+        let mut rom = [0; 0x8000];
+        // ret
+        rom[0x200] = 0xC9;
+        let cart = SmallInMemoryCartridge::from_slice(&rom);
+        let mut gb = GbRs::new_running_at(cart, 0x200, 0xC000);
+
+        // The return address
+        gb.cpu.bus.write(0xC000, 0x34);
+        gb.cpu.bus.write(0xC001, 0x12);
+
+        gb.run_one();
+        assert_eq!(gb.cpu.pc(), 0x1234);
+        assert_eq!(gb.cpu.sp(), 0xC002);
+    }
+
+    #[test]
+    fn static_cartridge() {
+        let mut rom = test_rom(b"STATIC");
+        // MBC1 with 8KiB of RAM
+        rom[0x147] = 0x3;
+        rom[0x149] = 0x2;
+        let cart = StaticCartridge::<0x10000, 0x8000>::from_slice(&rom);
+        assert_eq!(cart.rom_len(), 0x8000);
+        assert_eq!(cart.read_rom(0x100), 0x3C);
+        assert_eq!(cart.ram().len(), 0x2000);
+
+        let mut gb = GbRs::new(cart);
+        gb.run_frame();
+        assert_ne!(gb.cpu.bus.read(0xC000), 0);
+
+        // Save RAM works
+        gb.cpu.bus.write(0x0000, 0xA);
+        gb.cpu.bus.write(0xA123, 0x42);
+        assert_eq!(gb.cpu.bus.read(0xA123), 0x42);
+    }
+
+    #[test]
+    fn static_cartridge_in_place() {
+        static mut CART: StaticCartridge<0x8000, 0> = StaticCartridge::new();
+
+        // Only this test touches CART
+        let cart = unsafe { &mut *core::ptr::addr_of_mut!(CART) };
+        cart.load(&test_rom(b"IN PLACE"));
+
+        let mut gb = GbRs::new(cart);
+        gb.run_frame();
+        assert_ne!(gb.cpu.bus.read(0xC000), 0);
+        assert_eq!(gb.cpu.bus.cart.get_header().title, "IN PLACE");
+    }
+
+    #[test]
+    #[should_panic]
+    fn static_cartridge_rom_too_large() {
+        let mut rom = [0; 0x10000];
+        // A 64KiB ROM header
+        rom[0x148] = 0x1;
+        StaticCartridge::<0x8000, 0>::from_slice(&rom);
+    }
+
+    #[test]
+    #[should_panic]
+    fn static_cartridge_ram_too_large() {
+        let mut rom = [0; 0x8000];
+        // 32KiB of RAM
+        rom[0x149] = 0x3;
+        StaticCartridge::<0x8000, 0x2000>::from_slice(&rom);
+    }
+
+    #[test]
+    fn run_until_io_write() {
+        let mut rom = [0; 0x8000];
+        // ld a, 0x91; ldh (0x40), a; jr -2
+        rom[0x100..0x106].copy_from_slice(&[0x3E, 0x91, 0xE0, 0x40, 0x18, 0xFE]);
+        let mut gb = GbRs::new(SmallInMemoryCartridge::from_slice(&rom));
+
+        assert_eq!(gb.run_until_io_write(0xFF40, 100), Some(0x91));
+        assert_eq!(gb.cpu.pc(), 0x104);
+
+        // BGP is never written
+        assert_eq!(gb.run_until_io_write(0xFF47, 1000), None);
+    }
+
+    #[test]
+    fn run_until_io_write_keeps_watchpoints() {
+        let mut rom = [0; 0x8000];
+        // ld a, (0xC000); ld a, 0x91; ldh (0x40), a; jr -2
+        rom[0x100..0x109].copy_from_slice(&[0xFA, 0x00, 0xC0, 0x3E, 0x91, 0xE0, 0x40, 0x18, 0xFE]);
+        let mut gb = GbRs::new(SmallInMemoryCartridge::from_slice(&rom));
+        let bus = &mut gb.cpu.bus;
+        assert!(bus.add_watchpoint(0xC000, WatchKind::Read));
+        assert!(bus.add_watchpoint(0xFF40, WatchKind::Write));
+        for addr in 0xC001..0xC007 {
+            assert!(bus.add_watchpoint(addr, WatchKind::Access));
+        }
+
+        // The read of 0xC000 doesn't stop it, and both hits are still there
+        assert_eq!(gb.run_until_io_write(0xFF40, 100), Some(0x91));
+        let bus = &mut gb.cpu.bus;
+        assert_eq!(bus.take_watch_hit().map(|hit| hit.addr), Some(0xC000));
+        assert_eq!(bus.take_watch_hit().map(|hit| hit.addr), Some(0xFF40));
+        assert_eq!(bus.take_watch_hit(), None);
+        assert!(bus.has_watchpoint(0xFF40, WatchKind::Write));
+
+        // With every watchpoint in use nothing is run
+        assert!(!bus.add_watchpoint(0xC007, WatchKind::Access));
+        let pc = gb.cpu.pc();
+        assert_eq!(gb.run_until_io_write(0xFF47, 1000), None);
+        assert_eq!(gb.cpu.pc(), pc);
+    }
+
+    #[test]
+    fn run_to_vblank() {
+        let mut gb = GbRs::new(SmallInMemoryCartridge::from_slice(&test_rom(b"VBLANK")));
+
+        // The LCD is off, so this just runs a frame's worth of cycles
+        let cycles = gb.run_to_vblank();
+        assert!((CYCLES_PER_FRAME as usize..CYCLES_PER_FRAME as usize + 8).contains(&cycles));
+
+        gb.cpu.bus.ppu.write(0xFF40, 0x91);
+        gb.run_to_vblank();
+        assert!(matches!(
+            gb.cpu.bus.ppu.get_ppu_state().mode,
+            PpuMode::VBLANK
+        ));
+
+        // Lined up with VBLANK, every frame is the same length
+        let cycles = gb.run_to_vblank();
+        assert!(cycles.abs_diff(CYCLES_PER_FRAME as usize) < 8);
+        assert!(matches!(
+            gb.cpu.bus.ppu.get_ppu_state().mode,
+            PpuMode::VBLANK
+        ));
+    }
+
+    #[test]
+    fn run_line_carries_overshoot() {
+        let mut gb = GbRs::new(SmallInMemoryCartridge::from_slice(&test_rom(b"LINES")));
+        let start = gb.cpu.bus.timer.system_counter();
+
+        // The test loop's instructions don't line up with the end of a line,
+        // but the overshoot never adds up past a single instruction
+        for _ in 0..100 {
+            gb.run_line();
+        }
+        let elapsed = gb.cpu.bus.timer.system_counter().wrapping_sub(start) as i32;
+        assert!((0..4).contains(&(elapsed - 100 * CYCLES_PER_SCANLINE)));
+    }
+}
@@ -1,3 +1,8 @@
+use core::fmt;
+
+#[cfg(any(test, feature = "std"))]
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
 pub struct InterruptController {
     pub int_en: u8,
     pub int_f: u8, // IF, but I can't use `if`
@@ -12,6 +17,17 @@ pub enum IntSource {
     JOYPAD = 0x10,
 }
 
+impl IntSource {
+    // In bit order, which is also priority order
+    pub const ALL: [IntSource; 5] = [
+        IntSource::VBLANK,
+        IntSource::LCD,
+        IntSource::TIMER,
+        IntSource::SERIAL,
+        IntSource::JOYPAD,
+    ];
+}
+
 impl InterruptController {
     pub fn new() -> Self {
         InterruptController {
@@ -37,7 +53,8 @@ impl InterruptController {
     pub fn read(&self, addr: u16) -> u8 {
         match addr {
             0xFF0F => {
-                return self.int_f;
+                // The unused upper bits always read back as 1
+                return 0xE0 | self.int_f;
             }
             0xFFFF => {
                 return self.int_en;
@@ -56,8 +73,20 @@ impl InterruptController {
         self.int_f &= !(int_source as u8);
     }
 
+    // IE, indexed like IntSource::ALL
+    pub fn enabled(&self) -> [bool; 5] {
+        IntSource::ALL.map(|src| self.int_en & src as u8 != 0)
+    }
+
+    // IF, indexed like IntSource::ALL
+    pub fn pending_flags(&self) -> [bool; 5] {
+        IntSource::ALL.map(|src| self.int_f & src as u8 != 0)
+    }
+
+    // Whether an enabled interrupt has been requested,
+    // which is what wakes the CPU from HALT regardless of IME
     pub fn pending(&self) -> bool {
-        self.int_f != 0
+        self.int_f & self.int_en & 0x1F != 0
     }
 }
 
@@ -87,6 +116,40 @@ impl Iterator for InterruptController {
     }
 }
 
+// Each source followed by E if it's enabled and P if it's pending,
+// e.g. `VBLANK:EP LCD:E- TIMER:-- SERIAL:-- JOYPAD:-P`
+impl fmt::Display for InterruptController {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let enabled = self.enabled();
+        let pending = self.pending_flags();
+
+        for (i, src) in IntSource::ALL.iter().enumerate() {
+            if i != 0 {
+                write!(f, " ")?;
+            }
+            let e = if enabled[i] { 'E' } else { '-' };
+            let p = if pending[i] { 'P' } else { '-' };
+            write!(f, "{:?}:{}{}", src, e, p)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(any(test, feature = "std"))]
+impl Snapshot for InterruptController {
+    fn save(&self, w: &mut StateWriter) {
+        w.u8(self.int_en);
+        w.u8(self.int_f);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.int_en = r.u8()?;
+        self.int_f = r.u8()?;
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -106,4 +169,30 @@ mod tests {
         int_contr.write(0xFFFF, IntSource::TIMER as u8);
         assert_eq!(int_contr.next().unwrap(), IntSource::TIMER);
     }
+
+    #[test]
+    fn typed_registers() {
+        let mut int_contr = InterruptController::new();
+        int_contr.write(0xFFFF, IntSource::VBLANK as u8 | IntSource::LCD as u8);
+        int_contr.interrupt(IntSource::VBLANK);
+        int_contr.interrupt(IntSource::JOYPAD);
+
+        assert_eq!(int_contr.enabled(), [true, true, false, false, false]);
+        assert_eq!(int_contr.pending_flags(), [true, false, false, false, true]);
+        assert_eq!(
+            std::format!("{}", int_contr),
+            "VBLANK:EP LCD:E- TIMER:-- SERIAL:-- JOYPAD:-P"
+        );
+    }
+
+    #[test]
+    fn if_upper_bits() {
+        let mut int_contr = InterruptController::new();
+        int_contr.write(0xFF0F, 0x00);
+        assert_eq!(int_contr.read(0xFF0F), 0xE0);
+
+        int_contr.write(0xFF0F, 0xFF);
+        assert_eq!(int_contr.read(0xFF0F), 0xFF);
+        assert_eq!(int_contr.int_f, 0x1F);
+    }
 }
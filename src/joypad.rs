@@ -1,6 +1,10 @@
 use core::fmt::Display;
+use core::str::FromStr;
 
-#[derive(Debug)]
+#[cfg(any(test, feature = "std"))]
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum JoypadInput {
     START,
     SELECT,
@@ -13,7 +17,35 @@ pub enum JoypadInput {
 }
 
 impl JoypadInput {
-    fn to_reg(&self) -> u8 {
+    pub const ALL: [JoypadInput; 8] = [
+        JoypadInput::START,
+        JoypadInput::SELECT,
+        JoypadInput::B,
+        JoypadInput::A,
+        JoypadInput::DOWN,
+        JoypadInput::UP,
+        JoypadInput::LEFT,
+        JoypadInput::RIGHT,
+    ];
+
+    pub fn all() -> impl Iterator<Item = JoypadInput> {
+        Self::ALL.into_iter()
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            JoypadInput::START => "START",
+            JoypadInput::SELECT => "SELECT",
+            JoypadInput::B => "B",
+            JoypadInput::A => "A",
+            JoypadInput::DOWN => "DOWN",
+            JoypadInput::UP => "UP",
+            JoypadInput::LEFT => "LEFT",
+            JoypadInput::RIGHT => "RIGHT",
+        }
+    }
+
+    fn to_reg(self) -> u8 {
         match self {
             JoypadInput::START | JoypadInput::DOWN => 0x8,
             JoypadInput::SELECT | JoypadInput::UP => 0x4,
@@ -22,7 +54,7 @@ impl JoypadInput {
         }
     }
 
-    fn is_button(&self) -> bool {
+    fn is_button(self) -> bool {
         match self {
             JoypadInput::START | JoypadInput::SELECT | JoypadInput::A | JoypadInput::B => true,
             _ => false,
@@ -30,6 +62,26 @@ impl JoypadInput {
     }
 }
 
+impl Display for JoypadInput {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ParseJoypadInputError;
+
+// Parses the names printed by Display, ignoring case
+impl FromStr for JoypadInput {
+    type Err = ParseJoypadInputError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::all()
+            .find(|input| input.name().eq_ignore_ascii_case(s))
+            .ok_or(ParseJoypadInputError)
+    }
+}
+
 #[derive(Debug)]
 pub enum JoypadDirection {
     PRESS,
@@ -73,28 +125,41 @@ impl Joypad {
             panic!("Invalid write address to joypad!");
         }
 
-        if self.select_buttons() {
+        // Bits 6 and 7 are unused and always read 1
+        let lines = if self.select_buttons() {
             if self.select_dpad() {
-                return (self.dpad_state & self.button_state) | self.reg;
+                self.dpad_state & self.button_state
             } else {
-                return self.button_state | self.reg;
+                self.button_state
             }
         } else {
             if self.select_dpad() {
-                return self.dpad_state | self.reg;
+                self.dpad_state
             } else {
-                return 0x3F;
+                0xF
             }
-        }
+        };
+
+        0xC0 | self.reg | lines
     }
 
-    pub fn input(&mut self, button: JoypadInput, direction: JoypadDirection) {
+    // Returns true if the input should raise the joypad interrupt,
+    // i.e. a line in the currently selected group went from high to low
+    pub fn input(&mut self, button: JoypadInput, direction: JoypadDirection) -> bool {
+        let selected = if button.is_button() {
+            self.select_buttons()
+        } else {
+            self.select_dpad()
+        };
+
         let state_reg = if button.is_button() {
             &mut self.button_state
         } else {
             &mut self.dpad_state
         };
 
+        let was_high = *state_reg & button.to_reg() != 0;
+
         let pressed = match direction {
             JoypadDirection::PRESS => true,
             JoypadDirection::RELEASE => false,
@@ -106,6 +171,8 @@ impl Joypad {
         } else {
             *state_reg |= button.to_reg();
         }
+
+        pressed && was_high && selected
     }
 
     pub fn get_state(&self) -> JoypadState {
@@ -144,3 +211,68 @@ impl Display for JoypadState {
         Ok(())
     }
 }
+
+#[cfg(any(test, feature = "std"))]
+impl Snapshot for Joypad {
+    fn save(&self, w: &mut StateWriter) {
+        w.u8(self.dpad_state);
+        w.u8(self.button_state);
+        w.u8(self.reg);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.dpad_state = r.u8()?;
+        self.button_state = r.u8()?;
+        self.reg = r.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interrupt_on_press() {
+        let mut joypad = Joypad::new();
+
+        // Nothing selected
+        assert!(!joypad.input(JoypadInput::A, JoypadDirection::PRESS));
+        joypad.input(JoypadInput::A, JoypadDirection::RELEASE);
+
+        // Select the buttons
+        joypad.write(0xFF00, 0x10);
+        assert!(!joypad.input(JoypadInput::UP, JoypadDirection::PRESS));
+        assert!(joypad.input(JoypadInput::A, JoypadDirection::PRESS));
+
+        // Already low, and releases never interrupt
+        assert!(!joypad.input(JoypadInput::A, JoypadDirection::PRESS));
+        assert!(!joypad.input(JoypadInput::A, JoypadDirection::RELEASE));
+        assert!(joypad.input(JoypadInput::START, JoypadDirection::PRESS));
+    }
+
+    #[test]
+    fn unused_bits_read_high() {
+        let mut joypad = Joypad::new();
+        joypad.input(JoypadInput::A, JoypadDirection::PRESS);
+        joypad.input(JoypadInput::DOWN, JoypadDirection::PRESS);
+
+        joypad.write(0xFF00, 0x30);
+        assert_eq!(joypad.read(0xFF00), 0xFF);
+        joypad.write(0xFF00, 0x10);
+        assert_eq!(joypad.read(0xFF00), 0xDE);
+        joypad.write(0xFF00, 0x20);
+        assert_eq!(joypad.read(0xFF00), 0xE7);
+        joypad.write(0xFF00, 0x00);
+        assert_eq!(joypad.read(0xFF00), 0xC6);
+    }
+
+    #[test]
+    fn input_names() {
+        for input in JoypadInput::all() {
+            assert_eq!(std::format!("{input}").parse(), Ok(input));
+        }
+        assert_eq!("select".parse(), Ok(JoypadInput::SELECT));
+        assert_eq!("X".parse::<JoypadInput>(), Err(ParseJoypadInputError));
+    }
+}
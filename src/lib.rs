@@ -3,16 +3,26 @@
 #[cfg(any(test, feature = "std"))]
 extern crate std;
 
+pub mod apu;
 pub mod bus;
 pub mod cart;
 pub mod cpu;
+#[cfg(feature = "debug-port")]
+pub mod debug_port;
+pub mod disasm;
 pub mod gb;
 pub mod interrupts;
 pub mod joypad;
 pub mod oam;
 pub mod ppu;
+pub mod serial;
 pub mod tile;
 pub mod timer;
 
+#[cfg(any(test, feature = "std"))]
+pub mod state;
 #[cfg(any(test, feature = "std"))]
 pub mod util;
+
+#[cfg(test)]
+mod test_bus;
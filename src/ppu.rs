@@ -1,11 +1,16 @@
 use tile::Palette;
-use zerocopy::FromBytes;
+use zerocopy::{FromBytes, IntoBytes};
 
 use crate::interrupts::IntSource;
 use crate::oam::OamMap;
+#[cfg(any(test, feature = "std"))]
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
 use crate::tile::Tile;
 
-mod tile;
+mod fifo;
+pub mod tile;
+
+pub use tile::{OamEntry, OamFlags};
 
 // The number of tiles in all of VRAM
 pub const NTILES: usize = 384;
@@ -17,9 +22,59 @@ pub const BKG_WIDTH: usize = 256;
 pub const SCREEN_WIDTH: usize = 160;
 pub const SCREEN_HEIGHT: usize = 144;
 
+// PPU timings are in M-cycles, the same unit the CPU reports.
+// One M-cycle is 4 dots, so a scanline is 456 dots.
+pub const OAMSCAN_CYCLES: i32 = 20;
+// DRAW and HBLANK are shortest and longest with no fine scroll or
+// sprites, together they always fill the rest of the scanline
+pub const DRAW_CYCLES: i32 = 43;
+pub const HBLANK_CYCLES: i32 = 51;
+pub const CYCLES_PER_SCANLINE: i32 = OAMSCAN_CYCLES + DRAW_CYCLES + HBLANK_CYCLES;
+pub const DOTS_PER_SCANLINE: i32 = CYCLES_PER_SCANLINE * 4;
+// 144 visible lines plus 10 lines of VBLANK
+pub const SCANLINES_PER_FRAME: i32 = 154;
+
+// OAM DMA copies one byte per M-cycle, 640 dots in total
+pub const DMA_CYCLES: u8 = OAM_LEN as u8;
+
+// A color index outside the 2-bit range, used for debug overlays
+pub const SPRITE_OUTLINE_COLOR: u8 = 4;
+
 const VRAM_LEN: usize = 0x2000;
 const OAM_LEN: usize = 0xA0;
 
+// How overlapping sprites are prioritized.
+//
+// DMG hardware, and a CGB running a DMG game, uses the sprite's X coordinate
+// and then its OAM index. Native CGB games use only the OAM index by default
+// (see OPRI).
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum ObjPriority {
+    #[default]
+    Coordinate,
+    OamIndex,
+}
+
+// How lines are drawn.
+//
+// Scanline draws each line all at once at the end of DRAW, using the
+// registers as they are then. Fifo shifts pixels out one dot at a time
+// through DRAW like the hardware's pixel FIFO, so mid-line SCX or palette
+// writes show up, but it's slower.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum RenderMode {
+    #[default]
+    Scanline,
+    Fifo,
+}
+
+// The two tile maps in VRAM
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TileMap {
+    Map9800,
+    Map9C00,
+}
+
 #[derive(Clone, Copy, Debug)]
 pub enum PpuMode {
     HBLANK = 0,
@@ -42,10 +97,46 @@ pub struct PPU {
     obp1: u8,
     wy: u8,
     wx: u8,
+    // Latched the first time LY matches WY in a frame, the window can only
+    // be drawn from then until the end of the frame. Changing WY afterwards
+    // doesn't undo it.
     window_triggered: bool,
+    // The window's internal line counter. It only advances on lines where
+    // the window is visible (see window_visible()), so disabling the window
+    // with LCDC bit 5 or moving it off screen with WX pauses it, and it
+    // carries on from the same line once the window is back. Both are reset
+    // at the start of each frame.
     window_counter: u8,
     mode: PpuMode,
     r_cyc: i32,
+    // M-cycles of HBLANK left over after the current line's DRAW
+    hblank_cycles: i32,
+    dma_src: u16,
+    // M-cycles left in the current OAM DMA, 0 when idle
+    dma_remaining: u8,
+    sprite_debug_outline: bool,
+    // When set, the BG, window and sprite layers of each line are kept in
+    // debug_bufs as they're drawn
+    debug_layers: bool,
+    debug_bufs: [[u8; SCREEN_WIDTH]; 3],
+    // Cleared to skip drawing lines, e.g. while fast forwarding
+    render_enabled: bool,
+    render_mode: RenderMode,
+    fifo: fifo::PixelFifo,
+    restrict_access: bool,
+    // Emulate the DMG's OAM corruption bug, see oam_bug_write()
+    oam_bug: bool,
+    sprites_on_line: u8,
+    // SCX and SCY as each line of the current frame was drawn, lines not
+    // yet reached this frame are from the last one
+    line_scroll: [(u8, u8); SCREEN_HEIGHT],
+    obj_priority: ObjPriority,
+    // An interrupt raised by a register write rather than by run()
+    pending_int: Option<IntSource>,
+    // The internal STAT interrupt line, see update_stat_line()
+    stat_line: bool,
+    // Set when a frame has been fully drawn
+    frame_ready: bool,
     pub screen: Frame,
 }
 
@@ -73,6 +164,18 @@ pub struct PpuState {
     pub mode: PpuMode,
     pub lyc: u8,
     pub stat: u8,
+    // Always 0 until CGB support exists
+    pub vram_bank: u8,
+    pub bg_tile_map_addr: u16,
+    pub window_tile_map_addr: u16,
+    // 0x8000 for unsigned tile indices, 0x8800 for signed ones
+    pub tile_data_addr: u16,
+    // The number of sprites selected when the last line was drawn
+    pub sprites_on_line: u8,
+    // M-cycles left in the current OAM DMA
+    pub dma_remaining: u8,
+    // (SCX, SCY) as each line was drawn, for finding mid-frame scroll changes
+    pub line_scroll: [(u8, u8); SCREEN_HEIGHT],
 }
 
 impl PPU {
@@ -95,7 +198,24 @@ impl PPU {
             window_triggered: false,
             window_counter: 0,
             mode: PpuMode::OAMSCAN,
-            r_cyc: 20,
+            r_cyc: OAMSCAN_CYCLES,
+            hblank_cycles: HBLANK_CYCLES,
+            dma_src: 0,
+            dma_remaining: 0,
+            sprite_debug_outline: false,
+            debug_layers: false,
+            debug_bufs: [[0; SCREEN_WIDTH]; 3],
+            render_enabled: true,
+            render_mode: RenderMode::Scanline,
+            fifo: fifo::PixelFifo::new(),
+            restrict_access: false,
+            oam_bug: false,
+            sprites_on_line: 0,
+            line_scroll: [(0, 0); SCREEN_HEIGHT],
+            obj_priority: ObjPriority::default(),
+            pending_int: None,
+            stat_line: false,
+            frame_ready: false,
             screen: Frame::new(),
         }
     }
@@ -109,10 +229,34 @@ impl PPU {
                 self.oam[addr as usize - 0xFE00] = val;
             }
             0xFF40 => {
+                let was_enabled = self.lcd_enabled();
                 self.lcdc = val;
+
+                if was_enabled && !self.lcd_enabled() {
+                    // The PPU stops with LY held at 0 and the screen goes blank
+                    self.ly = 0;
+                    self.mode = PpuMode::HBLANK;
+                    self.window_counter = 0;
+                    self.window_triggered = false;
+                    self.screen.buf = [[0; SCREEN_WIDTH]; SCREEN_HEIGHT];
+                    self.stat_line = false;
+                    self.fifo = fifo::PixelFifo::new();
+                } else if !was_enabled && self.lcd_enabled() {
+                    // Restart from the top of the frame. LYC may have been changed
+                    // while the LCD was off, so the comparison is redone right away.
+                    self.ly = 0;
+                    self.mode = PpuMode::OAMSCAN;
+                    self.r_cyc = OAMSCAN_CYCLES;
+                    if self.update_stat_line() {
+                        self.pending_int = Some(IntSource::LCD);
+                    }
+                }
             }
             0xFF41 => {
                 self.stat = val;
+                if self.update_stat_line() {
+                    self.pending_int = Some(IntSource::LCD);
+                }
             }
             0xFF42 => {
                 self.scy = val;
@@ -125,9 +269,14 @@ impl PPU {
             }
             0xFF45 => {
                 self.lyc = val;
+                if self.update_stat_line() {
+                    self.pending_int = Some(IntSource::LCD);
+                }
             }
             0xFF46 => {
-                unimplemented!("DMA not implemented in PPU!")
+                // The bytes are copied by the bus, see Bus::run_cycles()
+                self.dma_src = (val as u16) << 8;
+                self.dma_remaining = DMA_CYCLES;
             }
             0xFF47 => {
                 self.bgp = val;
@@ -150,6 +299,74 @@ impl PPU {
         }
     }
 
+    // Copies `data` to the start of VRAM (0x8000), bypassing the per-address
+    // decoding in write(). Panics if `data` is larger than VRAM.
+    pub fn load_vram(&mut self, data: &[u8]) {
+        self.vram[..data.len()].copy_from_slice(data);
+    }
+
+    // Same as load_vram(), for OAM (0xFE00)
+    pub fn load_oam(&mut self, data: &[u8]) {
+        self.oam[..data.len()].copy_from_slice(data);
+    }
+
+    // Lock the CPU out of VRAM and OAM while the PPU is using them, like
+    // real hardware. Off by default, some test ROMs don't expect it.
+    pub fn set_access_restrictions(&mut self, enabled: bool) {
+        self.restrict_access = enabled;
+    }
+
+    // Off by default, games don't rely on the corruption and it's
+    // only partially emulated
+    pub fn set_oam_bug(&mut self, enabled: bool) {
+        self.oam_bug = enabled;
+    }
+
+    // On the DMG, a 16-bit INC or DEC of a register pointing into
+    // 0xFE00..=0xFEFF while the PPU is scanning OAM corrupts the row of OAM
+    // being scanned. The row's first word is mixed with the previous row's,
+    // and the rest of the previous row is copied over it. `addr` is the
+    // register's value before the INC or DEC.
+    pub fn oam_bug_write(&mut self, addr: u16) {
+        if !self.oam_bug
+            || !self.lcd_enabled()
+            || !matches!(self.mode, PpuMode::OAMSCAN)
+            || !(0xFE00..=0xFEFF).contains(&addr)
+        {
+            return;
+        }
+
+        // One 8 byte row is scanned per M-cycle, the first row is never hit
+        let row = (OAMSCAN_CYCLES - self.r_cyc) as usize;
+        if row == 0 || row >= OAM_LEN / 8 {
+            return;
+        }
+
+        let cur = row * 8;
+        let prev = cur - 8;
+        let word = |i: usize| u16::from_le_bytes([self.oam[i], self.oam[i + 1]]);
+        let (a, b, c) = (word(cur), word(prev), word(prev + 4));
+
+        let first = ((a ^ c) & (b ^ c)) ^ c;
+        self.oam[cur..cur + 2].copy_from_slice(&first.to_le_bytes());
+        self.oam.copy_within(prev + 2..prev + 8, cur + 2);
+    }
+
+    // Whether the CPU can currently reach VRAM
+    pub fn vram_accessible(&self) -> bool {
+        !self.restrict_access || !matches!(self.mode, PpuMode::DRAW)
+    }
+
+    // Whether the CPU can currently reach OAM
+    pub fn oam_accessible(&self) -> bool {
+        !self.restrict_access || !matches!(self.mode, PpuMode::OAMSCAN | PpuMode::DRAW)
+    }
+
+    // Places a single sprite in OAM. Panics if `index` isn't below 40.
+    pub fn set_oam_entry(&mut self, index: usize, entry: OamEntry) {
+        self.oam[index * 4..index * 4 + 4].copy_from_slice(entry.as_bytes());
+    }
+
     pub fn read(&self, addr: u16) -> u8 {
         match addr {
             0x8000..=0x9FFF => {
@@ -205,13 +422,19 @@ impl PPU {
         return (pallete >> (2 * color_id)) & 0x3;
     }
 
-    fn render_sprites(&mut self) {
+    // Draws the sprites over the current line. `bg_colors` is the line's BG and
+    // window before the palette was applied, which decides sprite priority.
+    fn render_sprites(&mut self, bg_colors: &[u8; SCREEN_WIDTH]) {
         let oam = tile::Oam::ref_from_bytes(&self.oam).unwrap();
         let vram = tile::VramBank::ref_from_bytes(&self.vram).unwrap();
 
         let large_sprites = self.large_sprites();
+        let outline = self.sprite_debug_outline;
+        let debug_layers = self.debug_layers;
+        let obj_layer = &mut self.debug_bufs[2];
         let screen_line = &mut self.screen.buf[self.ly as usize];
-        let objs = oam.get_oams_line(self.ly, large_sprites);
+        let objs = oam.get_oams_line(self.ly, large_sprites, self.obj_priority);
+        self.sprites_on_line = objs.len() as u8;
 
         for obj in objs {
             let x = obj.x as usize;
@@ -237,75 +460,141 @@ impl PPU {
                 vram,
                 obj_offset,
                 large_sprites,
-                tile::Palette(pal),
+                tile::Palette::DEFAULT_PALETTE,
                 &mut oam_pixels,
             );
 
-            let (dst, src) = {
-                if x < 8 {
-                    // Clipped at beginning of line
-                    (&mut screen_line[..x], &oam_pixels[8 - x..])
-                } else if x > 160 {
-                    // Clipped at end of line
-                    let b = 168 - x;
-                    (&mut screen_line[x - 8..], &oam_pixels[..b])
-                } else {
-                    (&mut screen_line[x - 8..x], &oam_pixels[..])
+            // The sprite covers screen columns x - 8..x, clip that to the screen
+            // and take the matching columns of the sprite
+            let start = x.saturating_sub(8);
+            let end = x.min(SCREEN_WIDTH);
+            let dst = &mut screen_line[start..end];
+            let src = &oam_pixels[start + 8 - x..end + 8 - x];
+            let bg = &bg_colors[start..end];
+
+            // Color 0 is transparent, and a sprite behind the BG only
+            // shows over BG color 0, whatever shade that is
+            for i in 0..dst.len() {
+                if src[i] != 0 && (!obj.flags.priority() || bg[i] == 0) {
+                    dst[i] = tile::Line::apply_palette(src[i], tile::Palette(pal));
                 }
-            };
+                if debug_layers && src[i] != 0 {
+                    obj_layer[start + i] = src[i];
+                }
+            }
 
-            assert!(dst.len() == src.len());
+            if outline {
+                let height = if large_sprites { 16 } else { 8 };
+                let edge_row = obj_offset == 0 || obj_offset == height - 1;
+                // Column within the sprite of dst[0]
+                let first_col = 8_usize.saturating_sub(x);
 
-            if obj.flags.priority() {
-                for i in 0..dst.len() {
-                    if dst[i] == 0 {
-                        dst[i] = src[i];
-                    }
-                }
-            } else {
-                for i in 0..dst.len() {
-                    if src[i] != 0 {
-                        dst[i] = src[i];
+                for (i, px) in dst.iter_mut().enumerate() {
+                    let col = first_col + i;
+                    if edge_row || col == 0 || col == 7 {
+                        *px = SPRITE_OUTLINE_COLOR;
                     }
                 }
             }
         }
     }
 
+    // Should only be changed from the default by a CGB running a CGB game
+    pub fn set_obj_priority(&mut self, priority: ObjPriority) {
+        self.obj_priority = priority;
+    }
+
+    // Draws a box around every rendered sprite in SPRITE_OUTLINE_COLOR
+    pub fn set_sprite_debug_outline(&mut self, enable: bool) {
+        self.sprite_debug_outline = enable;
+    }
+
+    // Keep the layers of each line before they're composited, see debug_layers()
+    pub fn set_debug_layers(&mut self, enable: bool) {
+        self.debug_layers = enable;
+    }
+
+    // The BG, window and sprite layers of the last line drawn, as color IDs
+    // before the palettes are applied. Sprites are drawn whatever their
+    // priority, and 0 is transparent in the window and sprite layers.
+    // Only updated while set_debug_layers() is on.
+    pub fn debug_layers(
+        &self,
+    ) -> (
+        &[u8; SCREEN_WIDTH],
+        &[u8; SCREEN_WIDTH],
+        &[u8; SCREEN_WIDTH],
+    ) {
+        let [bg, window, obj] = &self.debug_bufs;
+        (bg, window, obj)
+    }
+
+    // With rendering disabled the PPU keeps its timing and interrupts, but
+    // the screen is left as it is
+    pub fn set_render_enabled(&mut self, enable: bool) {
+        self.render_enabled = enable;
+    }
+
+    pub fn render_enabled(&self) -> bool {
+        self.render_enabled
+    }
+
+    // Takes effect from the next line
+    pub fn set_render_mode(&mut self, mode: RenderMode) {
+        self.render_mode = mode;
+    }
+
+    pub fn render_mode(&self) -> RenderMode {
+        self.render_mode
+    }
+
     fn render_line(&mut self) {
         //TODO: Better timing
 
         let ly = self.ly as usize;
 
+        // The BG and window are drawn as color IDs, sprite priority needs
+        // those, and the palette is applied afterwards
         self.render_bg2();
+        if self.debug_layers {
+            self.debug_bufs = [self.screen.buf[ly], [0; SCREEN_WIDTH], [0; SCREEN_WIDTH]];
+        }
 
         // Window
-        if self.lcdc & 0x20 != 0 && self.window_triggered {
-            let wx = self.wx as usize;
-            let window_line = if (self.lcdc & 0x1) == 0 {
-                [0; 256]
-            } else {
-                self.render_window_line(self.window_counter)
-            };
-            let screen_line = &mut self.screen.buf[ly];
-
-            if wx < 8 {
-                let window_offset = 7 - wx;
-                screen_line.copy_from_slice(&window_line[window_offset..window_offset + 160]);
-                self.window_counter += 1;
-            } else if wx > 166 {
-                // Window not visible
-            } else {
-                let screen_offset = wx - 7;
-                let window_len = 160 - screen_offset;
-                screen_line[screen_offset..].copy_from_slice(&window_line[..window_len]);
-                self.window_counter += 1;
+        if self.window_visible() {
+            if self.bg_win_enabled() {
+                let wx = self.wx as usize;
+                let window_line = self.render_map_line(
+                    self.window_map_start_addr(),
+                    self.window_counter,
+                    Palette::DEFAULT_PALETTE,
+                );
+
+                // The window starts at screen column WX - 7, a WX below 7
+                // cuts off the window's leftmost columns instead
+                let (screen_offset, window_offset) = if wx < 7 { (0, 7 - wx) } else { (wx - 7, 0) };
+                let window_px = &window_line[window_offset..][..SCREEN_WIDTH - screen_offset];
+                self.screen.buf[ly][screen_offset..].copy_from_slice(window_px);
+                if self.debug_layers {
+                    self.debug_bufs[1][screen_offset..].copy_from_slice(window_px);
+                }
+            }
+
+            // The window is still fetched, and uses up a line, while it's blanked
+            self.window_counter = self.window_counter.wrapping_add(1);
+        }
+
+        let bg_colors = self.screen.buf[ly];
+        if self.bg_win_enabled() {
+            for px in &mut self.screen.buf[ly] {
+                *px = tile::Line::apply_palette(*px, Palette(self.bgp));
             }
         }
 
         // Sprites
+        self.sprites_on_line = 0;
         if self.obj_en() {
-            self.render_sprites()
+            self.render_sprites(&bg_colors)
             /*
             let oam_map = OamMap::from_mem(&self.oam);
 
@@ -373,30 +662,6 @@ impl PPU {
         }
     }
 
-    fn render_tiles(tiles: &[Tile; TILE_MAP_LEN], line: u8) -> [u8; BKG_WIDTH] {
-        // The number of tiles in a horizontal line
-        const N_TILES_IN_LINE: usize = BKG_WIDTH / 8;
-
-        // The tile offset corresponding to the begining of this line
-        let y_tile_offset = (line as usize / 8) * N_TILES_IN_LINE;
-
-        // vertical offset inside the tile
-        // e.g. if we are drawing line 10, this should be 2
-        // since we are drawing the third line inside the tile
-        let vert_line_tile_offset: u8 = (line % 8).try_into().unwrap();
-
-        let mut pixels = [0; BKG_WIDTH];
-        let mut i = 0;
-
-        for (_, eight_pixels) in pixels.chunks_exact_mut(8).enumerate() {
-            eight_pixels
-                .copy_from_slice(&tiles[y_tile_offset + i].pixel_buf(vert_line_tile_offset));
-            i += 1;
-        }
-
-        pixels
-    }
-
     fn render_bg2_line(
         vram: &tile::VramBank,
         mut line_buf: &mut [u8],
@@ -451,8 +716,9 @@ impl PPU {
         //TODO: This just renders the BG, have it render
         //      the window in the same pass for opt
 
+        let bg_win_enabled = self.bg_win_enabled();
         let line_buf: &mut [u8] = &mut self.screen.buf[self.ly as usize];
-        if self.lcdc & 0x01 == 0 {
+        if !bg_win_enabled {
             line_buf.fill(0);
             return;
         }
@@ -460,29 +726,72 @@ impl PPU {
         let vram = tile::VramBank::ref_from_bytes(&self.vram).unwrap();
         let high_tile_map = self.lcdc & 0x8 == 0x8;
         let alt_address_mode = self.lcdc & 0x10 == 0;
-        let palette = tile::Palette(self.bgp);
 
+        // Color IDs, see render_line()
         Self::render_bg2_line(
             vram,
             line_buf,
             self.ly.wrapping_add(self.scy),
             self.scx,
-            palette,
+            Palette::DEFAULT_PALETTE,
             high_tile_map,
             alt_address_mode,
         );
     }
 
+    // Renders a full 256 pixel line of the tile map at `map_start_addr`,
+    // used for the window and the debug views
+    fn render_map_line(&self, map_start_addr: u16, line: u8, palette: Palette) -> [u8; BKG_WIDTH] {
+        let mut line_buf = [0; BKG_WIDTH];
+        let vram = tile::VramBank::ref_from_bytes(&self.vram).unwrap();
+
+        Self::render_bg2_line(
+            vram,
+            &mut line_buf,
+            line,
+            0,
+            palette,
+            map_start_addr == 0x9C00,
+            self.lcdc & 0x10 == 0,
+        );
+
+        line_buf
+    }
+
     fn render_bg_line(&self, ly: u8) -> [u8; BKG_WIDTH] {
-        Self::render_tiles(&self.get_background_tiles(), ly)
+        self.render_map_line(self.bkgr_map_start_addr(), ly, Palette(self.bgp))
     }
 
     fn render_window_line(&self, ly: u8) -> [u8; BKG_WIDTH] {
-        //TODO: We can do optimizations for rendering BG w/ Window
-        // 1. If tile maps are the same, we only need to arrange the tiles once
-        // 2. Consider keeping around all of the tiles and only update them
-        //    when VRAM is updated
-        Self::render_tiles(&self.get_window_tiles(), ly)
+        self.render_map_line(self.window_map_start_addr(), ly, Palette(self.bgp))
+    }
+
+    // VRAM viewed as tile data and the two tilemaps
+    pub fn vram_tiles(&self) -> &tile::VramBank {
+        tile::VramBank::ref_from_bytes(&self.vram).unwrap()
+    }
+
+    // Any of the 384 tiles in VRAM, by index, through `palette`
+    pub fn render_tile(&self, index: usize, palette: Palette) -> [[u8; 8]; 8] {
+        self.vram_tiles().tile(index).render_with_palette(palette)
+    }
+
+    pub fn bgp(&self) -> Palette {
+        Palette(self.bgp)
+    }
+
+    pub fn obp0(&self) -> Palette {
+        Palette(self.obp0)
+    }
+
+    pub fn obp1(&self) -> Palette {
+        Palette(self.obp1)
+    }
+
+    // The background tilemap and tile data currently selected by LCDC
+    pub fn bg_tilemap(&self) -> impl Iterator<Item = (u8, &tile::Tile)> {
+        self.vram_tiles()
+            .tilemap(self.lcdc & 0x10 == 0, self.bkgr_map_start_addr() == 0x9C00)
     }
 
     pub fn render_bg(&self) -> [[u8; BKG_WIDTH]; BKG_WIDTH] {
@@ -499,6 +808,17 @@ impl PPU {
         bkg
     }
 
+    // On the DMG, LCDC bit 0 blanks both the BG and the window to color 0.
+    // Sprites are still drawn.
+    fn bg_win_enabled(&self) -> bool {
+        self.lcdc & 0x1 != 0
+    }
+
+    // Whether the window covers part of the current line
+    fn window_visible(&self) -> bool {
+        self.lcdc & 0x20 != 0 && self.window_triggered && self.wx <= 166
+    }
+
     fn obj_en(&self) -> bool {
         self.lcdc & 0x2 != 0
     }
@@ -519,37 +839,22 @@ impl PPU {
         }
     }
 
-    pub fn palette_to_rgba(ind: u8) -> [u8; 4] {
-        let val = 255 - (85 * ind);
-        return [val, val, val, 0xFF];
-    }
-
-    fn get_tile_map(&self, start_addr: u16) -> [u8; TILE_MAP_LEN] {
-        let start_addr = start_addr as usize;
-        let tile_map: [u8; TILE_MAP_LEN] = core::array::from_fn(|index| {
-            let tilemap_index = index + start_addr - 0x8000;
-            self.vram[tilemap_index]
-        });
-
-        tile_map
+    pub fn palette_to_rgba(&self, ind: u8) -> [u8; 4] {
+        self.screen.palette.to_rgba(ind)
     }
 
-    fn get_background_tiles(&self) -> [Tile; TILE_MAP_LEN] {
-        let tile_map = self.get_tile_map(self.bkgr_map_start_addr());
-
-        let tiles: [Tile; TILE_MAP_LEN] =
-            core::array::from_fn(|index| self.bkgr_tile(tile_map[index]));
-
-        tiles
+    // The colors the four shades are shown in by get_screen()
+    pub fn set_display_palette(&mut self, palette: DisplayPalette) {
+        self.screen.palette = palette;
     }
 
-    fn get_window_tiles(&self) -> [Tile; TILE_MAP_LEN] {
-        let tile_map = self.get_tile_map(self.window_map_start_addr());
-
-        let tiles: [Tile; TILE_MAP_LEN] =
-            core::array::from_fn(|index| self.bkgr_tile(tile_map[index]));
-
-        tiles
+    // The raw tile indices of one of the tile maps, row by row
+    pub fn tile_map(&self, which: TileMap) -> &[u8] {
+        let start = match which {
+            TileMap::Map9800 => 0x1800,
+            TileMap::Map9C00 => 0x1C00,
+        };
+        &self.vram[start..start + TILE_MAP_LEN]
     }
 
     pub fn bkgr_tile(&self, tile_index: u8) -> Tile {
@@ -580,6 +885,46 @@ impl PPU {
         self.screen.to_rgba()
     }
 
+    // Like get_screen(), but into a buffer the caller keeps around, see
+    // Frame::write_rgba()
+    pub fn write_rgba_into(&self, dest: &mut [u8]) {
+        self.screen.write_rgba(dest);
+    }
+
+    // See Frame::to_2bpp()
+    pub fn get_screen_indices(&self) -> [u8; SCREEN_WIDTH * SCREEN_HEIGHT / 4] {
+        self.screen.to_2bpp()
+    }
+
+    // The color (after palette) of a single pixel of the current frame,
+    // or None if the coordinate is off screen. Pixels covered by a sprite
+    // outline are SPRITE_OUTLINE_COLOR instead.
+    pub fn pixel_at(&self, x: usize, y: usize) -> Option<u8> {
+        self.screen.buf.get(y)?.get(x).copied()
+    }
+
+    // A 64-bit FNV-1a hash of the current frame's colors, which is stable
+    // across runs and platforms, for checking frames without golden images
+    pub fn frame_hash(&self) -> u64 {
+        self.screen
+            .buf
+            .iter()
+            .flatten()
+            .fold(0xCBF2_9CE4_8422_2325, |hash, &pixel| {
+                (hash ^ pixel as u64).wrapping_mul(0x0000_0100_0000_01B3)
+            })
+    }
+
+    // The window's internal line counter, i.e. the line
+    // of the window that will be drawn next
+    pub fn window_line(&self) -> u8 {
+        self.window_counter
+    }
+
+    pub fn set_window_line(&mut self, line: u8) {
+        self.window_counter = line;
+    }
+
     pub fn get_sprite_map(&self) -> OamMap {
         OamMap::from_mem(&self.oam)
     }
@@ -589,69 +934,92 @@ impl PPU {
         Tile::from_bytes(&self.vram[vram_index..vram_index + 16])
     }
 
+    // An interrupt caused by the last register write, if any
+    pub fn take_interrupt(&mut self) -> Option<IntSource> {
+        self.pending_int.take()
+    }
+
+    // While OAM DMA runs the CPU can only access HRAM
+    pub fn dma_active(&self) -> bool {
+        self.dma_remaining != 0
+    }
+
+    // The source address of the current OAM DMA
+    pub fn dma_source(&self) -> u16 {
+        self.dma_src
+    }
+
+    // The number of bytes the current OAM DMA has already copied
+    pub fn dma_progress(&self) -> u16 {
+        (DMA_CYCLES - self.dma_remaining) as u16
+    }
+
+    // Counts down the OAM DMA. It's clocked with the CPU rather than the
+    // PPU, and runs whether or not the LCD is on.
+    pub fn run_dma(&mut self, cycles: u16) {
+        self.dma_remaining = self.dma_remaining.saturating_sub(cycles.min(0xFF) as u8);
+    }
+
     pub fn run(&mut self, cycles: i32) -> Option<IntSource> {
+        if !self.lcd_enabled() {
+            return None;
+        }
+
+        if self.fifo.active {
+            self.fifo_run(cycles.min(self.r_cyc) * 4);
+        }
+
         if cycles < self.r_cyc {
             self.r_cyc = self.r_cyc - cycles;
             return None;
         }
 
         let over_cycles = cycles - self.r_cyc;
+        let mut int = None;
 
         match self.mode {
             PpuMode::OAMSCAN => {
-                // 43 is the minimum, real should be
-                // based on PPU / OAM state
+                let draw_cycles = self.draw_cycles();
+                self.hblank_cycles = CYCLES_PER_SCANLINE - OAMSCAN_CYCLES - draw_cycles;
+                if !self.window_triggered && self.ly == self.wy {
+                    self.window_triggered = true;
+                }
+                if self.render_enabled && self.render_mode == RenderMode::Fifo {
+                    self.fifo_start_line();
+                }
                 self.mode = PpuMode::DRAW;
-                self.r_cyc = 43 - over_cycles;
+                self.r_cyc = draw_cycles - over_cycles;
             }
 
             PpuMode::DRAW => {
-                if self.ly == self.wy {
-                    self.window_triggered = true;
-                }
+                self.line_scroll[self.ly as usize] = (self.scx, self.scy);
 
                 // Exiting DRAW state
-                self.render_line();
+                if self.fifo.active {
+                    self.fifo_finish_line();
+                } else if self.render_enabled {
+                    self.render_line();
+                } else if self.window_visible() {
+                    // The window uses up a line whether or not it's drawn
+                    self.window_counter = self.window_counter.wrapping_add(1);
+                }
 
-                // TODO: Use actual timing, not just 51
                 self.mode = PpuMode::HBLANK;
-                self.r_cyc = 51 - over_cycles;
-
-                // Check for HBlank interrupt
-                if (self.stat & 0x8) != 0 {
-                    return Some(IntSource::LCD);
-                }
+                self.r_cyc = self.hblank_cycles - over_cycles;
             }
 
             PpuMode::HBLANK => {
                 self.ly += 1;
 
-                // Are we entering VBLANK?
-                if self.ly == 143 {
+                // Lines 0-143 are drawn, 144-153 are VBLANK
+                if self.ly == SCREEN_HEIGHT as u8 {
                     self.mode = PpuMode::VBLANK;
-                    self.r_cyc = 114 - over_cycles;
-                    // Check for LYC int
-                    if (self.stat & 0x40) != 0 {
-                        if self.ly == self.lyc {
-                            return Some(IntSource::LCD);
-                        }
-                    }
-                    return Some(IntSource::VBLANK);
+                    self.frame_ready = true;
+                    self.r_cyc = CYCLES_PER_SCANLINE - over_cycles;
+                    int = Some(IntSource::VBLANK);
                 } else {
                     self.mode = PpuMode::OAMSCAN;
-                    self.r_cyc = 20 - over_cycles;
-
-                    // Check for LYC int
-                    if (self.stat & 0x40) != 0 {
-                        if self.ly == self.lyc {
-                            return Some(IntSource::LCD);
-                        }
-                    }
-
-                    // Check for OAM scan interrupt
-                    if (self.stat & 0x20) != 0 {
-                        return Some(IntSource::LCD);
-                    }
+                    self.r_cyc = OAMSCAN_CYCLES - over_cycles;
                 }
             }
 
@@ -659,35 +1027,101 @@ impl PPU {
                 if self.ly == 153 {
                     // Go back OAM Scan and restart!
                     self.mode = PpuMode::OAMSCAN;
-                    self.r_cyc = 20 - over_cycles;
+                    self.r_cyc = OAMSCAN_CYCLES - over_cycles;
                     self.ly = 0;
                     self.window_counter = 0;
                     self.window_triggered = false;
-
-                    // Check for OAM scan interrupt
-                    if (self.stat & 0x20) != 0 {
-                        return Some(IntSource::LCD);
-                    }
                 } else {
                     self.ly += 1;
-                    self.r_cyc = 114 - over_cycles;
-
-                    // Check for LYC int
-                    if (self.stat & 0x40) != 0 {
-                        if self.ly == self.lyc {
-                            return Some(IntSource::LCD);
-                        }
-                    }
+                    self.r_cyc = CYCLES_PER_SCANLINE - over_cycles;
                 }
             }
         }
 
-        None
+        if self.update_stat_line() {
+            // Only one interrupt can be returned, the other is picked up
+            // through take_interrupt()
+            if int.is_some() {
+                self.pending_int = Some(IntSource::LCD);
+            } else {
+                int = Some(IntSource::LCD);
+            }
+        }
+
+        int
+    }
+
+    // The length of DRAW for the current line. The 172 dot minimum is
+    // lengthened by the pixels discarded for SCX's fine scroll, and by
+    // fetching each sprite on the line: 6 dots, plus up to 5 more waiting for
+    // the BG fetch of the tile the sprite lands on, paid once per BG tile.
+    fn draw_cycles(&self) -> i32 {
+        let fine_scroll = self.scx % 8;
+        let obj_dots: i32 = self.line_objs().iter().map(|o| o.fetch_dots as i32).sum();
+        let dots = DRAW_CYCLES * 4 + fine_scroll as i32 + obj_dots;
+
+        // Rounded up to whole M-cycles
+        (dots + 3) / 4
+    }
+
+    // The sprites on the current line in the order they're fetched, left
+    // to right, with how many dots fetching each one adds to DRAW. None
+    // are fetched with OBJ disabled, and those past the right edge never are.
+    fn line_objs(&self) -> heapless::Vec<fifo::LineObj, 10> {
+        let mut objs = heapless::Vec::new();
+        if !self.obj_en() {
+            return objs;
+        }
+
+        let oam = tile::Oam::ref_from_bytes(&self.oam).unwrap();
+        let by_priority = oam.get_oams_line(self.ly, self.large_sprites(), self.obj_priority);
+        for (rank, &obj) in by_priority.iter().rev().enumerate() {
+            let _ = objs.push(fifo::LineObj {
+                obj,
+                rank: rank as u8,
+                fetch_dots: 0,
+            });
+        }
+        objs.sort_unstable_by_key(|o| (o.obj.x, o.rank));
+
+        // One bit for each BG tile a sprite can land on
+        let fine_scroll = self.scx % 8;
+        let mut tiles_fetched = 0_u32;
+        for o in objs.iter_mut().filter(|o| o.obj.x < 168) {
+            let px = o.obj.x as u32 + fine_scroll as u32;
+            let tile = px / 8;
+            o.fetch_dots = 6;
+            if tiles_fetched & (1 << tile) == 0 {
+                tiles_fetched |= 1 << tile;
+                o.fetch_dots += 5 - (px % 8).min(5) as u8;
+            }
+        }
+
+        objs
+    }
+
+    // The STAT interrupt fires on the rising edge of all of the enabled
+    // sources OR'd together, so a source becoming true while another one
+    // already holds the line high doesn't raise a second interrupt.
+    // Returns true on a rising edge.
+    fn update_stat_line(&mut self) -> bool {
+        let mode_src = match self.mode {
+            PpuMode::HBLANK => 0x08,
+            PpuMode::VBLANK => 0x10,
+            PpuMode::OAMSCAN => 0x20,
+            PpuMode::DRAW => 0,
+        };
+        let lyc_src = if self.ly == self.lyc { 0x40 } else { 0 };
+
+        let line = self.lcd_enabled() && self.stat & (mode_src | lyc_src) != 0;
+        let rising = line && !self.stat_line;
+        self.stat_line = line;
+        rising
     }
 
     fn get_stat(&self) -> u8 {
         let base = self.stat & !0x7;
-        return base | self.mode as u8 | if self.ly == self.lyc { 0x6 } else { 0 };
+        return base | self.mode as u8 | if self.ly == self.lyc { 0x4 } else { 0 };
     }
 
     fn get_lcdc_state(&self) -> Lcdc {
@@ -715,36 +1149,1242 @@ impl PPU {
             mode: self.mode,
             stat: self.stat,
             window_counter: self.window_counter,
+            vram_bank: 0,
+            bg_tile_map_addr: self.bkgr_map_start_addr(),
+            window_tile_map_addr: self.window_map_start_addr(),
+            tile_data_addr: if self.lcdc & 0x10 == 0 {
+                0x8800
+            } else {
+                0x8000
+            },
+            sprites_on_line: self.sprites_on_line,
+            dma_remaining: self.dma_remaining,
+            line_scroll: self.line_scroll,
         }
     }
 
+    // True once per frame, the first time it's called after VBLANK starts
+    pub fn take_frame_ready(&mut self) -> bool {
+        core::mem::take(&mut self.frame_ready)
+    }
+
+    pub fn lcd_enabled(&self) -> bool {
+        self.lcdc & 0x80 != 0
+    }
+
     fn large_sprites(&self) -> bool {
         self.lcdc & 0x04 != 0
     }
 }
 
+// The RGBA color each of the four shades is displayed as, lightest first
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DisplayPalette(pub [[u8; 4]; 4]);
+
+impl DisplayPalette {
+    pub const GRAYSCALE: Self = Self([
+        [0xFF, 0xFF, 0xFF, 0xFF],
+        [0xAA, 0xAA, 0xAA, 0xFF],
+        [0x55, 0x55, 0x55, 0xFF],
+        [0x00, 0x00, 0x00, 0xFF],
+    ]);
+
+    // The green tint of the original DMG screen
+    pub const DMG_GREEN: Self = Self([
+        [0x9B, 0xBC, 0x0F, 0xFF],
+        [0x8B, 0xAC, 0x0F, 0xFF],
+        [0x30, 0x62, 0x30, 0xFF],
+        [0x0F, 0x38, 0x0F, 0xFF],
+    ]);
+
+    // The more neutral screen of the Game Boy Pocket
+    pub const POCKET: Self = Self([
+        [0xC4, 0xCF, 0xA1, 0xFF],
+        [0x8B, 0x95, 0x6D, 0xFF],
+        [0x4D, 0x53, 0x3C, 0xFF],
+        [0x1F, 0x1F, 0x1F, 0xFF],
+    ]);
+
+    pub fn to_rgba(&self, ind: u8) -> [u8; 4] {
+        // Debug overlays stand out regardless of the palette
+        if ind == SPRITE_OUTLINE_COLOR {
+            return [0xFF, 0, 0, 0xFF];
+        }
+
+        self.0[(ind & 0x3) as usize]
+    }
+}
+
+impl Default for DisplayPalette {
+    fn default() -> Self {
+        Self::GRAYSCALE
+    }
+}
+
 pub struct Frame {
     pub buf: [[u8; SCREEN_WIDTH]; SCREEN_HEIGHT],
+    pub palette: DisplayPalette,
 }
 
 impl Frame {
     pub fn new() -> Self {
         Frame {
             buf: [[0; SCREEN_WIDTH]; SCREEN_HEIGHT],
+            palette: DisplayPalette::default(),
         }
     }
 
     pub fn to_rgba(&self) -> [u8; 4 * SCREEN_WIDTH * SCREEN_HEIGHT] {
         let mut pixels = [0; 4 * SCREEN_WIDTH * SCREEN_HEIGHT];
+        self.write_rgba(&mut pixels);
+        pixels
+    }
 
-        let mut frame_iter = self.buf.into_iter().flatten();
+    // Writes the frame as RGBA into the start of `dest`, stopping early if
+    // `dest` is shorter than a whole frame
+    pub fn write_rgba(&self, dest: &mut [u8]) {
+        for (one_pixel, &new_pixel) in dest.chunks_exact_mut(4).zip(self.buf.as_flattened()) {
+            one_pixel.copy_from_slice(&self.palette.to_rgba(new_pixel));
+        }
+    }
+
+    // The shade of every pixel packed four to a byte, the leftmost pixel in
+    // the top two bits, for frontends that apply the palette themselves.
+    // Sprite outlines don't fit in two bits and are packed as shade 3.
+    pub fn to_2bpp(&self) -> [u8; SCREEN_WIDTH * SCREEN_HEIGHT / 4] {
+        let mut packed = [0; SCREEN_WIDTH * SCREEN_HEIGHT / 4];
+
+        for (byte, pixels) in packed
+            .iter_mut()
+            .zip(self.buf.as_flattened().chunks_exact(4))
+        {
+            *byte = pixels.iter().fold(0, |acc, &pixel| {
+                let shade = if pixel == SPRITE_OUTLINE_COLOR {
+                    3
+                } else {
+                    pixel
+                };
+                (acc << 2) | shade
+            });
+        }
 
-        for (_, one_pixel) in pixels.chunks_exact_mut(4).enumerate() {
-            if let Some(new_pixel) = frame_iter.next() {
-                one_pixel.copy_from_slice(&PPU::palette_to_rgba(new_pixel));
+        packed
+    }
+}
+
+#[cfg(any(test, feature = "std"))]
+impl Snapshot for PPU {
+    fn save(&self, w: &mut StateWriter) {
+        w.bytes(&self.vram);
+        w.bytes(&self.oam);
+        for reg in [
+            self.lcdc, self.stat, self.scy, self.scx, self.ly, self.lyc, self.bgp, self.obp0,
+            self.obp1, self.wy, self.wx,
+        ] {
+            w.u8(reg);
+        }
+        w.bool(self.window_triggered);
+        w.u8(self.window_counter);
+        w.u8(self.mode as u8);
+        w.i32(self.r_cyc);
+        w.i32(self.hblank_cycles);
+        w.u16(self.dma_src);
+        w.u8(self.dma_remaining);
+        w.bool(self.obj_priority == ObjPriority::OamIndex);
+        w.bool(self.stat_line);
+        for line in &self.screen.buf {
+            w.bytes(line);
+        }
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.bytes(&mut self.vram)?;
+        r.bytes(&mut self.oam)?;
+        for reg in [
+            &mut self.lcdc,
+            &mut self.stat,
+            &mut self.scy,
+            &mut self.scx,
+            &mut self.ly,
+            &mut self.lyc,
+            &mut self.bgp,
+            &mut self.obp0,
+            &mut self.obp1,
+            &mut self.wy,
+            &mut self.wx,
+        ] {
+            *reg = r.u8()?;
+        }
+        self.window_triggered = r.bool()?;
+        self.window_counter = r.u8()?;
+        self.mode = match r.u8()? {
+            0 => PpuMode::HBLANK,
+            1 => PpuMode::VBLANK,
+            2 => PpuMode::OAMSCAN,
+            _ => PpuMode::DRAW,
+        };
+        self.r_cyc = r.i32()?;
+        self.hblank_cycles = r.i32()?;
+        self.dma_src = r.u16()?;
+        self.dma_remaining = r.u8()?;
+        self.obj_priority = if r.bool()? {
+            ObjPriority::OamIndex
+        } else {
+            ObjPriority::Coordinate
+        };
+        self.stat_line = r.bool()?;
+        for line in &mut self.screen.buf {
+            r.bytes(line)?;
+        }
+        // A line the FIFO was partway through isn't saved, the scanline
+        // renderer draws it instead
+        self.fifo = fifo::PixelFifo::new();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_frame(ppu: &mut PPU) {
+        for _ in 0..CYCLES_PER_SCANLINE * SCANLINES_PER_FRAME {
+            ppu.run(1);
+        }
+    }
+
+    #[test]
+    fn pixel_at() {
+        let mut ppu = PPU::new();
+
+        // Only the first line of tile 0 is set, to color 3
+        ppu.write(0x8000, 0xFF);
+        ppu.write(0x8001, 0xFF);
+        ppu.write(0xFF47, 0xE4);
+        // LCD on, BG on, tile data at 0x8000
+        ppu.write(0xFF40, 0x91);
+
+        run_frame(&mut ppu);
+
+        assert_eq!(ppu.pixel_at(0, 0), Some(3));
+        assert_eq!(ppu.pixel_at(8, 0), Some(3));
+        assert_eq!(ppu.pixel_at(0, 1), Some(0));
+        assert_eq!(ppu.pixel_at(159, 8), Some(3));
+        assert_eq!(ppu.pixel_at(SCREEN_WIDTH, 0), None);
+        assert_eq!(ppu.pixel_at(0, SCREEN_HEIGHT), None);
+    }
+
+    #[test]
+    fn bg_tilemap() {
+        let mut ppu = PPU::new();
+
+        // Tile 1 is solid color 1, tile 0x101 (tile 1 at 0x9000) is solid 2
+        for line in 0..8 {
+            ppu.write(0x8010 + line * 2, 0xFF);
+            ppu.write(0x9011 + line * 2, 0xFF);
+        }
+        ppu.write(0x9801, 1);
+        ppu.write(0x9C00, 1);
+
+        // BG map at 0x9800, tile data at 0x8000
+        ppu.write(0xFF40, 0x91);
+        let tiles: std::vec::Vec<_> = ppu
+            .bg_tilemap()
+            .map(|(i, t)| (i, t.render()[0][0]))
+            .collect();
+        assert_eq!(tiles.len(), TILE_MAP_LEN);
+        assert_eq!(&tiles[..3], [(0, 0), (1, 1), (0, 0)]);
+
+        // BG map at 0x9C00, tile data at 0x8800
+        ppu.write(0xFF40, 0x89);
+        let tiles: std::vec::Vec<_> = ppu
+            .bg_tilemap()
+            .map(|(i, t)| (i, t.render()[0][0]))
+            .collect();
+        assert_eq!(&tiles[..2], [(1, 2), (0, 0)]);
+    }
+
+    #[test]
+    fn screen_indices() {
+        let mut ppu = PPU::new();
+        ppu.screen.buf[0][..5].copy_from_slice(&[3, 2, 1, 0, 1]);
+        ppu.screen.buf[143][159] = 2;
+
+        let packed = ppu.get_screen_indices();
+        assert_eq!(packed[0], 0b11_10_01_00);
+        assert_eq!(packed[1], 0b01_00_00_00);
+        assert_eq!(packed[packed.len() - 1], 0b00_00_00_10);
+        assert!(packed[2..packed.len() - 1].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn bg_win_disable_mid_frame() {
+        let mut ppu = PPU::new();
+
+        // Tile 1 is solid color 1 and tile 2 is solid color 3. The window's
+        // first row of tiles is tile 1 and its second row is tile 2.
+        let mut vram = [0; VRAM_LEN];
+        vram[16..32].copy_from_slice(&[0xFF, 0x00].repeat(8));
+        vram[32..48].fill(0xFF);
+        vram[0x1C00..0x1C20].fill(1);
+        vram[0x1C20..0x1C40].fill(2);
+        ppu.load_vram(&vram);
+        ppu.set_oam_entry(
+            0,
+            OamEntry {
+                y: 16,
+                x: 20,
+                tile_idx: 2,
+                flags: OamFlags::new(),
+            },
+        );
+
+        ppu.write(0xFF47, 0xE4);
+        ppu.write(0xFF48, 0xE4);
+        ppu.write(0xFF4A, 0);
+        ppu.write(0xFF4B, 7);
+        // LCD on, window on with the 0x9C00 map, tile data at 0x8000,
+        // OBJ on, BG/window off
+        ppu.write(0xFF40, 0xF2);
+
+        while ppu.read(0xFF44) != 8 {
+            ppu.run(1);
+        }
+        ppu.write(0xFF40, 0xF3);
+        while !ppu.take_frame_ready() {
+            ppu.run(1);
+        }
+
+        // Blank, apart from the sprite
+        assert_eq!(ppu.pixel_at(0, 0), Some(0));
+        assert_eq!(ppu.pixel_at(12, 0), Some(3));
+        // The window's line counter kept going while it was blanked
+        assert_eq!(ppu.pixel_at(0, 8), Some(3));
+        assert_eq!(ppu.pixel_at(0, 16), Some(0));
+    }
+
+    #[test]
+    fn debug_layers() {
+        let mut ppu = PPU::new();
+
+        // Tile 1 is solid color 1, tile 2 is solid color 2. The BG is tile 1
+        // and the window is tile 2.
+        let mut vram = [0; VRAM_LEN];
+        vram[16..32].copy_from_slice(&[0xFF, 0x00].repeat(8));
+        vram[32..48].copy_from_slice(&[0x00, 0xFF].repeat(8));
+        vram[0x1800..0x1C00].fill(1);
+        vram[0x1C00..0x2000].fill(2);
+        ppu.load_vram(&vram);
+        // A solid color 2 sprite behind the BG
+        ppu.set_oam_entry(
+            0,
+            OamEntry {
+                y: 16,
+                x: 8,
+                tile_idx: 2,
+                flags: OamFlags::new().with_priority(true),
+            },
+        );
+
+        ppu.write(0xFF47, 0xE4);
+        ppu.write(0xFF4A, 0);
+        ppu.write(0xFF4B, 87);
+        // LCD on, window on with the 0x9C00 map, tile data at 0x8000,
+        // OBJ on, BG on
+        ppu.write(0xFF40, 0xF3);
+        ppu.set_debug_layers(true);
+
+        // Up to the end of line 0
+        while ppu.read(0xFF44) == 0 {
+            ppu.run(1);
+        }
+
+        let (bg, window, obj) = ppu.debug_layers();
+        assert!(bg.iter().all(|&px| px == 1));
+        assert!(window[..80].iter().all(|&px| px == 0));
+        assert!(window[80..].iter().all(|&px| px == 2));
+        assert!(obj[..8].iter().all(|&px| px == 2));
+        assert!(obj[8..].iter().all(|&px| px == 0));
+
+        // The sprite is hidden in the composited line
+        assert_eq!(ppu.pixel_at(0, 0), Some(1));
+        assert_eq!(ppu.pixel_at(80, 0), Some(2));
+    }
+
+    // Window map rows 0, 1 and 2 are tiles 1, 2 and 3, which are solid
+    // colors 1, 2 and 3. The BG is all tile 0, color 0.
+    fn window_test_ppu() -> PPU {
+        let mut vram = [0; VRAM_LEN];
+        vram[16..32].copy_from_slice(&[0xFF, 0x00].repeat(8));
+        vram[32..48].copy_from_slice(&[0x00, 0xFF].repeat(8));
+        vram[48..64].fill(0xFF);
+        for row in 0..3 {
+            let start = 0x1C00 + row * 32;
+            vram[start..start + 32].fill(row as u8 + 1);
+        }
+
+        let mut ppu = PPU::new();
+        ppu.load_vram(&vram);
+        ppu.write(0xFF47, 0xE4);
+        ppu
+    }
+
+    fn run_to_line(ppu: &mut PPU, ly: u8) {
+        while ppu.read(0xFF44) != ly {
+            ppu.run(1);
+        }
+    }
+
+    #[test]
+    fn line_scroll() {
+        let mut ppu = PPU::new();
+        ppu.write(0xFF40, 0x91);
+
+        // A wave on lines 50-59, then scrolled down for the rest of the frame
+        run_to_line(&mut ppu, 50);
+        for ly in 50..60 {
+            ppu.write(0xFF43, ly - 50);
+            run_to_line(&mut ppu, ly + 1);
+        }
+        ppu.write(0xFF43, 0);
+        ppu.write(0xFF42, 0x20);
+        run_to_line(&mut ppu, 144);
+
+        let scroll = ppu.get_ppu_state().line_scroll;
+        assert_eq!(scroll[49], (0, 0));
+        for ly in 50..60 {
+            assert_eq!(scroll[ly], (ly as u8 - 50, 0));
+        }
+        assert_eq!(scroll[60], (0, 0x20));
+        assert_eq!(scroll[143], (0, 0x20));
+    }
+
+    #[test]
+    fn window_full_width() {
+        let mut ppu = window_test_ppu();
+        ppu.write(0xFF4A, 0);
+        ppu.write(0xFF4B, 7);
+        // LCD on, window on with the 0x9C00 map, BG on, tile data at 0x8000
+        ppu.write(0xFF40, 0xF1);
+
+        run_to_line(&mut ppu, 20);
+        assert_eq!(ppu.window_line(), 20);
+        for y in 0..20 {
+            let row_color = y as u8 / 8 + 1;
+            assert!(ppu.screen.buf[y].iter().all(|&px| px == row_color));
+        }
+    }
+
+    #[test]
+    fn window_from_line_100() {
+        let mut ppu = window_test_ppu();
+        ppu.write(0xFF4A, 100);
+        ppu.write(0xFF4B, 7);
+        ppu.write(0xFF40, 0xF1);
+
+        run_to_line(&mut ppu, 100);
+        assert_eq!(ppu.window_line(), 0);
+        assert_eq!(ppu.pixel_at(0, 99), Some(0));
+
+        // Moving WY away once the window has started doesn't stop it
+        run_to_line(&mut ppu, 101);
+        ppu.write(0xFF4A, 0);
+        run_to_line(&mut ppu, 110);
+        assert_eq!(ppu.window_line(), 10);
+        assert_eq!(ppu.pixel_at(0, 100), Some(1));
+        assert_eq!(ppu.pixel_at(0, 109), Some(2));
+
+        // Everything starts over the next frame
+        run_to_line(&mut ppu, 0);
+        assert_eq!(ppu.window_line(), 0);
+    }
+
+    #[test]
+    fn window_paused_while_disabled() {
+        let mut ppu = window_test_ppu();
+        ppu.write(0xFF4A, 0);
+        ppu.write(0xFF4B, 7);
+        ppu.write(0xFF40, 0xF1);
+
+        // Window off for lines 4..12
+        run_to_line(&mut ppu, 4);
+        ppu.write(0xFF40, 0xD1);
+        run_to_line(&mut ppu, 12);
+        assert_eq!(ppu.window_line(), 4);
+        ppu.write(0xFF40, 0xF1);
+
+        // And moved off screen for lines 14..16
+        run_to_line(&mut ppu, 14);
+        ppu.write(0xFF4B, 167);
+        run_to_line(&mut ppu, 16);
+        ppu.write(0xFF4B, 7);
+        run_to_line(&mut ppu, 20);
+
+        assert_eq!(ppu.window_line(), 10);
+        assert_eq!(ppu.pixel_at(0, 11), Some(0));
+        // Line 12 picks up at window line 4
+        assert_eq!(ppu.pixel_at(0, 12), Some(1));
+        assert_eq!(ppu.pixel_at(0, 15), Some(0));
+        // Window line 8 is the second row of tiles
+        assert_eq!(ppu.pixel_at(0, 18), Some(2));
+    }
+
+    #[test]
+    fn oam_bug() {
+        let mut oam = [0; OAM_LEN];
+        for (i, b) in oam.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+
+        let mut ppu = PPU::new();
+        ppu.load_oam(&oam);
+        ppu.write(0xFF40, 0x80);
+        ppu.run(5);
+
+        // Off by default
+        ppu.oam_bug_write(0xFE00);
+        assert_eq!(ppu.oam, oam);
+
+        ppu.set_oam_bug(true);
+        ppu.oam_bug_write(0xFE10);
+        // Row 5 is being scanned, row 4 is bytes 32..40
+        let (a, b, c) = (0x2928_u16, 0x2120_u16, 0x2524_u16);
+        let first = (((a ^ c) & (b ^ c)) ^ c).to_le_bytes();
+        assert_eq!(ppu.oam[40..42], first);
+        assert_eq!(ppu.oam[42..48], oam[34..40]);
+        assert_eq!(ppu.oam[..40], oam[..40]);
+        assert_eq!(ppu.oam[48..], oam[48..]);
+
+        // Outside of OAM scan nothing happens
+        ppu.run(OAMSCAN_CYCLES);
+        let before = ppu.oam;
+        ppu.oam_bug_write(0xFE10);
+        assert_eq!(ppu.oam, before);
+    }
+
+    #[test]
+    fn render_disabled() {
+        let setup = |ppu: &mut PPU| {
+            ppu.write(0x8000, 0xFF);
+            ppu.write(0xFF47, 0xE4);
+            ppu.write(0xFF4A, 0x10);
+            ppu.write(0xFF4B, 0x07);
+            // LCD on, window on, BG on, tile data at 0x8000
+            ppu.write(0xFF40, 0xB1);
+        };
+
+        let mut drawn = PPU::new();
+        setup(&mut drawn);
+        let mut skipped = PPU::new();
+        setup(&mut skipped);
+        skipped.set_render_enabled(false);
+
+        let mut ints = 0;
+        for _ in 0..CYCLES_PER_SCANLINE * SCREEN_HEIGHT as i32 {
+            drawn.run(1);
+            if skipped.run(1).is_some() {
+                ints += 1;
             }
+            assert_eq!(skipped.window_line(), drawn.window_line());
         }
 
-        pixels
+        // Still got to VBLANK, just without drawing anything
+        assert_eq!(ints, 1);
+        assert!(skipped.take_frame_ready());
+        assert!(skipped.window_line() > 0);
+        assert_eq!(drawn.pixel_at(0, 0), Some(1));
+        assert_eq!(skipped.frame_hash(), PPU::new().frame_hash());
+    }
+
+    #[test]
+    fn frame_hash() {
+        let mut ppu = PPU::new();
+        let blank = ppu.frame_hash();
+        assert_eq!(blank, PPU::new().frame_hash());
+
+        ppu.screen.buf[143][159] = 1;
+        let one_pixel = ppu.frame_hash();
+        assert_ne!(one_pixel, blank);
+
+        ppu.screen.buf[143][159] = 2;
+        assert_ne!(ppu.frame_hash(), one_pixel);
+    }
+
+    #[test]
+    fn sprite_debug_outline() {
+        let mut ppu = PPU::new();
+        ppu.set_sprite_debug_outline(true);
+
+        // Sprite 0 at screen (16, 8), using the blank tile 0
+        ppu.write(0xFE00, 8 + 16);
+        ppu.write(0xFE01, 16 + 8);
+        // LCD on, BG on, OBJ on, tile data at 0x8000
+        ppu.write(0xFF40, 0x93);
+
+        run_frame(&mut ppu);
+
+        // Top and bottom edges
+        for x in 16..24 {
+            assert_eq!(ppu.pixel_at(x, 8), Some(SPRITE_OUTLINE_COLOR));
+            assert_eq!(ppu.pixel_at(x, 15), Some(SPRITE_OUTLINE_COLOR));
+        }
+        // Left and right edges
+        for y in 8..16 {
+            assert_eq!(ppu.pixel_at(16, y), Some(SPRITE_OUTLINE_COLOR));
+            assert_eq!(ppu.pixel_at(23, y), Some(SPRITE_OUTLINE_COLOR));
+        }
+        // Inside and outside are untouched
+        assert_eq!(ppu.pixel_at(19, 11), Some(0));
+        assert_eq!(ppu.pixel_at(15, 8), Some(0));
+        assert_eq!(ppu.pixel_at(16, 16), Some(0));
+        assert_eq!(
+            ppu.palette_to_rgba(SPRITE_OUTLINE_COLOR),
+            [0xFF, 0, 0, 0xFF]
+        );
+
+        // The packed shades show the outline as shade 3
+        let packed = ppu.get_screen_indices();
+        let byte_at = |x: usize, y: usize| packed[(y * SCREEN_WIDTH + x) / 4];
+        assert_eq!(byte_at(16, 8), 0xFF);
+        assert_eq!(byte_at(20, 8), 0xFF);
+        assert_eq!(byte_at(16, 11), 0b11_00_00_00);
+        assert_eq!(byte_at(20, 11), 0b00_00_00_11);
+        assert_eq!(byte_at(12, 8), 0);
+    }
+
+    #[test]
+    fn display_palette() {
+        let mut ppu = PPU::new();
+
+        // The first line of tile 0 is color 3, the rest is color 0
+        ppu.write(0x8000, 0xFF);
+        ppu.write(0x8001, 0xFF);
+        ppu.write(0xFF47, 0xE4);
+        ppu.write(0xFF40, 0x91);
+        run_frame(&mut ppu);
+
+        let screen = ppu.get_screen();
+        assert_eq!(screen[..4], [0, 0, 0, 0xFF]);
+        assert_eq!(ppu.palette_to_rgba(1), [0xAA, 0xAA, 0xAA, 0xFF]);
+
+        ppu.set_display_palette(DisplayPalette::DMG_GREEN);
+        let screen = ppu.get_screen();
+        assert_eq!(screen[..4], DisplayPalette::DMG_GREEN.0[3]);
+        let line_1 = 4 * SCREEN_WIDTH;
+        assert_eq!(screen[line_1..line_1 + 4], DisplayPalette::DMG_GREEN.0[0]);
+    }
+
+    #[test]
+    fn write_rgba_into() {
+        let mut ppu = PPU::new();
+        ppu.write(0x8000, 0xFF);
+        ppu.write(0xFF47, 0xE4);
+        ppu.write(0xFF40, 0x91);
+        run_frame(&mut ppu);
+
+        let mut buf = std::vec![0xEE; 4 * SCREEN_WIDTH * SCREEN_HEIGHT + 4];
+        ppu.write_rgba_into(&mut buf);
+        assert_eq!(buf[..buf.len() - 4], ppu.get_screen());
+        assert_eq!(buf[buf.len() - 4..], [0xEE; 4]);
+
+        // A short buffer gets the start of the frame
+        let mut short = [0; 8];
+        ppu.write_rgba_into(&mut short);
+        assert_eq!(short[..], ppu.get_screen()[..8]);
+    }
+
+    #[test]
+    fn bg_scx_wrap() {
+        let mut ppu = PPU::new();
+
+        // Distinct first lines for the tiles at both edges of the map
+        let tiles: [(u8, u8); 3] = [(0xFF, 0xF0), (0x00, 0xFF), (0xAA, 0x00)];
+        for (i, (lo, hi)) in tiles.into_iter().enumerate() {
+            ppu.write(0x8010 + i as u16 * 16, lo);
+            ppu.write(0x8011 + i as u16 * 16, hi);
+        }
+        ppu.write(0x9800 + 31, 1);
+        ppu.write(0x9800, 2);
+        ppu.write(0x9801, 3);
+        ppu.write(0xFF47, 0xE4);
+        ppu.write(0xFF40, 0x91);
+
+        let mut map_line = [0; BKG_WIDTH];
+        map_line[248..].copy_from_slice(&[3, 3, 3, 3, 1, 1, 1, 1]);
+        map_line[..8].copy_from_slice(&[2; 8]);
+        map_line[8..16].copy_from_slice(&[1, 0, 1, 0, 1, 0, 1, 0]);
+
+        // Including SCX = 7, 8 and 255, where a tile straddles the wrap
+        for scx in 0..=255 {
+            ppu.write(0xFF43, scx);
+            ppu.render_bg2();
+
+            let expected: [u8; SCREEN_WIDTH] =
+                core::array::from_fn(|x| map_line[(x + scx as usize) % BKG_WIDTH]);
+            assert_eq!(ppu.screen.buf[0], expected, "SCX = {}", scx);
+        }
+
+        ppu.write(0xFF43, 255);
+        ppu.render_bg2();
+        assert_eq!(ppu.screen.buf[0][..10], [1, 2, 2, 2, 2, 2, 2, 2, 2, 1]);
+    }
+
+    #[test]
+    fn ppu_state_tile_maps() {
+        let mut ppu = PPU::new();
+
+        ppu.write(0xFF40, 0x80);
+        let state = ppu.get_ppu_state();
+        assert_eq!(state.bg_tile_map_addr, 0x9800);
+        assert_eq!(state.window_tile_map_addr, 0x9800);
+        assert_eq!(state.tile_data_addr, 0x8800);
+
+        ppu.write(0xFF40, 0x80 | 0x40 | 0x10 | 0x08);
+        let state = ppu.get_ppu_state();
+        assert_eq!(state.bg_tile_map_addr, 0x9C00);
+        assert_eq!(state.window_tile_map_addr, 0x9C00);
+        assert_eq!(state.tile_data_addr, 0x8000);
+        assert_eq!(state.vram_bank, 0);
+    }
+
+    #[test]
+    fn load_vram() {
+        let mut ppu = PPU::new();
+
+        // Tile 1's first line alternates colors 1 and 2,
+        // and the top left of the tile map uses it
+        let mut vram = [0; VRAM_LEN];
+        vram[16] = 0xAA;
+        vram[17] = 0x55;
+        vram[0x1800] = 1;
+        ppu.load_vram(&vram);
+
+        let mut oam = [0; OAM_LEN];
+        oam[0] = 16;
+        oam[1] = 8 + 8;
+        oam[2] = 1;
+        ppu.load_oam(&oam);
+
+        ppu.write(0xFF47, 0xE4);
+        ppu.write(0xFF48, 0xE4);
+        ppu.write(0xFF40, 0x93);
+        run_frame(&mut ppu);
+
+        // The tile at (0, 0) from the background, and at (8, 0) from the sprite
+        let expected = [1, 2, 1, 2, 1, 2, 1, 2];
+        assert_eq!(ppu.screen.buf[0][..8], expected);
+        assert_eq!(ppu.screen.buf[0][8..16], expected);
+        assert_eq!(ppu.screen.buf[0][16..24], [0; 8]);
+        assert_eq!(ppu.read(0x8010), 0xAA);
+        assert_eq!(ppu.read(0xFE02), 1);
+    }
+
+    #[test]
+    fn obj_priority() {
+        let mut ppu = PPU::new();
+
+        // Tile 1 is solid color 1, tile 2 is solid color 2
+        for line in 0..8 {
+            ppu.write(0x8010 + line * 2, 0xFF);
+            ppu.write(0x8020 + line * 2 + 1, 0xFF);
+        }
+
+        // Sprite 0 (tile 1) overlaps sprite 1 (tile 2), which is further left
+        ppu.load_oam(&[16, 8 + 4, 1, 0, 16, 8, 2, 0]);
+        ppu.write(0xFF48, 0xE4);
+        ppu.write(0xFF40, 0x93);
+
+        // DMG (and DMG compatibility): the lower X coordinate wins
+        run_frame(&mut ppu);
+        assert_eq!(ppu.screen.buf[0][4..12], [2, 2, 2, 2, 1, 1, 1, 1]);
+
+        // Native CGB: the lower OAM index wins
+        ppu.set_obj_priority(ObjPriority::OamIndex);
+        run_frame(&mut ppu);
+        assert_eq!(
+            ppu.screen.buf[0][..12],
+            [2, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1]
+        );
+    }
+
+    #[test]
+    fn set_window_line() {
+        let mut ppu = PPU::new();
+
+        // Tile 1 is color 1 on line 0 and color 3 on line 5,
+        // and the window is made entirely of it
+        let mut vram = [1; VRAM_LEN];
+        vram[..0x1800].fill(0);
+        vram[16] = 0xFF;
+        vram[16 + 10] = 0xFF;
+        vram[16 + 11] = 0xFF;
+        ppu.load_vram(&vram);
+
+        ppu.write(0xFF47, 0xE4);
+        ppu.write(0xFF4A, 0);
+        ppu.write(0xFF4B, 7);
+        // LCD on, window on, BG/window on, tile data at 0x8000
+        ppu.write(0xFF40, 0xB1);
+
+        run_frame(&mut ppu);
+        assert_eq!(ppu.pixel_at(0, 0), Some(1));
+        assert_eq!(ppu.window_line(), 0);
+
+        // Restore the counter at the start of the frame
+        ppu.set_window_line(5);
+        for _ in 0..CYCLES_PER_SCANLINE {
+            ppu.run(1);
+        }
+
+        assert_eq!(ppu.pixel_at(0, 0), Some(3));
+        assert_eq!(ppu.window_line(), 6);
+    }
+
+    #[test]
+    fn window_line_wraps() {
+        for mode in [RenderMode::Scanline, RenderMode::Fifo] {
+            let mut ppu = PPU::new();
+            ppu.set_render_mode(mode);
+
+            // Tile 1 is color 1 on line 0, and the window is made entirely of it
+            let mut vram = [1; VRAM_LEN];
+            vram[..0x1800].fill(0);
+            vram[16] = 0xFF;
+            ppu.load_vram(&vram);
+
+            ppu.write(0xFF47, 0xE4);
+            ppu.write(0xFF4A, 0);
+            ppu.write(0xFF4B, 7);
+            ppu.write(0xFF40, 0xB1);
+            run_frame(&mut ppu);
+
+            // Line 255 is the last line of the tile map, and the next is line 0
+            ppu.set_window_line(255);
+            for _ in 0..CYCLES_PER_SCANLINE {
+                ppu.run(1);
+            }
+            assert_eq!(ppu.pixel_at(0, 0), Some(0));
+            assert_eq!(ppu.window_line(), 0);
+
+            for _ in 0..CYCLES_PER_SCANLINE {
+                ppu.run(1);
+            }
+            assert_eq!(ppu.pixel_at(0, 1), Some(1));
+            assert_eq!(ppu.window_line(), 1);
+        }
+    }
+
+    #[test]
+    fn equal_x_sprites() {
+        let mut ppu = PPU::new();
+
+        // Tile 1 is solid color 1, tile 2 is solid color 2
+        for line in 0..8 {
+            ppu.write(0x8010 + line * 2, 0xFF);
+            ppu.write(0x8020 + line * 2 + 1, 0xFF);
+        }
+
+        ppu.write(0xFF48, 0xE4);
+        ppu.write(0xFF40, 0x93);
+
+        // Sprites with the same X, the one earlier in OAM is on top
+        ppu.load_oam(&[16, 8, 1, 0, 16, 8, 2, 0]);
+        run_frame(&mut ppu);
+        assert_eq!(ppu.screen.buf[0][..8], [1; 8]);
+
+        ppu.load_oam(&[16, 8, 2, 0, 16, 8, 1, 0]);
+        run_frame(&mut ppu);
+        assert_eq!(ppu.screen.buf[0][..8], [2; 8]);
+    }
+
+    #[test]
+    fn set_oam_entry() {
+        let mut ppu = PPU::new();
+
+        // The left half of the first line of tile 1 is color 1
+        ppu.write(0x8010, 0xF0);
+        ppu.write(0xFF48, 0xE4);
+        ppu.write(0xFF40, 0x93);
+
+        // Mirrored, on screen line 10 starting at x = 20
+        let entry = OamEntry {
+            y: 16 + 10,
+            x: 8 + 20,
+            tile_idx: 1,
+            flags: OamFlags::new().with_x_flip(true),
+        };
+        ppu.set_oam_entry(5, entry);
+        assert_eq!(ppu.read(0xFE14), 26);
+        assert_eq!(ppu.read(0xFE17), 0x20);
+
+        run_frame(&mut ppu);
+        assert_eq!(ppu.screen.buf[10][20..28], [0, 0, 0, 0, 1, 1, 1, 1]);
+        assert_eq!(ppu.screen.buf[9][20..28], [0; 8]);
+        assert_eq!(ppu.screen.buf[11][20..28], [0; 8]);
+    }
+
+    #[test]
+    fn lyc_on_lcd_enable() {
+        let mut ppu = PPU::new();
+        ppu.write(0xFF40, 0x91);
+        for _ in 0..CYCLES_PER_SCANLINE * 3 {
+            ppu.run(1);
+        }
+        assert_eq!(ppu.read(0xFF44), 3);
+
+        // LY is held at 0 while the LCD is off
+        ppu.write(0xFF40, 0x11);
+        assert_eq!(ppu.read(0xFF44), 0);
+        ppu.run(CYCLES_PER_SCANLINE * 2);
+        assert_eq!(ppu.read(0xFF44), 0);
+
+        ppu.write(0xFF45, 0);
+        ppu.write(0xFF41, 0x40);
+        assert_eq!(ppu.take_interrupt(), None);
+
+        ppu.write(0xFF40, 0x91);
+        assert_eq!(ppu.read(0xFF41) & 0x4, 0x4);
+        assert_eq!(ppu.take_interrupt(), Some(IntSource::LCD));
+        assert_eq!(ppu.take_interrupt(), None);
+    }
+
+    #[test]
+    fn lcd_disable_blanks_screen() {
+        let mut ppu = PPU::new();
+        ppu.write(0x8000, 0xFF);
+        ppu.write(0x8001, 0xFF);
+        ppu.write(0xFF47, 0xE4);
+        ppu.write(0xFF40, 0x91);
+        run_frame(&mut ppu);
+        assert_eq!(ppu.pixel_at(0, 0), Some(3));
+
+        // Nothing advances while the LCD is off, and STAT reads HBLANK
+        ppu.write(0xFF45, 1);
+        ppu.write(0xFF41, 0x08);
+        ppu.write(0xFF40, 0x11);
+        assert_eq!(ppu.pixel_at(0, 0), Some(0));
+        assert_eq!(ppu.run(CYCLES_PER_SCANLINE * SCANLINES_PER_FRAME), None);
+        assert_eq!(ppu.read(0xFF44), 0);
+        assert_eq!(ppu.read(0xFF41) & 0x3, PpuMode::HBLANK as u8);
+
+        ppu.write(0xFF40, 0x91);
+        assert_eq!(ppu.read(0xFF41) & 0x3, PpuMode::OAMSCAN as u8);
+    }
+
+    #[test]
+    fn stat_irq_line() {
+        let mut ppu = PPU::new();
+        ppu.write(0xFF45, 0x10);
+        ppu.write(0xFF40, 0x91);
+        assert_eq!(ppu.take_interrupt(), None);
+
+        // HBLANK and OAMSCAN back to back hold the line high, so there's
+        // one interrupt per line rather than two. The first line's OAMSCAN
+        // is already underway, so enabling it raises the line right away.
+        ppu.write(0xFF41, 0x28);
+        assert_eq!(ppu.take_interrupt(), Some(IntSource::LCD));
+        let mut ints = 0;
+        for _ in 0..CYCLES_PER_SCANLINE * 4 {
+            if ppu.run(1) == Some(IntSource::LCD) {
+                ints += 1;
+            }
+        }
+        assert_eq!(ints, 4);
+
+        // Enabling a source that's already true raises the line right away
+        ppu.write(0xFF41, 0x00);
+        ppu.write(0xFF45, ppu.read(0xFF44));
+        assert_eq!(ppu.take_interrupt(), None);
+        ppu.write(0xFF41, 0x40);
+        assert_eq!(ppu.take_interrupt(), Some(IntSource::LCD));
+    }
+
+    #[test]
+    fn lyc_in_vblank() {
+        let mut ppu = PPU::new();
+        ppu.write(0xFF45, 150);
+        ppu.write(0xFF41, 0x40);
+        ppu.write(0xFF40, 0x91);
+
+        let mut lcd_lines = std::vec::Vec::new();
+        for _ in 0..CYCLES_PER_SCANLINE * SCANLINES_PER_FRAME {
+            if ppu.run(1) == Some(IntSource::LCD) {
+                lcd_lines.push(ppu.read(0xFF44));
+                assert_eq!(ppu.read(0xFF41) & 0x7, 0x4 | PpuMode::VBLANK as u8);
+            }
+        }
+        assert_eq!(lcd_lines, [150]);
+    }
+
+    #[test]
+    fn vblank_lines() {
+        use crate::gb::CYCLES_PER_FRAME;
+
+        // Tile 0 is solid color 1
+        let mut ppu = PPU::new();
+        for addr in (0x8000..0x8010).step_by(2) {
+            ppu.write(addr, 0xFF);
+        }
+        ppu.write(0xFF47, 0xE4);
+        ppu.write(0xFF40, 0x91);
+
+        let mut vblanks = std::vec::Vec::new();
+        let mut modes = [[false; 4]; SCANLINES_PER_FRAME as usize];
+        for cycle in 0..CYCLES_PER_FRAME * 2 {
+            let ly = ppu.read(0xFF44) as usize;
+            modes[ly][(ppu.read(0xFF41) & 0x3) as usize] = true;
+            if ppu.run(1) == Some(IntSource::VBLANK) {
+                assert_eq!(ppu.read(0xFF44), 144);
+                vblanks.push(cycle);
+            }
+        }
+
+        // Lines 0-143 go through DRAW, 144-153 are all VBLANK
+        for (ly, seen) in modes.iter().enumerate() {
+            let visible = ly < SCREEN_HEIGHT;
+            assert_eq!(seen[PpuMode::DRAW as usize], visible, "line {ly}");
+            assert_eq!(seen[PpuMode::VBLANK as usize], !visible, "line {ly}");
+        }
+        assert_eq!(ppu.pixel_at(0, SCREEN_HEIGHT - 1), Some(1));
+
+        assert_eq!(vblanks.len(), 2);
+        assert_eq!(vblanks[0] + 1, CYCLES_PER_SCANLINE * SCREEN_HEIGHT as i32);
+        assert_eq!(vblanks[1] - vblanks[0], CYCLES_PER_FRAME);
+    }
+
+    #[test]
+    fn window_map_and_palette() {
+        let mut ppu = PPU::new();
+
+        // Tile 1 is solid color 1, and only the 0x9C00 map uses it
+        let mut vram = [0; VRAM_LEN];
+        vram[16..32].copy_from_slice(&[0xFF, 0x00].repeat(8));
+        vram[0x1C00..0x2000].fill(1);
+        ppu.load_vram(&vram);
+
+        // Color 1 shows as shade 2
+        ppu.write(0xFF47, 0x08);
+        ppu.write(0xFF4A, 0);
+        ppu.write(0xFF4B, 7);
+        // LCD on, window on with the 0x9C00 map, BG/window on, tile data at 0x8000
+        ppu.write(0xFF40, 0xF1);
+
+        run_frame(&mut ppu);
+        assert_eq!(ppu.pixel_at(0, 0), Some(2));
+        assert_eq!(ppu.render_window()[0][0], 2);
+        assert_eq!(ppu.render_bg()[0][0], 0);
+    }
+
+    #[test]
+    fn draw_timing() {
+        // M-cycles spent in DRAW and HBLANK on line 0
+        fn line_timing(scx: u8, sprite_xs: &[u8]) -> (i32, i32) {
+            let mut ppu = PPU::new();
+            for (i, &x) in sprite_xs.iter().enumerate() {
+                ppu.set_oam_entry(
+                    i,
+                    OamEntry {
+                        y: 16,
+                        x,
+                        tile_idx: 0,
+                        flags: OamFlags::new(),
+                    },
+                );
+            }
+            ppu.write(0xFF43, scx);
+            // LCD on, OBJ on, BG on
+            ppu.write(0xFF40, 0x83);
+
+            let (mut draw, mut hblank) = (0, 0);
+            while ppu.read(0xFF44) == 0 {
+                match ppu.mode {
+                    PpuMode::DRAW => draw += 1,
+                    PpuMode::HBLANK => hblank += 1,
+                    _ => {}
+                }
+                ppu.run(1);
+            }
+            (draw, hblank)
+        }
+
+        assert_eq!(line_timing(0, &[]), (DRAW_CYCLES, HBLANK_CYCLES));
+        // 3 dots of fine scroll
+        assert_eq!(line_timing(3, &[]), (44, 50));
+        // 6 dots for the sprite, 5 waiting on its BG tile
+        assert_eq!(line_timing(0, &[8]), (46, 48));
+        // The second sprite shares the first one's BG tile
+        assert_eq!(line_timing(0, &[8, 9]), (48, 46));
+        // Sprites past the right edge aren't fetched
+        assert_eq!(line_timing(0, &[168]), (DRAW_CYCLES, HBLANK_CYCLES));
+    }
+
+    #[test]
+    fn sprites_clipped_at_edges() {
+        // Tile 1 is solid color 3
+        let mut vram = [0; VRAM_LEN];
+        vram[16..32].fill(0xFF);
+
+        for x in (1..=7).chain(160..=167) {
+            let mut ppu = PPU::new();
+            ppu.load_vram(&vram);
+            ppu.write(0xFF48, 0xE4);
+            ppu.set_oam_entry(
+                0,
+                OamEntry {
+                    y: 16,
+                    x,
+                    tile_idx: 1,
+                    flags: OamFlags::new(),
+                },
+            );
+            // LCD on, OBJ on, BG off
+            ppu.write(0xFF40, 0x82);
+            run_frame(&mut ppu);
+
+            let line = &ppu.screen.buf[0];
+            let visible = line.iter().filter(|&&px| px == 3).count();
+            let expected = (x as usize).min(8).min(168 - x as usize);
+            assert_eq!(visible, expected, "sprite at x = {x}");
+
+            if x < 8 {
+                assert!(line[..x as usize].iter().all(|&px| px == 3));
+            } else {
+                assert!(line[x as usize - 8..].iter().all(|&px| px == 3));
+            }
+        }
+    }
+
+    #[test]
+    fn sprite_bg_priority() {
+        // Tile 1 is color 1 on the left half and color 0 on the right,
+        // tile 2 is solid color 3
+        let mut vram = [0; VRAM_LEN];
+        vram[16..32].iter_mut().step_by(2).for_each(|b| *b = 0xF0);
+        vram[32..48].fill(0xFF);
+        // The BG is all tile 1
+        vram[0x1800..0x1C00].fill(1);
+
+        let mut ppu = PPU::new();
+        ppu.load_vram(&vram);
+        // BG colors 0 and 1 are swapped, the sprite's color 3 is shade 2
+        ppu.write(0xFF47, 0xE1);
+        ppu.write(0xFF48, 0x80);
+        for (i, priority) in [false, true].into_iter().enumerate() {
+            ppu.set_oam_entry(
+                i,
+                OamEntry {
+                    y: 16 + 8 * i as u8,
+                    x: 8,
+                    tile_idx: 2,
+                    flags: OamFlags::new().with_priority(priority),
+                },
+            );
+        }
+        // LCD on, OBJ on, BG on, tile data at 0x8000
+        ppu.write(0xFF40, 0x93);
+        run_frame(&mut ppu);
+
+        // In front of the BG the whole sprite shows
+        assert_eq!(ppu.screen.buf[0][..8], [2; 8]);
+        // Behind it only BG color 0 is covered, even though it's shade 1
+        assert_eq!(ppu.screen.buf[8][..8], [0, 0, 0, 0, 2, 2, 2, 2]);
+        assert_eq!(ppu.screen.buf[8][8..16], [0, 0, 0, 0, 1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn render_tile() {
+        // The top row of tile 300 is color 1, the rest color 0
+        let mut ppu = PPU::new();
+        ppu.write(0x8000 + 300 * 16, 0xFF);
+        ppu.write(0xFF47, 0xE4);
+        ppu.write(0xFF48, 0x1B);
+        ppu.write(0xFF49, 0x04);
+
+        let tile = ppu.render_tile(300, ppu.bgp());
+        assert_eq!(tile[0], [1; 8]);
+        assert_eq!(tile[1], [0; 8]);
+
+        let tile = ppu.render_tile(300, ppu.obp0());
+        assert_eq!(tile[0], [2; 8]);
+        assert_eq!(tile[1], [3; 8]);
+
+        let tile = ppu.render_tile(300, ppu.obp1());
+        assert_eq!(tile[0], [1; 8]);
+        assert_eq!(tile[1], [0; 8]);
+    }
+
+    #[test]
+    fn fifo_matches_scanline() {
+        let frame = |mode| {
+            let mut ppu = window_test_ppu();
+            ppu.set_render_mode(mode);
+            for i in 0..4 {
+                ppu.set_oam_entry(
+                    i,
+                    OamEntry {
+                        y: 16 + 12 * i as u8,
+                        x: 3 + 30 * i as u8,
+                        tile_idx: 3,
+                        flags: OamFlags::new().with_dmg_palette(i % 2 == 1),
+                    },
+                );
+            }
+            ppu.write(0xFF43, 5);
+            ppu.write(0xFF42, 3);
+            ppu.write(0xFF4A, 40);
+            ppu.write(0xFF4B, 60);
+            ppu.write(0xFF48, 0x1B);
+            // LCD on, window on with the 0x9C00 map, OBJ on, BG on
+            ppu.write(0xFF40, 0xF3);
+            run_frame(&mut ppu);
+            ppu.screen.buf
+        };
+
+        assert_eq!(frame(RenderMode::Fifo), frame(RenderMode::Scanline));
+    }
+
+    #[test]
+    fn fifo_load_mid_line() {
+        let mut ppu = window_test_ppu();
+        ppu.set_render_mode(RenderMode::Fifo);
+        ppu.write(0xFF40, 0x99);
+
+        run_to_line(&mut ppu, 150);
+        let mut w = StateWriter::new();
+        ppu.save(&mut w);
+        let state = w.finish();
+
+        run_to_line(&mut ppu, 10);
+        while ppu.read(0xFF41) & 0x3 != 3 {
+            ppu.run(1);
+        }
+        ppu.run(10);
+        ppu.load(&mut StateReader::new(&state)).unwrap();
+
+        // Back in VBLANK with nothing left over from the line being drawn
+        run_to_line(&mut ppu, 2);
+        assert!(ppu.screen.buf[1].iter().all(|&px| px == 1));
+    }
+
+    #[test]
+    fn fifo_mid_line_palette() {
+        let mut ppu = window_test_ppu();
+        ppu.set_render_mode(RenderMode::Fifo);
+        // LCD on, BG with the 0x9C00 map, tile data at 0x8000
+        ppu.write(0xFF40, 0x99);
+
+        run_to_line(&mut ppu, 2);
+        while ppu.read(0xFF41) & 0x3 != 3 {
+            ppu.run(1);
+        }
+        ppu.run(20);
+        ppu.write(0xFF47, 0x1B);
+        run_to_line(&mut ppu, 3);
+
+        // The left of the line was drawn with the old palette
+        let line = ppu.screen.buf[2];
+        let split = line.iter().position(|&px| px != 1).unwrap();
+        assert!(split > 0 && split < SCREEN_WIDTH);
+        assert!(line[split..].iter().all(|&px| px == 2));
     }
 }
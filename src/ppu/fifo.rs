@@ -0,0 +1,286 @@
+// The pixel FIFO renderer, see RenderMode::Fifo.
+//
+// During DRAW the BG/window FIFO shifts out one pixel per dot, refilled
+// eight pixels at a time by a fetcher, and sprites are fetched into a
+// second FIFO as the line reaches them. SCX, SCY and the tile maps are
+// read when a tile is fetched, and LCDC's enable bits and the palettes
+// when each pixel comes out, so writes partway through DRAW land partway
+// through the line.
+//
+// The fetcher itself isn't stepped, fetches are instant. Instead the
+// FIFO stalls for the same startup and sprite fetch delays draw_cycles()
+// adds, so the last pixel comes out as DRAW ends.
+
+use heapless::{Deque, Vec};
+use zerocopy::FromBytes;
+
+use super::tile::{self, OamEntry, Palette};
+use super::{PPU, SCREEN_WIDTH, SPRITE_OUTLINE_COLOR};
+
+// Dots from the start of DRAW until the first pixel comes out, 172 dots
+// less the 160 pixels
+const STARTUP_DOTS: u8 = 12;
+
+// Where a sprite is on screen, and how long fetching it takes. `rank` is
+// its priority, lower wins.
+#[derive(Clone, Copy)]
+pub(super) struct LineObj {
+    pub obj: OamEntry,
+    pub rank: u8,
+    pub fetch_dots: u8,
+}
+
+#[derive(Clone, Copy)]
+struct ObjPixel {
+    color: u8,
+    obp1: bool,
+    behind_bg: bool,
+    rank: u8,
+    outline: bool,
+}
+
+impl ObjPixel {
+    const EMPTY: Self = Self {
+        color: 0,
+        obp1: false,
+        behind_bg: false,
+        rank: u8::MAX,
+        outline: false,
+    };
+}
+
+pub(super) struct PixelFifo {
+    // Set from the start of DRAW until the line is finished
+    pub active: bool,
+    // BG or window color IDs waiting to be shifted out
+    bg: Deque<u8, 16>,
+    // Sprite pixels, lined up with the front of `bg`
+    obj: [ObjPixel; 8],
+    // The next screen column
+    lx: u8,
+    // Pixels to throw away before the next one is drawn, for SCX's fine
+    // scroll or a WX below 7
+    discard: u8,
+    // Dots left before the FIFO moves again
+    stall: u8,
+    // The next tile column the fetcher reads
+    fetch_x: u8,
+    // The fetcher switched to the window on this line
+    window: bool,
+    // This line's sprites, left to right
+    objs: Vec<LineObj, 10>,
+    next_obj: usize,
+}
+
+impl PixelFifo {
+    pub fn new() -> Self {
+        Self {
+            active: false,
+            bg: Deque::new(),
+            obj: [ObjPixel::EMPTY; 8],
+            lx: 0,
+            discard: 0,
+            stall: 0,
+            fetch_x: 0,
+            window: false,
+            objs: Vec::new(),
+            next_obj: 0,
+        }
+    }
+
+    fn done(&self) -> bool {
+        self.lx as usize >= SCREEN_WIDTH
+    }
+}
+
+impl Default for PixelFifo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PPU {
+    // Sets the FIFO up for the line at the start of DRAW
+    pub(super) fn fifo_start_line(&mut self) {
+        let objs = self.line_objs();
+        self.sprites_on_line = objs.len() as u8;
+        if self.debug_layers {
+            self.debug_bufs = [[0; SCREEN_WIDTH]; 3];
+        }
+
+        self.fifo = PixelFifo {
+            active: true,
+            discard: self.scx % 8,
+            stall: STARTUP_DOTS,
+            objs,
+            ..PixelFifo::new()
+        };
+    }
+
+    pub(super) fn fifo_run(&mut self, dots: i32) {
+        for _ in 0..dots {
+            self.fifo_dot();
+        }
+    }
+
+    // Draws whatever is left of the line at the end of DRAW
+    pub(super) fn fifo_finish_line(&mut self) {
+        while !self.fifo.done() {
+            self.fifo_dot();
+        }
+
+        // Like the scanline renderer, a line the window was fetched on
+        // uses up a window line, even if it was blanked
+        if self.fifo.window {
+            self.window_counter = self.window_counter.wrapping_add(1);
+        }
+        self.fifo.active = false;
+    }
+
+    fn fifo_dot(&mut self) {
+        if self.fifo.stall > 0 {
+            self.fifo.stall -= 1;
+            return;
+        }
+        if self.fifo.done() {
+            return;
+        }
+
+        // The window takes over from the next pixel on. A WX below 7 starts
+        // it on the first pixel with its leftmost columns cut off.
+        if !self.fifo.window && self.window_visible() && self.fifo.lx + 7 >= self.wx {
+            self.fifo.window = true;
+            self.fifo.bg.clear();
+            self.fifo.fetch_x = 0;
+            self.fifo.discard = 7_u8.saturating_sub(self.wx);
+        }
+
+        if self.fifo.bg.len() <= 8 {
+            self.fifo_fetch();
+        }
+
+        if self.fifo.discard > 0 {
+            self.fifo.discard -= 1;
+            self.fifo.bg.pop_front();
+            return;
+        }
+
+        // A sprite is fetched once the line reaches its left edge, or at
+        // the first pixel for sprites hanging off the left of the screen
+        if let Some(&line_obj) = self.fifo.objs.get(self.fifo.next_obj) {
+            if line_obj.obj.x <= self.fifo.lx + 8 {
+                self.fifo.next_obj += 1;
+                self.fifo_fetch_obj(line_obj);
+                self.fifo.stall = line_obj.fetch_dots.saturating_sub(1);
+                return;
+            }
+        }
+
+        let color = self.fifo.bg.pop_front().unwrap_or(0);
+        let obj = self.fifo.obj[0];
+        self.fifo.obj.copy_within(1.., 0);
+        self.fifo.obj[7] = ObjPixel::EMPTY;
+
+        let bg_color = if self.bg_win_enabled() { color } else { 0 };
+        let mut px = if self.bg_win_enabled() {
+            tile::Line::apply_palette(color, Palette(self.bgp))
+        } else {
+            0
+        };
+
+        let obj_en = self.obj_en();
+        if obj_en && obj.color != 0 && (!obj.behind_bg || bg_color == 0) {
+            let pal = if obj.obp1 { self.obp1 } else { self.obp0 };
+            px = tile::Line::apply_palette(obj.color, Palette(pal));
+        }
+        if obj_en && obj.outline {
+            px = SPRITE_OUTLINE_COLOR;
+        }
+
+        let lx = self.fifo.lx as usize;
+        if self.debug_layers {
+            self.debug_bufs[self.fifo.window as usize][lx] = bg_color;
+            if obj_en {
+                self.debug_bufs[2][lx] = obj.color;
+            }
+        }
+
+        self.screen.buf[self.ly as usize][lx] = px;
+        self.fifo.lx += 1;
+    }
+
+    // Pushes the next 8 BG or window pixels
+    fn fifo_fetch(&mut self) {
+        let vram = tile::VramBank::ref_from_bytes(&self.vram).unwrap();
+        let alt_address_mode = self.lcdc & 0x10 == 0;
+
+        let (high_tile_map, line, col) = if self.fifo.window {
+            (
+                self.window_map_start_addr() == 0x9C00,
+                self.window_counter,
+                self.fifo.fetch_x,
+            )
+        } else {
+            (
+                self.bkgr_map_start_addr() == 0x9C00,
+                self.ly.wrapping_add(self.scy),
+                (self.scx / 8).wrapping_add(self.fifo.fetch_x),
+            )
+        };
+        self.fifo.fetch_x = self.fifo.fetch_x.wrapping_add(1);
+
+        let idx = (line as usize / 8) * 32 + (col as usize % 32);
+        let tile = vram.get_bg_tile(idx, alt_address_mode, high_tile_map);
+
+        let mut pixels = [0; 8];
+        tile.lines[line as usize % 8].render(&mut pixels, Palette::DEFAULT_PALETTE);
+        for px in pixels {
+            let _ = self.fifo.bg.push_back(px);
+        }
+    }
+
+    // Mixes a sprite's row into the sprite FIFO. Where sprites overlap the
+    // highest priority one that isn't transparent wins, and it alone is
+    // then checked against the BG.
+    fn fifo_fetch_obj(&mut self, line_obj: LineObj) {
+        let vram = tile::VramBank::ref_from_bytes(&self.vram).unwrap();
+        let obj = line_obj.obj;
+        let large_sprites = self.large_sprites();
+        let height = if large_sprites { 16 } else { 8 };
+
+        // LCDC's sprite size can change after the sprite was picked
+        let obj_offset = (self.ly + 16) - obj.y;
+        if obj_offset >= height {
+            return;
+        }
+
+        let mut pixels = [0; 8];
+        obj.render(
+            vram,
+            obj_offset,
+            large_sprites,
+            Palette::DEFAULT_PALETTE,
+            &mut pixels,
+        );
+
+        let edge_row = obj_offset == 0 || obj_offset == height - 1;
+        // The sprite column that lines up with the front of the FIFO
+        let first_col = (self.fifo.lx + 8 - obj.x) as usize;
+
+        for (slot, col) in self.fifo.obj.iter_mut().zip(first_col..8) {
+            let new = ObjPixel {
+                color: pixels[col],
+                obp1: obj.flags.dmg_palette(),
+                behind_bg: obj.flags.priority(),
+                rank: line_obj.rank,
+                outline: self.sprite_debug_outline && (edge_row || col == 0 || col == 7),
+            };
+
+            let outline = slot.outline || new.outline;
+            if new.color != 0 && (slot.color == 0 || new.rank < slot.rank) {
+                *slot = new;
+            }
+            slot.outline = outline;
+        }
+    }
+}
@@ -1,13 +1,16 @@
 use bitfield_struct::bitfield;
 use core::iter::IntoIterator;
 use heapless::Vec;
-use zerocopy_derive::{FromBytes, Immutable, KnownLayout};
+use zerocopy_derive::{FromBytes, Immutable, IntoBytes, KnownLayout};
+
+use super::ObjPriority;
 
 #[derive(Clone, Copy)]
 pub struct Palette(pub u8);
 
 impl Palette {
-    const DEFAULT_PALETTE: Self = Palette(0b11100100_u8);
+    // Maps every color to itself
+    pub const DEFAULT_PALETTE: Self = Palette(0b11100100_u8);
 }
 
 #[derive(FromBytes, Immutable, KnownLayout)]
@@ -70,13 +73,16 @@ pub struct VramBank {
 }
 
 impl VramBank {
-    pub fn get_bg_tile(&self, idx: usize, alt_address_mode: bool, high_tile_map: bool) -> &Tile {
-        let tile_idx = if high_tile_map {
-            self.tilemap1[idx]
+    fn tilemap_data(&self, high_tile_map: bool) -> &[u8; 32 * 32] {
+        if high_tile_map {
+            &self.tilemap1
         } else {
-            self.tilemap0[idx]
-        };
+            &self.tilemap0
+        }
+    }
 
+    // The tile a tilemap entry refers to
+    fn resolve_tile(&self, tile_idx: u8, alt_address_mode: bool) -> &Tile {
         if alt_address_mode {
             //Selet 'blocks' 1 and 2
             let tiles = &self.tiles[128..];
@@ -85,10 +91,30 @@ impl VramBank {
             &self.tiles[tile_idx as usize]
         }
     }
+
+    // One of the 384 tiles, in VRAM order
+    pub fn tile(&self, index: usize) -> &Tile {
+        &self.tiles[index]
+    }
+
+    pub fn get_bg_tile(&self, idx: usize, alt_address_mode: bool, high_tile_map: bool) -> &Tile {
+        self.resolve_tile(self.tilemap_data(high_tile_map)[idx], alt_address_mode)
+    }
+
+    // Every entry of a 32x32 tilemap in row order, with the tile it refers to
+    pub fn tilemap(
+        &self,
+        alt_address_mode: bool,
+        high_tile_map: bool,
+    ) -> impl Iterator<Item = (u8, &Tile)> {
+        self.tilemap_data(high_tile_map)
+            .iter()
+            .map(move |&tile_idx| (tile_idx, self.resolve_tile(tile_idx, alt_address_mode)))
+    }
 }
 
 #[bitfield(u8)]
-#[derive(FromBytes, Immutable, KnownLayout)]
+#[derive(FromBytes, IntoBytes, Immutable, KnownLayout)]
 pub struct OamFlags {
     #[bits(3)]
     _gcb_palette: u8,
@@ -104,7 +130,8 @@ pub struct OamFlags {
     pub priority: bool,
 }
 
-#[derive(FromBytes, Immutable, KnownLayout, Clone, Copy)]
+#[derive(FromBytes, IntoBytes, Immutable, KnownLayout, Clone, Copy)]
+#[repr(C)]
 pub struct OamEntry {
     pub y: u8,
     pub x: u8,
@@ -157,7 +184,14 @@ pub struct Oam {
 }
 
 impl Oam {
-    pub fn get_oams_line(&self, line: u8, large_tiles: bool) -> Vec<OamEntry, 10> {
+    // Returns the sprites on `line`, in the order they should be drawn
+    // (lowest priority first)
+    pub fn get_oams_line(
+        &self,
+        line: u8,
+        large_tiles: bool,
+        priority: ObjPriority,
+    ) -> Vec<OamEntry, 10> {
         // The PPU only generates the first 10
         let mut oams: Vec<OamEntry, 10> = Vec::new();
 
@@ -184,7 +218,11 @@ impl Oam {
                 // This will maintain a reverse-sorted list of OAM entries
                 // by their X position. `<` is used rather than `<=` because
                 // entries earlier in RAM are higher priority if X is the same.
-                let idx = oams.partition_point(|&o| oam_entry.x < o.x);
+                let idx = match priority {
+                    ObjPriority::Coordinate => oams.partition_point(|&o| oam_entry.x < o.x),
+                    // Entries earlier in RAM always win, so they are drawn last
+                    ObjPriority::OamIndex => 0,
+                };
 
                 let _ = oams.insert(idx, *oam_entry);
             }
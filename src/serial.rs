@@ -0,0 +1,205 @@
+#[cfg(any(test, feature = "std"))]
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+#[cfg(any(test, feature = "std"))]
+use std::boxed::Box;
+
+// The serial port clocks one bit every 128 M-cycles (8192 Hz)
+const CYCLES_PER_BIT: u16 = 128;
+
+// Something on the other end of the link cable
+pub trait SerialDevice {
+    // Exchanges a byte with the peer, returning the byte it shifted out
+    fn transfer(&mut self, out: u8) -> u8;
+}
+
+pub struct Serial {
+    sb: u8,
+    sc: u8,
+    // The number of M-cycles until the current transfer finishes
+    r_cyc: u16,
+    #[cfg(any(test, feature = "std"))]
+    device: Option<Box<dyn SerialDevice>>,
+}
+
+impl Serial {
+    pub fn new() -> Self {
+        Self {
+            sb: 0,
+            sc: 0,
+            r_cyc: 0,
+            #[cfg(any(test, feature = "std"))]
+            device: None,
+        }
+    }
+
+    #[cfg(any(test, feature = "std"))]
+    pub fn connect(&mut self, device: Box<dyn SerialDevice>) {
+        self.device = Some(device);
+    }
+
+    #[cfg(any(test, feature = "std"))]
+    pub fn disconnect(&mut self) -> Option<Box<dyn SerialDevice>> {
+        self.device.take()
+    }
+
+    fn connected(&self) -> bool {
+        #[cfg(any(test, feature = "std"))]
+        {
+            self.device.is_some()
+        }
+        #[cfg(not(any(test, feature = "std")))]
+        {
+            false
+        }
+    }
+
+    fn transfer_requested(&self) -> bool {
+        self.sc & 0x80 == 0x80
+    }
+
+    fn internal_clock(&self) -> bool {
+        self.sc & 0x1 == 0x1
+    }
+
+    pub fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0xFF01 => {
+                self.sb = val;
+            }
+            0xFF02 => {
+                self.sc = val & 0x81;
+                self.r_cyc = 8 * CYCLES_PER_BIT;
+            }
+            _ => {
+                unreachable!("Invalid write to serial");
+            }
+        }
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF01 => self.sb,
+            0xFF02 => self.sc | 0x7E,
+            _ => {
+                unreachable!("Invalid read from serial");
+            }
+        }
+    }
+
+    // Advances one M-cycle, returns true if a transfer finished
+    pub fn tick(&mut self) -> bool {
+        if !self.transfer_requested() {
+            return false;
+        }
+
+        // With an external clock, the transfer only moves
+        // when the peer is clocking it. Without one it stalls forever.
+        if !self.internal_clock() && !self.connected() {
+            return false;
+        }
+
+        self.r_cyc -= 1;
+        if self.r_cyc != 0 {
+            return false;
+        }
+
+        self.sb = self.exchange(self.sb);
+        self.sc &= !0x80;
+        true
+    }
+
+    fn exchange(&mut self, out: u8) -> u8 {
+        #[cfg(any(test, feature = "std"))]
+        if let Some(device) = &mut self.device {
+            return device.transfer(out);
+        }
+
+        // Nothing on the other end, the input line is pulled high
+        let _ = out;
+        0xFF
+    }
+}
+
+impl Default for Serial {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(any(test, feature = "std"))]
+impl Snapshot for Serial {
+    // The connected device isn't part of the state
+    fn save(&self, w: &mut StateWriter) {
+        w.u8(self.sb);
+        w.u8(self.sc);
+        w.u16(self.r_cyc);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.sb = r.u8()?;
+        self.sc = r.u8()?;
+        self.r_cyc = r.u16()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Echo(u8);
+
+    impl SerialDevice for Echo {
+        fn transfer(&mut self, out: u8) -> u8 {
+            let sent = self.0;
+            self.0 = out;
+            sent
+        }
+    }
+
+    #[test]
+    fn external_clock_stall() {
+        let mut serial = Serial::new();
+        serial.write(0xFF01, 0x42);
+        serial.write(0xFF02, 0x80);
+
+        // No peer to supply the clock, nothing ever happens
+        for _ in 0..100_000 {
+            assert!(!serial.tick());
+        }
+        assert_eq!(serial.read(0xFF01), 0x42);
+        assert_eq!(serial.read(0xFF02), 0xFE);
+
+        serial.connect(Box::new(Echo(0x99)));
+        let cycles = (0..).position(|_| serial.tick()).unwrap() + 1;
+        assert_eq!(cycles, 8 * CYCLES_PER_BIT as usize);
+        assert_eq!(serial.read(0xFF01), 0x99);
+        assert_eq!(serial.read(0xFF02), 0x7E);
+    }
+
+    #[test]
+    fn internal_clock() {
+        let mut serial = Serial::new();
+        serial.write(0xFF01, 0x42);
+        serial.write(0xFF02, 0x81);
+
+        // Without a peer the byte shifted in is all ones
+        let cycles = (0..).position(|_| serial.tick()).unwrap() + 1;
+        assert_eq!(cycles, 8 * CYCLES_PER_BIT as usize);
+        assert_eq!(serial.read(0xFF01), 0xFF);
+        assert_eq!(serial.read(0xFF02), 0x7F);
+        assert!(!serial.tick());
+
+        serial.connect(Box::new(Echo(0x99)));
+        serial.write(0xFF01, 0x42);
+        serial.write(0xFF02, 0x81);
+        let cycles = (0..).position(|_| serial.tick()).unwrap() + 1;
+        assert_eq!(cycles, 8 * CYCLES_PER_BIT as usize);
+        assert_eq!(serial.read(0xFF01), 0x99);
+
+        // The device got our byte
+        serial.write(0xFF02, 0x81);
+        while !serial.tick() {}
+        assert_eq!(serial.read(0xFF01), 0x42);
+    }
+}
@@ -0,0 +1,170 @@
+// A CPU over a flat 64KiB of RAM with nothing else mapped, for checking
+// single instructions against the sm83 JSON test vectors
+// (https://github.com/SingleStepTests/sm83).
+//
+// The vectors aren't checked in. Point SM83_TESTS at their `v1` directory
+// to run all of them, otherwise only the samples below are run.
+
+use crate::bus::{Bus, Device};
+use crate::cpu::{Cpu, CpuRegisters};
+use crate::gb::SmallInMemoryCartridge;
+use serde_json::Value;
+use std::string::String;
+
+pub fn flat_cpu() -> Cpu<SmallInMemoryCartridge> {
+    let mut bus = Bus::new(SmallInMemoryCartridge::from_slice(&[0; 0x8000]));
+    bus.use_flat_ram();
+    Cpu::new(bus)
+}
+
+fn reg(state: &Value, name: &str) -> u16 {
+    state[name].as_u64().expect("Missing register") as u16
+}
+
+fn registers(state: &Value) -> CpuRegisters {
+    let f = reg(state, "f");
+    CpuRegisters {
+        a: reg(state, "a") as u8,
+        b: reg(state, "b") as u8,
+        c: reg(state, "c") as u8,
+        d: reg(state, "d") as u8,
+        e: reg(state, "e") as u8,
+        h: reg(state, "h") as u8,
+        l: reg(state, "l") as u8,
+        sp: reg(state, "sp"),
+        pc: reg(state, "pc"),
+        z_f: f & 0x80 != 0,
+        n_f: f & 0x40 != 0,
+        h_f: f & 0x20 != 0,
+        c_f: f & 0x10 != 0,
+    }
+}
+
+fn ram(state: &Value) -> impl Iterator<Item = (u16, u8)> + '_ {
+    state["ram"]
+        .as_array()
+        .expect("Missing RAM")
+        .iter()
+        .map(|pair| {
+            let addr = pair[0].as_u64().expect("Bad RAM address") as u16;
+            let val = pair[1].as_u64().expect("Bad RAM value") as u8;
+            (addr, val)
+        })
+}
+
+// Runs one vector, returning a description of the first mismatch
+fn run_vector(vector: &Value) -> Result<(), String> {
+    let name = vector["name"].as_str().unwrap_or("?");
+
+    let mut cpu = flat_cpu();
+    cpu.set_registers(registers(&vector["initial"]));
+    for (addr, val) in ram(&vector["initial"]) {
+        cpu.bus.write(addr, val);
+    }
+
+    cpu.run_one();
+
+    let expected = registers(&vector["final"]);
+    if cpu.registers() != expected {
+        return Err(std::format!(
+            "{name}: expected {:?}, got {:?}",
+            expected,
+            cpu.registers()
+        ));
+    }
+
+    for (addr, val) in ram(&vector["final"]) {
+        if cpu.bus.read(addr) != val {
+            return Err(std::format!(
+                "{name}: expected {val:#04X} at {addr:#06X}, got {:#04X}",
+                cpu.bus.read(addr)
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    const SAMPLES: &str = r#"[
+        {
+            "name": "80 add a,b",
+            "initial": {
+                "a": 58, "b": 198, "c": 0, "d": 0, "e": 0, "f": 0, "h": 0, "l": 0,
+                "pc": 4096, "sp": 49152, "ram": [[4096, 128]]
+            },
+            "final": {
+                "a": 0, "b": 198, "c": 0, "d": 0, "e": 0, "f": 176, "h": 0, "l": 0,
+                "pc": 4097, "sp": 49152, "ram": [[4096, 128]]
+            }
+        },
+        {
+            "name": "77 ld (hl),a",
+            "initial": {
+                "a": 66, "b": 0, "c": 0, "d": 0, "e": 0, "f": 16, "h": 192, "l": 16,
+                "pc": 8192, "sp": 49152, "ram": [[8192, 119], [49168, 0]]
+            },
+            "final": {
+                "a": 66, "b": 0, "c": 0, "d": 0, "e": 0, "f": 16, "h": 192, "l": 16,
+                "pc": 8193, "sp": 49152, "ram": [[8192, 119], [49168, 66]]
+            }
+        },
+        {
+            "name": "cd call $1234",
+            "initial": {
+                "a": 0, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0, "h": 0, "l": 0,
+                "pc": 12288, "sp": 53248, "ram": [[12288, 205], [12289, 52], [12290, 18]]
+            },
+            "final": {
+                "a": 0, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0, "h": 0, "l": 0,
+                "pc": 4660, "sp": 53246, "ram": [[53246, 3], [53247, 48]]
+            }
+        }
+    ]"#;
+
+    #[test]
+    fn sample_vectors() {
+        let vectors: Value = serde_json::from_str(SAMPLES).unwrap();
+        for vector in vectors.as_array().unwrap() {
+            run_vector(vector).unwrap();
+        }
+    }
+
+    #[test]
+    fn sm83_vectors() {
+        let Ok(dir) = std::env::var("SM83_TESTS") else {
+            return;
+        };
+
+        let mut paths: Vec<_> = std::fs::read_dir(dir)
+            .expect("Can't read SM83_TESTS")
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .collect();
+        paths.sort();
+
+        let mut failures = Vec::new();
+        for path in paths {
+            // STOP faults the CPU instead of entering low power mode
+            if path.file_stem().is_some_and(|stem| stem == "10") {
+                continue;
+            }
+
+            let file = std::fs::read_to_string(&path).unwrap();
+            let vectors: Value = serde_json::from_str(&file).unwrap();
+            if let Some(failure) = vectors
+                .as_array()
+                .unwrap()
+                .iter()
+                .find_map(|v| run_vector(v).err())
+            {
+                failures.push(failure);
+            }
+        }
+
+        assert!(failures.is_empty(), "{:#?}", failures);
+    }
+}
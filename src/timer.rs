@@ -1,10 +1,31 @@
+#[cfg(any(test, feature = "std"))]
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
 pub struct Timer {
     tima: u8,
     tma: u8,
     tac: u8,
     system_counter: u16,
+    // After TIMA overflows it reads 0 for this many ticks before it's
+    // reloaded from TMA and the interrupt is raised
+    reload_pending: u8,
+    // TIMA was reloaded on the last tick
+    reloaded: bool,
+}
+
+// A snapshot of the timer registers, for debugging
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimerState {
+    pub div: u8,
+    pub tima: u8,
+    pub tma: u8,
+    pub tac: u8,
+    pub enabled: bool,
 }
 
+// M-cycles between TIMA overflowing and being reloaded
+const RELOAD_DELAY: u8 = 1;
+
 impl Timer {
     pub fn new() -> Self {
         Timer {
@@ -12,6 +33,8 @@ impl Timer {
             tma: 0,
             tac: 0x0,
             system_counter: 0,
+            reload_pending: 0,
+            reloaded: false,
         }
     }
 
@@ -27,13 +50,28 @@ impl Timer {
                 self.system_counter = 0;
             }
             0xFF05 => {
-                self.tima = val;
+                // Writing TIMA before the reload aborts it, and writing it
+                // during the reload is ignored
+                self.reload_pending = 0;
+                if !self.reloaded {
+                    self.tima = val;
+                }
             }
             0xFF06 => {
+                // Writing TMA during the reload also reloads the new value
                 self.tma = val;
+                if self.reloaded {
+                    self.tima = val;
+                }
             }
             0xFF07 => {
-                self.tac = val;
+                // TIMA is clocked by the falling edge of the selected counter
+                // bit ANDed with the enable, so changing TAC can clock it too
+                let pre = self.timer_bit();
+                self.tac = val & 0x7;
+                if pre && !self.timer_bit() {
+                    self.increment();
+                }
             }
             _ => {
                 unreachable!("Invalid write to timer");
@@ -44,7 +82,7 @@ impl Timer {
     pub fn read(&self, addr: u16) -> u8 {
         match addr {
             0xFF04 => {
-                return (self.system_counter >> 8) as u8;
+                return self.div();
             }
             0xFF05 => {
                 return self.tima;
@@ -53,7 +91,7 @@ impl Timer {
                 return self.tma;
             }
             0xFF07 => {
-                return self.tac;
+                return 0xF8 | self.tac;
             }
             _ => {
                 unreachable!("Invalid write to timer");
@@ -61,14 +99,28 @@ impl Timer {
         }
     }
 
-    pub fn tick(&mut self) -> bool {
-        let pre_add = self.system_counter;
-        self.system_counter = self.system_counter.wrapping_add(1);
+    // The internal counter DIV is taken from, in M-cycles
+    pub fn system_counter(&self) -> u16 {
+        self.system_counter
+    }
 
-        if !self.enabled() {
-            return false;
+    // DIV counts at 16384Hz, once every 64 M-cycles
+    fn div(&self) -> u8 {
+        (self.system_counter >> 6) as u8
+    }
+
+    pub fn state(&self) -> TimerState {
+        TimerState {
+            div: self.div(),
+            tima: self.tima,
+            tma: self.tma,
+            tac: self.tac,
+            enabled: self.enabled(),
         }
+    }
 
+    // The selected bit of the system counter, ANDed with the enable
+    fn timer_bit(&self) -> bool {
         // See: https://gbdev.io/pandocs/Timer_Obscure_Behaviour.html
         let num_shift = match self.tac & 0x3 {
             // This is really just log2 of the table on pandocs
@@ -79,20 +131,58 @@ impl Timer {
             _ => unreachable!("No"),
         } - 1;
 
-        // Check and see if the LSB triggered falling edge
-        let pre_lsb = ((pre_add >> num_shift) & 1) == 1;
-        let post_lsb = ((self.system_counter >> num_shift) & 1) == 1;
+        self.enabled() && ((self.system_counter >> num_shift) & 1) == 1
+    }
 
-        if pre_lsb && !post_lsb {
-            // Timer tick!
-            self.tima = self.tima.wrapping_add(1);
-            if self.tima == 0 {
+    fn increment(&mut self) {
+        self.tima = self.tima.wrapping_add(1);
+        if self.tima == 0 {
+            self.reload_pending = RELOAD_DELAY;
+        }
+    }
+
+    // Returns true when TIMA is reloaded and the interrupt should be raised
+    pub fn tick(&mut self) -> bool {
+        self.reloaded = false;
+        if self.reload_pending > 0 {
+            self.reload_pending -= 1;
+            if self.reload_pending == 0 {
                 self.tima = self.tma;
-                return true;
+                self.reloaded = true;
             }
         }
 
-        return false;
+        let pre = self.timer_bit();
+        self.system_counter = self.system_counter.wrapping_add(1);
+
+        // Check and see if the bit triggered a falling edge
+        if pre && !self.timer_bit() {
+            self.increment();
+        }
+
+        self.reloaded
+    }
+}
+
+#[cfg(any(test, feature = "std"))]
+impl Snapshot for Timer {
+    fn save(&self, w: &mut StateWriter) {
+        w.u8(self.tima);
+        w.u8(self.tma);
+        w.u8(self.tac);
+        w.u16(self.system_counter);
+        w.u8(self.reload_pending);
+        w.bool(self.reloaded);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.tima = r.u8()?;
+        self.tma = r.u8()?;
+        self.tac = r.u8()?;
+        self.system_counter = r.u16()?;
+        self.reload_pending = r.u8()?;
+        self.reloaded = r.bool()?;
+        Ok(())
     }
 }
 
@@ -109,6 +199,40 @@ mod tests {
         }
     }
 
+    #[test]
+    fn state() {
+        let mut timer = Timer::new();
+        timer.write(0xFF06, 0x42);
+        timer.write(0xFF07, 0x5);
+        for _ in 0..0x300 {
+            timer.tick();
+        }
+        assert_eq!(
+            timer.state(),
+            TimerState {
+                div: 0x0C,
+                tima: 0xC0,
+                tma: 0x42,
+                tac: 0x5,
+                enabled: true,
+            }
+        );
+
+        // DIV counts up every 64 ticks and resets on any write
+        for div in 0x0D..0x10 {
+            for _ in 0..63 {
+                timer.tick();
+                assert_eq!(timer.state().div, div - 1);
+            }
+            timer.tick();
+            assert_eq!(timer.state().div, div);
+            assert_eq!(timer.read(0xFF04), div);
+        }
+        timer.write(0xFF04, 0x12);
+        assert_eq!(timer.state().div, 0x00);
+        assert_eq!(timer.read(0xFF04), 0x00);
+    }
+
     #[test]
     fn clock0() {
         let mut timer = Timer::new();
@@ -176,16 +300,99 @@ mod tests {
         timer.write(0xFF05, 0xFF);
         assert_eq!(timer.enabled(), true);
 
+        for _ in 0..63 {
+            assert_eq!(timer.tick(), false);
+            assert_eq!(timer.read(0xFF05), 0xFF);
+        }
+
         for _ in 0..5 {
-            for _ in 0..63 {
+            // TIMA reads 0 for a cycle before it's reloaded
+            assert_eq!(timer.tick(), false);
+            assert_eq!(timer.read(0xFF05), 0);
+            assert!(timer.tick());
+            assert_eq!(timer.read(0xFF05), 0xFF);
+
+            for _ in 0..62 {
                 assert_eq!(timer.tick(), false);
                 assert_eq!(timer.read(0xFF05), 0xFF);
             }
-            assert_eq!(timer.tick(), true);
-            assert_eq!(timer.read(0xFF05), 0xFF);
         }
     }
 
+    #[test]
+    fn tac_write_glitch() {
+        let mut timer = Timer::new();
+        timer.write(0xFF07, 0x5);
+
+        // Bit 1 of the counter is now high
+        timer.tick();
+        timer.tick();
+        assert_eq!(timer.read(0xFF05), 0);
+
+        // Disabling the timer drops the selected bit
+        timer.write(0xFF07, 0x1);
+        assert_eq!(timer.read(0xFF05), 1);
+        assert_eq!(timer.read(0xFF07), 0xF9);
+
+        // Enabling it again doesn't
+        timer.write(0xFF07, 0xFD);
+        assert_eq!(timer.read(0xFF05), 1);
+        assert_eq!(timer.read(0xFF07), 0xFD);
+
+        // Switching to a clock whose bit is low does, and the overflow
+        // interrupt is raised on the next tick
+        timer.write(0xFF05, 0xFF);
+        timer.write(0xFF07, 0x6);
+        assert_eq!(timer.read(0xFF05), 0);
+        assert!(timer.tick());
+        assert!(!timer.tick());
+    }
+
+    // Runs a timer clocked every 4 ticks until TIMA has just overflowed
+    fn overflowed_timer() -> Timer {
+        let mut timer = Timer::new();
+        timer.write(0xFF06, 0x80);
+        timer.write(0xFF07, 0x5);
+        timer.write(0xFF05, 0xFF);
+        for _ in 0..4 {
+            assert!(!timer.tick());
+        }
+        assert_eq!(timer.read(0xFF05), 0);
+        timer
+    }
+
+    #[test]
+    fn reload_delay() {
+        let mut timer = overflowed_timer();
+        assert!(timer.tick());
+        assert_eq!(timer.read(0xFF05), 0x80);
+
+        // Writing TMA before the reload changes the reloaded value
+        let mut timer = overflowed_timer();
+        timer.write(0xFF06, 0x40);
+        assert!(timer.tick());
+        assert_eq!(timer.read(0xFF05), 0x40);
+
+        // Writing TIMA before the reload aborts it
+        let mut timer = overflowed_timer();
+        timer.write(0xFF05, 0x10);
+        assert!(!timer.tick());
+        assert_eq!(timer.read(0xFF05), 0x10);
+
+        // Writing TIMA during the reload is ignored, writing TMA isn't
+        let mut timer = overflowed_timer();
+        assert!(timer.tick());
+        timer.write(0xFF05, 0x10);
+        assert_eq!(timer.read(0xFF05), 0x80);
+        timer.write(0xFF06, 0x20);
+        assert_eq!(timer.read(0xFF05), 0x20);
+
+        // Until the next tick
+        timer.tick();
+        timer.write(0xFF05, 0x10);
+        assert_eq!(timer.read(0xFF05), 0x10);
+    }
+
     #[test]
     fn blargg_instr_timing_incre_every_four() {
         // The blargg 'instr_timing'
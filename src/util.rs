@@ -1,5 +1,10 @@
 use crate::cart::CartridgeData;
+use crate::ppu::{TileMap, PPU};
+use core::cell::RefCell;
+use core::fmt::Write;
 use std::borrow::ToOwned;
+use std::boxed::Box;
+use std::io::{Read, Seek, SeekFrom};
 use std::println;
 use std::string::String;
 use std::vec;
@@ -51,8 +56,12 @@ impl Drop for VecCart {
 }
 
 impl CartridgeData for VecCart {
-    fn rom(&self) -> &[u8] {
-        &self.rom
+    fn rom_len(&self) -> usize {
+        self.rom.len()
+    }
+
+    fn read_rom(&self, addr: usize) -> u8 {
+        self.rom.get(addr).copied().unwrap_or(0xFF)
     }
 
     fn ram(&self) -> &[u8] {
@@ -71,3 +80,207 @@ impl CartridgeData for VecCart {
         }
     }
 }
+
+const ROM_BANK_LEN: usize = 0x4000;
+// The number of ROM banks a StreamCart keeps in memory
+const STREAM_CACHE_BANKS: usize = 4;
+
+// A cartridge that reads its ROM from `src` a bank at a time as it's
+// needed, for ROMs too large to keep in memory. The most recently used
+// banks are cached. Banks that fail to read, read as 0xFF.
+pub struct StreamCart<R: Read + Seek> {
+    src: RefCell<R>,
+    rom_len: usize,
+    // Bank numbers and their data, most recently used first
+    cache: RefCell<Vec<(usize, Box<[u8; ROM_BANK_LEN]>)>>,
+    ram: Vec<u8>,
+}
+
+impl<R: Read + Seek> StreamCart<R> {
+    pub fn new(mut src: R) -> std::io::Result<Self> {
+        let rom_len = src.seek(SeekFrom::End(0))? as usize;
+        let mut cart = Self {
+            src: RefCell::new(src),
+            rom_len,
+            cache: RefCell::new(Vec::new()),
+            ram: Vec::new(),
+        };
+
+        cart.ram = vec![0; cart.get_header().ram_size as usize];
+        Ok(cart)
+    }
+
+    fn load_bank(&self, bank: usize) -> Box<[u8; ROM_BANK_LEN]> {
+        let mut buf = Box::new([0xFF; ROM_BANK_LEN]);
+        let start = bank * ROM_BANK_LEN;
+        let len = ROM_BANK_LEN.min(self.rom_len - start);
+
+        let mut src = self.src.borrow_mut();
+        let read = src
+            .seek(SeekFrom::Start(start as u64))
+            .and_then(|_| src.read_exact(&mut buf[..len]));
+        if read.is_err() {
+            buf.fill(0xFF);
+        }
+
+        buf
+    }
+}
+
+impl<R: Read + Seek> CartridgeData for StreamCart<R> {
+    fn rom_len(&self) -> usize {
+        self.rom_len
+    }
+
+    fn read_rom(&self, addr: usize) -> u8 {
+        if addr >= self.rom_len {
+            return 0xFF;
+        }
+
+        let bank = addr / ROM_BANK_LEN;
+        let mut cache = self.cache.borrow_mut();
+        match cache.iter().position(|(b, _)| *b == bank) {
+            Some(0) => {}
+            Some(i) => {
+                let entry = cache.remove(i);
+                cache.insert(0, entry);
+            }
+            None => {
+                cache.truncate(STREAM_CACHE_BANKS - 1);
+                cache.insert(0, (bank, self.load_bank(bank)));
+            }
+        }
+
+        cache[0].1[addr % ROM_BANK_LEN]
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+}
+
+// The width (and height) of a tile map in tiles
+const TILE_MAP_WIDTH: usize = 32;
+
+// Writes the tile map as 32 lines of 32 space separated tile indices
+fn write_tilemap(out: &mut String, ppu: &PPU, which: TileMap, hex: bool) {
+    for row in ppu.tile_map(which).chunks(TILE_MAP_WIDTH) {
+        for (i, idx) in row.iter().enumerate() {
+            if i != 0 {
+                out.push(' ');
+            }
+            let _ = if hex {
+                write!(out, "{:02X}", idx)
+            } else {
+                write!(out, "{}", idx)
+            };
+        }
+        out.push('\n');
+    }
+}
+
+// Dumps a tile map's raw tile indices as a 32x32 grid of hex bytes
+pub fn dump_tilemap(ppu: &PPU, which: TileMap) -> String {
+    let mut out = String::new();
+    write_tilemap(&mut out, ppu, which, true);
+    out
+}
+
+// Dumps a tile map's raw tile indices as a 32x32 plain PGM image,
+// one pixel per tile with the index as its brightness
+pub fn dump_tilemap_pgm(ppu: &PPU, which: TileMap) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "P2\n{} {}\n255", TILE_MAP_WIDTH, TILE_MAP_WIDTH);
+    write_tilemap(&mut out, ppu, which, false);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Counts the reads made of the ROM
+    struct CountingReader {
+        inner: std::io::Cursor<Vec<u8>>,
+        reads: std::rc::Rc<core::cell::Cell<usize>>,
+    }
+
+    impl Read for CountingReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.reads.set(self.reads.get() + 1);
+            self.inner.read(buf)
+        }
+    }
+
+    impl Seek for CountingReader {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn stream_cart() {
+        use crate::cart::Cartridge;
+
+        // MBC1+RAM with 8 banks, each filled with its bank number
+        let mut rom: Vec<u8> = (0..8).flat_map(|bank| [bank; ROM_BANK_LEN]).collect();
+        rom[0x147] = 0x2;
+        rom[0x148] = 0x2;
+        rom[0x149] = 0x2;
+
+        let reads = std::rc::Rc::new(core::cell::Cell::new(0));
+        let src = CountingReader {
+            inner: std::io::Cursor::new(rom),
+            reads: reads.clone(),
+        };
+        let mut cart = Cartridge::new(StreamCart::new(src).unwrap());
+        assert_eq!(cart.get_header().num_rom_banks, 8);
+        assert_eq!(cart.export_ram().len(), 0x2000);
+
+        for bank in 1..8 {
+            cart.write(0x2000, bank);
+            assert_eq!(cart.read(0x4000), bank);
+            assert_eq!(cart.read(0x7FFF), bank);
+        }
+        assert_eq!(cart.read(0x0000), 0);
+
+        // Bank 0 and the last three are cached
+        let before = reads.get();
+        cart.write(0x2000, 5);
+        assert_eq!(cart.read(0x4000), 5);
+        assert_eq!(cart.read(0x0100), 0);
+        assert_eq!(reads.get(), before);
+
+        cart.write(0x2000, 1);
+        assert_eq!(cart.read(0x4000), 1);
+        assert!(reads.get() > before);
+    }
+
+    #[test]
+    fn tilemap_dump() {
+        let mut ppu = PPU::new();
+        ppu.write(0x9800, 0x01);
+        ppu.write(0x9800 + 32 + 2, 0xAB);
+        ppu.write(0x9C00 + 1023, 0xFF);
+
+        let dump = dump_tilemap(&ppu, TileMap::Map9800);
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(lines.len(), 32);
+        assert!(lines[0].starts_with("01 00 00"));
+        assert!(lines[1].starts_with("00 00 AB 00"));
+
+        let dump = dump_tilemap(&ppu, TileMap::Map9C00);
+        assert!(dump.lines().last().unwrap().ends_with("00 FF"));
+
+        let pgm = dump_tilemap_pgm(&ppu, TileMap::Map9800);
+        let lines: Vec<&str> = pgm.lines().collect();
+        assert_eq!(lines[..3], ["P2", "32 32", "255"]);
+        assert!(lines[3].starts_with("1 0 0"));
+        assert!(lines[4].starts_with("0 0 171 0"));
+        assert_eq!(lines.len(), 3 + 32);
+    }
+}
@@ -1,31 +1,22 @@
 use std::fs::read;
 use std::path::Path;
-use std::time;
 
 use gb_rs::gb::{GbRs, SmallInMemoryCartridge};
 
+mod common;
+use common::{PassedSniffer, MAX_CYCLES};
+
 fn rom_test(rom_path: &str) {
     let rom_path = Path::new(rom_path);
     let rom = read(rom_path).expect(format!("Unable to load test rom: {:?}", rom_path).as_str());
     let cartridge = SmallInMemoryCartridge::from_slice(rom.as_slice());
 
     let mut gb = GbRs::new(cartridge);
+    let (sniffer, passed) = PassedSniffer::new();
+    gb.cpu.bus.serial.connect(Box::new(sniffer));
 
-    let timeout = time::Instant::now() + time::Duration::from_secs(30);
-
-    let mut cnt = 0;
-
-    while !gb.cpu.is_passed() {
-        gb.run_one();
-
-        if cnt == 1000 {
-            // Timeout check
-            assert!(time::Instant::now() < timeout);
-            cnt = 0;
-        }
-
-        cnt += 1;
-    }
+    let result = gb.run_until(MAX_CYCLES, |_| passed.is_passed());
+    assert!(!result.timed_out, "{:?} didn't pass", rom_path);
 }
 
 #[test]
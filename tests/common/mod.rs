@@ -0,0 +1,45 @@
+use gb_rs::serial::SerialDevice;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+// How long a test ROM gets to pass, about two minutes of emulated time
+pub const MAX_CYCLES: u64 = 1 << 27;
+
+// What Mooneye tests send over serial when they pass, the Fibonacci numbers
+const MOONEYE_PASSED: [u8; 6] = [3, 5, 8, 13, 21, 34];
+
+// Listens on the link cable for a test ROM to report that it passed.
+// Blargg's tests print "Passed", Mooneye's send MOONEYE_PASSED.
+pub struct PassedSniffer {
+    received: Rc<RefCell<Vec<u8>>>,
+}
+
+impl PassedSniffer {
+    // The sniffer to connect, and a handle to check it with
+    pub fn new() -> (Self, PassedHandle) {
+        let received = Rc::new(RefCell::new(Vec::new()));
+        let handle = PassedHandle {
+            received: received.clone(),
+        };
+        (Self { received }, handle)
+    }
+}
+
+impl SerialDevice for PassedSniffer {
+    fn transfer(&mut self, out: u8) -> u8 {
+        self.received.borrow_mut().push(out);
+        // Nothing on the other end
+        0xFF
+    }
+}
+
+pub struct PassedHandle {
+    received: Rc<RefCell<Vec<u8>>>,
+}
+
+impl PassedHandle {
+    pub fn is_passed(&self) -> bool {
+        let received = self.received.borrow();
+        received.ends_with(b"Passed") || received.ends_with(&MOONEYE_PASSED)
+    }
+}
@@ -1,9 +1,9 @@
 use gb_rs::gb::{GbRs, SmallInMemoryCartridge};
+use gb_rs::ppu::RenderMode;
 use std::fs::read;
 use std::path::Path;
 
-#[test]
-fn dmg2_acid_test() {
+fn acid_test(mode: RenderMode) {
     let rom_path = Path::new("tests/roms/dmg-acid2.gb");
     let bin_path = Path::new("tests/dmg-acid2.bin");
 
@@ -13,6 +13,7 @@ fn dmg2_acid_test() {
     let cartridge = SmallInMemoryCartridge::from_slice(rom.as_slice());
 
     let mut gb = GbRs::new(cartridge);
+    gb.cpu.bus.ppu.set_render_mode(mode);
 
     for _ in 0..10 {
         gb.run_frame();
@@ -20,3 +21,30 @@ fn dmg2_acid_test() {
 
     assert_eq!(gb.cpu.bus.ppu.get_screen(), *bin);
 }
+
+#[test]
+fn dmg2_acid_test() {
+    acid_test(RenderMode::Scanline);
+}
+
+#[test]
+fn dmg2_acid_test_fifo() {
+    acid_test(RenderMode::Fifo);
+}
+
+#[test]
+fn dmg2_acid_frame_hashes() {
+    let rom = read("tests/roms/dmg-acid2.gb").expect("Unable to load dmg-acid2 ROM");
+
+    let hashes = |frames| {
+        let mut gb = GbRs::new(SmallInMemoryCartridge::from_slice(rom.as_slice()));
+        (0..frames)
+            .map(|_| gb.run_frame_and_hash())
+            .collect::<Vec<_>>()
+    };
+
+    // Every run renders the same frames, and the picture changes as it's drawn
+    let first = hashes(10);
+    assert_eq!(first, hashes(10));
+    assert_ne!(first[0], first[9]);
+}
@@ -5,7 +5,9 @@ use gb_rs::{
 };
 use std::fs::read;
 use std::path::Path;
-use std::time;
+
+mod common;
+use common::{PassedSniffer, MAX_CYCLES};
 
 fn rom_test(rom_path: &str) {
     let rom_path = Path::new(rom_path);
@@ -13,22 +15,11 @@ fn rom_test(rom_path: &str) {
     let cartridge = VecCart::from_slice(rom.as_slice(), None);
 
     let mut gb = GbRs::new(cartridge);
+    let (sniffer, passed) = PassedSniffer::new();
+    gb.cpu.bus.serial.connect(Box::new(sniffer));
 
-    let timeout = time::Instant::now() + time::Duration::from_secs(30);
-
-    let mut cnt = 0;
-
-    while !gb.cpu.is_passed() {
-        gb.run_one();
-
-        if cnt == 1000 {
-            // Timeout check
-            assert!(time::Instant::now() < timeout);
-            cnt = 0;
-        }
-
-        cnt += 1;
-    }
+    let result = gb.run_until(MAX_CYCLES, |_| passed.is_passed());
+    assert!(!result.timed_out, "{:?} didn't pass", rom_path);
 }
 
 #[test]
@@ -75,3 +66,38 @@ fn mbc1_4mb() {
 fn mbc1_8mb() {
     rom_test("tests/roms/mooneye/mbc1/rom_8Mb.gb");
 }
+
+#[test]
+fn mbc2_bits_ramg() {
+    rom_test("tests/roms/mooneye/mbc2/bits_ramg.gb");
+}
+
+#[test]
+fn mbc2_bits_romb() {
+    rom_test("tests/roms/mooneye/mbc2/bits_romb.gb");
+}
+
+#[test]
+fn mbc2_bits_unused() {
+    rom_test("tests/roms/mooneye/mbc2/bits_unused.gb");
+}
+
+#[test]
+fn mbc2_ram() {
+    rom_test("tests/roms/mooneye/mbc2/ram.gb");
+}
+
+#[test]
+fn mbc2_512k() {
+    rom_test("tests/roms/mooneye/mbc2/rom_512kb.gb");
+}
+
+#[test]
+fn mbc2_1mb() {
+    rom_test("tests/roms/mooneye/mbc2/rom_1Mb.gb");
+}
+
+#[test]
+fn mbc2_2mb() {
+    rom_test("tests/roms/mooneye/mbc2/rom_2Mb.gb");
+}